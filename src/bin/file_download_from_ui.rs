@@ -1,9 +1,15 @@
-use distributed_fs::peer::connection::{ConnectionFrame, GetFileFrame};
-use distributed_fs::ui::connection::Connection;
-use distributed_fs::values::LOCAL_PEER_ADDRESS;
+use toml::Table;
+use distributed_fs::control::client::ControlClient;
+use distributed_fs::control::protocol::ControlRequest;
+use distributed_fs::domain::config::PeerConfig;
+use distributed_fs::values::DEFAULT_DOWNLOAD_PRIORITY;
 
 fn main() {
-    let frame = ConnectionFrame::GetFile(GetFileFrame {file_id: "4148f04f-41e3-4f39-94e8-155bc6dcd3ae".to_string()});
-    let mut connection = Connection::from_address(&LOCAL_PEER_ADDRESS.to_string()).unwrap();
-    connection.write_frame(frame);
+    let fs_config = PeerConfig::load(None, Table::new()).unwrap().fs_config();
+    let mut client = ControlClient::connect(&fs_config.control_socket).unwrap();
+    let response = client.request(ControlRequest::DownloadFile {
+        file_id: "4148f04f-41e3-4f39-94e8-155bc6dcd3ae".to_string(),
+        priority: DEFAULT_DOWNLOAD_PRIORITY,
+    }).unwrap();
+    println!("{:?}", response);
 }
@@ -1,9 +1,11 @@
 use std::sync::Arc;
 use clap::Parser;
-use tokio::sync::Mutex;
-use distributed_fs::domain::config::FSConfig;
+use toml::Table;
+use distributed_fs::domain::config::PeerConfig;
 use distributed_fs::domain::fs::check_folders;
-use distributed_fs::peer::client::Client;
+use distributed_fs::peer::access::generate_swarm_secret;
+use distributed_fs::domain::signing::encode_key;
+use distributed_fs::peer::client::{Client, MetaFileOptions};
 use distributed_fs::peer::state::State;
 
 #[derive(Parser, Debug)]
@@ -11,17 +13,48 @@ use distributed_fs::peer::state::State;
 struct Args {
     #[arg(short, long)]
     path: String,
+
+    /// Path to the config file of the peer, the addresses and the piece size are taken from it.
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// Makes the file private with a new swarm secret.
+    #[arg(long, conflicts_with = "swarm_secret")]
+    private: bool,
+
+    /// Makes the file private with the secret of an existing swarm.
+    #[arg(long)]
+    swarm_secret: Option<String>,
+
+    /// Key of the peer allowed to get the file, can be repeated.
+    #[arg(long = "allow-peer")]
+    allowed_peers: Vec<String>,
+
+    /// Signs the metafile with the identity key of the peer.
+    #[arg(long)]
+    sign: bool,
 }
 
 #[tokio::main]
 async fn main() {
     let args: Args = Args::parse();
-    let fs_config = FSConfig::new(None);
+    let config = PeerConfig::load(args.config, Table::new()).unwrap();
+    let fs_config = config.fs_config();
     check_folders(&fs_config);
-    let sharable_state_container = Arc::new(Mutex::new(State::new(fs_config.clone())));
 
-    let client = Arc::new(Client::new("127.0.0.1:8000".to_string(), sharable_state_container.clone()));
+    // the metafile lists the address the other peers can reach this peer by
+    let address = config.address_options().resolve_advertised_address().unwrap();
+    let sharable_state_container = Arc::new(State::with_config(fs_config.clone(), config));
+    let client = Arc::new(Client::new(address, sharable_state_container.clone()));
 
-    client.generate_meta_file(&args.path).await.unwrap();
+    let swarm_secret = args.swarm_secret.or(args.private.then(generate_swarm_secret));
+    if args.sign {
+        println!("Signing the metafile as the publisher {}", encode_key(&sharable_state_container.identity_key.verifying_key()));
+    }
+    client.generate_meta_file(&args.path, MetaFileOptions {
+        swarm_secret,
+        allowed_peers: args.allowed_peers,
+        sign: args.sign,
+    }).await.unwrap();
     println!("Finished!")
 }
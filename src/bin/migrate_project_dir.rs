@@ -0,0 +1,33 @@
+use clap::Parser;
+use toml::Table;
+use distributed_fs::domain::config::PeerConfig;
+use distributed_fs::domain::fs::check_folders;
+
+/// Moves the metafiles and the files kept in the project dir by the old versions into the rfs dir.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Project dir with the `meta_files` and `files` dirs.
+    #[arg(short, long, default_value = ".")]
+    from: String,
+
+    #[arg(short, long)]
+    config: Option<String>,
+
+    #[arg(short, long)]
+    rfs_dir: Option<String>,
+}
+
+fn main() {
+    let args: Args = Args::parse();
+    let mut cli = Table::new();
+    if let Some(rfs_dir) = args.rfs_dir {
+        cli.insert("dir".to_string(), rfs_dir.into());
+    }
+    let fs_config = PeerConfig::load(args.config, cli).unwrap().fs_config();
+    check_folders(&fs_config);
+    match fs_config.storage.migrate_project_dir(args.from.as_ref()) {
+        Ok(moved) => println!("Moved {moved} files into {}", fs_config.rfs_dir),
+        Err(err) => println!("Migration failed: {err}"),
+    }
+}
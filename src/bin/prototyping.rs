@@ -1,9 +1,9 @@
-use serde_cbor::{from_slice, to_vec};
 use distributed_fs::peer::connection::{ConnectionFrame, GetPingFrame};
+use distributed_fs::peer::protocol::{decode_frame, encode_frame};
 
 fn main() {
-    let frame = ConnectionFrame::GetPing(GetPingFrame {});
-    let data = to_vec(&frame).expect("Failed to serialize GetInfo frame!");
-    let new_frame: Result<ConnectionFrame, _> = from_slice(&data);
+    let frame = ConnectionFrame::GetPing(GetPingFrame { request_id: 1 });
+    let data = encode_frame(&frame).expect("Failed to serialize GetInfo frame!");
+    let new_frame = decode_frame(&data);
     println!("{:?}", new_frame)
 }
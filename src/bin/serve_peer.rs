@@ -1,48 +1,189 @@
+use std::net::Ipv4Addr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use distributed_fs::control::server::serve_control;
+use distributed_fs::domain::config::AdvertiseMode;
 use distributed_fs::peer::client::Client;
+use distributed_fs::peer::dht::run_dht;
+use distributed_fs::peer::discovery::run_discovery;
+use distributed_fs::peer::noise::encode_public_key;
+use distributed_fs::peer::pex::run_pex;
 use distributed_fs::peer::listener::{refresh_pings_for_peers, serve_listener};
 use distributed_fs::peer::state::State;
 
 use clap::Parser;
-use distributed_fs::domain::config::FSConfig;
+use toml::{Table, Value};
+use distributed_fs::domain::config::PeerConfig;
 use distributed_fs::domain::fs::check_folders;
-use distributed_fs::values::LOCAL_PEER_ADDRESS;
 
+/// Flags override the `RFS_*` environment variables, which override the config file.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Path to the config file, `<rfs dir>/config.toml` by default.
     #[arg(short, long)]
-    address: Option<String>,
+    config: Option<String>,
+
+    /// Prints the resulting config and exits.
+    #[arg(long)]
+    print_config: bool,
+
+    /// Address the peer listens on.
+    #[arg(short = 'a', long = "address")]
+    listen_address: Option<String>,
+
+    #[arg(long, value_enum)]
+    advertise: Option<AdvertiseMode>,
+
+    /// Address written into the metafiles and sent to the other peers.
+    #[arg(long)]
+    advertised_address: Option<String>,
 
     #[arg(short, long)]
     rfs_dir: Option<String>,
+
+    #[arg(long)]
+    max_frame_size: Option<usize>,
+
+    #[arg(long)]
+    max_downloads: Option<usize>,
+
+    /// Disables the encrypted transport.
+    #[arg(long)]
+    no_encryption: bool,
+
+    /// Refuses the peers that don't support the encrypted transport.
+    #[arg(long)]
+    require_encryption: bool,
+
+    /// Refuses the metafiles that are not signed by a trusted publisher.
+    #[arg(long)]
+    require_signed_metafiles: bool,
+
+    /// Disables the peer exchange.
+    #[arg(long)]
+    no_pex: bool,
+
+    /// Disables the DHT.
+    #[arg(long)]
+    no_dht: bool,
+
+    /// Disables the discovery of the peers in the local network.
+    #[arg(long)]
+    no_discovery: bool,
+
+    #[arg(long)]
+    discovery_group: Option<Ipv4Addr>,
+
+    #[arg(long)]
+    discovery_port: Option<u16>,
+
+    /// Address of the interface the discovery announcements are sent and received on.
+    #[arg(long)]
+    discovery_interface: Option<Ipv4Addr>,
+}
+
+impl Args {
+    /// Config layer of the flags that are set.
+    fn to_table(&self) -> Table {
+        let mut table = Table::new();
+        let mut discovery = Table::new();
+        let set = |table: &mut Table, key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                table.insert(key.to_string(), value);
+            }
+        };
+        set(&mut table, "listen_address", self.listen_address.clone().map(Value::String));
+        set(&mut table, "advertise", self.advertise.and_then(|mode| Value::try_from(mode).ok()));
+        set(&mut table, "advertised_address", self.advertised_address.clone().map(Value::String));
+        set(&mut table, "dir", self.rfs_dir.clone().map(Value::String));
+        set(&mut table, "max_frame_size", self.max_frame_size.map(|v| Value::Integer(v as i64)));
+        set(&mut table, "max_downloads", self.max_downloads.map(|v| Value::Integer(v as i64)));
+        set(&mut table, "encryption", self.no_encryption.then_some(Value::Boolean(false)));
+        set(&mut table, "require_encryption", self.require_encryption.then_some(Value::Boolean(true)));
+        set(&mut table, "require_signed_metafiles", self.require_signed_metafiles.then_some(Value::Boolean(true)));
+        set(&mut table, "pex", self.no_pex.then_some(Value::Boolean(false)));
+        set(&mut table, "dht", self.no_dht.then_some(Value::Boolean(false)));
+        set(&mut discovery, "enabled", self.no_discovery.then_some(Value::Boolean(false)));
+        set(&mut discovery, "group", self.discovery_group.map(|v| Value::String(v.to_string())));
+        set(&mut discovery, "port", self.discovery_port.map(|v| Value::Integer(v as i64)));
+        set(&mut discovery, "interface", self.discovery_interface.map(|v| Value::String(v.to_string())));
+        if !discovery.is_empty() {
+            table.insert("discovery".to_string(), Value::Table(discovery));
+        }
+        table
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let args: Args = Args::parse();
-    
-    let fs_config = FSConfig::new(args.rfs_dir);
+
+    let config = match PeerConfig::load(args.config.clone(), args.to_table()) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("{err}");
+            return;
+        }
+    };
+    if args.print_config {
+        match config.to_toml() {
+            Ok(config) => print!("{config}"),
+            Err(err) => println!("{err}"),
+        }
+        return;
+    }
+
+    let fs_config = config.fs_config();
     check_folders(&fs_config);
     
-    let sharable_state_container = Arc::new(Mutex::new(State::new(fs_config.clone())));
+    let listen_address = config.listen_address.clone();
+    let address = match config.address_options().resolve_advertised_address() {
+        Ok(address) => address,
+        Err(err) => {
+            println!("{err}");
+            return;
+        }
+    };
 
-    let address = args.address.unwrap_or(LOCAL_PEER_ADDRESS.to_string());
+    let mut state = State::with_config(fs_config.clone(), config.clone());
+    state.connection_options.advertised_address = Some(address.clone());
+    let sharable_state_container = Arc::new(state);
     
     let mut client = Client::new(address.clone(), sharable_state_container.clone());
     
     client.load_state(address.clone(), &fs_config).await.unwrap();
 
-    println!("Starting peer with address {} and fs location {} ...", address, fs_config.rfs_dir);
+    println!("Starting peer listening on {} with address {} and fs location {} ...", listen_address, address, fs_config.rfs_dir);
+    if let Some(key) = &sharable_state_container.connection_options.noise_key {
+        println!("Peer key is {}", encode_public_key(&key.public));
+    }
     
+    tokio::spawn(async move {
+        if let Err(err) = client.link_legacy_files().await {
+            println!("Error when verifying legacy files: {err}");
+        }
+        if let Err(err) = client.resume_downloads().await {
+            println!("Error when resuming downloads: {err}");
+        }
+    });
+
+    tokio::spawn(serve_control(fs_config.control_socket.clone(), sharable_state_container.clone()));
+
+    if config.dht {
+        tokio::spawn(run_dht(sharable_state_container.clone()));
+    }
+    if config.pex {
+        tokio::spawn(run_pex(sharable_state_container.clone()));
+    }
+    tokio::spawn(run_discovery(sharable_state_container.clone(), config.discovery_options()));
+
     let mut c = sharable_state_container.clone();
     tokio::spawn(async move {
         refresh_pings_for_peers(&mut c).await;
     });
 
     serve_listener(
-        address,
+        listen_address,
         &mut sharable_state_container.clone(),
     ).await
-}
\ No newline at end of file
+}
@@ -0,0 +1,62 @@
+use clap::{Parser, Subcommand};
+use toml::Table;
+use distributed_fs::domain::config::PeerConfig;
+use distributed_fs::domain::fs::check_folders;
+use distributed_fs::domain::signing::TrustStore;
+
+/// Manages the publishers whose signed metafiles are trusted by the peer.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: TrustCommand,
+
+    #[arg(short, long)]
+    config: Option<String>,
+
+    #[arg(short, long)]
+    rfs_dir: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum TrustCommand {
+    /// Trusts the publisher with the given key.
+    Add {
+        key: String,
+        #[arg(default_value = "")]
+        name: String,
+    },
+    /// Stops trusting the publisher with the given key.
+    Remove {
+        key: String,
+    },
+    /// Lists the trusted publishers.
+    List,
+}
+
+fn main() {
+    let args: Args = Args::parse();
+    let mut cli = Table::new();
+    if let Some(rfs_dir) = args.rfs_dir {
+        cli.insert("dir".to_string(), rfs_dir.into());
+    }
+    let fs_config = PeerConfig::load(args.config, cli).unwrap().fs_config();
+    check_folders(&fs_config);
+    let mut trust_store = TrustStore::load(&fs_config);
+    let result = match args.command {
+        TrustCommand::Add { key, name } => trust_store.add(key, name).and_then(|_| trust_store.save()),
+        TrustCommand::Remove { key } => match trust_store.remove(&key) {
+            true => trust_store.save(),
+            false => Err(format!("Publisher {key} is not trusted")),
+        },
+        TrustCommand::List => {
+            for (key, name) in trust_store.publishers() {
+                println!("{key} {name}");
+            }
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        println!("{err}");
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use crate::control::protocol::{decode_message, encode_message, ControlEvent, ControlMessage, ControlRequest, ControlRequestFrame, ControlResponse};
+use crate::peer::codec::{CodecError, FrameCodec};
+use crate::values::{DEFAULT_BUFFER_SIZE, DEFAULT_MAX_FRAME_SIZE};
+
+#[derive(Debug)]
+pub enum ControlError {
+    WouldBlock,
+    Generic(String),
+}
+
+/// Blocking client of the control api of the local peer, used by the ui and the cli tools.
+pub struct ControlClient {
+    stream: UnixStream,
+    codec: FrameCodec,
+    next_request_id: u64,
+    // events received while waiting for a response
+    events: VecDeque<ControlEvent>,
+}
+
+impl ControlClient {
+    pub fn connect(path: &str) -> Result<Self, String> {
+        let stream = UnixStream::connect(path)
+            .map_err(|err| format!("Error when connecting to the control socket {path}: {err}"))?;
+        Ok(ControlClient {
+            stream,
+            codec: FrameCodec::new(DEFAULT_MAX_FRAME_SIZE, DEFAULT_BUFFER_SIZE),
+            next_request_id: 1,
+            events: Default::default(),
+        })
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), String> {
+        self.stream.set_nonblocking(nonblocking)
+            .map_err(|err| format!("Error when configuring the control socket: {err}"))
+    }
+
+    /// Sends the request without waiting for the response, returns the id of the request.
+    pub fn send(&mut self, request: ControlRequest) -> Result<u64, String> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        let data = self.codec.encode(&encode_message(&ControlRequestFrame { request_id, request })?)?;
+        self.stream.write_all(&data)
+            .map_err(|err| format!("Failed to send control request {err}"))?;
+        Ok(request_id)
+    }
+
+    /// Returns the events received while waiting for a response first, then the messages from the socket.
+    pub fn read_message(&mut self) -> Result<ControlMessage, ControlError> {
+        match self.events.pop_front() {
+            Some(event) => Ok(ControlMessage::Event(event)),
+            None => self.read_socket_message(),
+        }
+    }
+
+    fn read_socket_message(&mut self) -> Result<ControlMessage, ControlError> {
+        let data = self.codec.read_frame_sync(&mut self.stream).map_err(|err| match err {
+            CodecError::WouldBlock => ControlError::WouldBlock,
+            err => ControlError::Generic(err.into()),
+        })?;
+        decode_message(&data).map_err(ControlError::Generic)
+    }
+
+    /// Sends the request and waits for its response, the stream should be in blocking mode.
+    /// Events received in the meantime are kept for `read_message` and `next_event`.
+    pub fn request(&mut self, request: ControlRequest) -> Result<ControlResponse, String> {
+        let request_id = self.send(request)?;
+        loop {
+            match self.read_socket_message().map_err(|err| format!("Error when reading control response {:?}", err))? {
+                ControlMessage::Response { request_id: id, response } if id == request_id => {
+                    return match response {
+                        ControlResponse::Error(err) => Err(err),
+                        response => Ok(response),
+                    };
+                }
+                ControlMessage::Response { request_id: id, .. } => println!("Skipping response to the request {id}"),
+                ControlMessage::Event(event) => self.events.push_back(event),
+            }
+        }
+    }
+
+    /// Waits for the next event of the subscription, the stream should be in blocking mode.
+    pub fn next_event(&mut self) -> Result<ControlEvent, String> {
+        loop {
+            match self.read_message().map_err(|err| format!("Error when reading control event {:?}", err))? {
+                ControlMessage::Event(event) => return Ok(event),
+                ControlMessage::Response { request_id, .. } => println!("Skipping response to the request {request_id}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::enums::PieceDownloadStatus;
+    use super::*;
+
+    fn event(piece: u64) -> ControlEvent {
+        ControlEvent::PieceDownloadStatus { file_id: "file".to_string(), piece, status: PieceDownloadStatus::Downloaded }
+    }
+
+    fn write_message(stream: &mut UnixStream, message: &ControlMessage) {
+        let codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE, DEFAULT_BUFFER_SIZE);
+        stream.write_all(&codec.encode(&encode_message(message).unwrap()).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn events_received_before_response_are_delivered() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let mut client = ControlClient {
+            stream,
+            codec: FrameCodec::new(DEFAULT_MAX_FRAME_SIZE, DEFAULT_BUFFER_SIZE),
+            next_request_id: 1,
+            events: Default::default(),
+        };
+        write_message(&mut peer, &ControlMessage::Event(event(0)));
+        write_message(&mut peer, &ControlMessage::Event(event(1)));
+        write_message(&mut peer, &ControlMessage::Response { request_id: 1, response: ControlResponse::Subscribed });
+        write_message(&mut peer, &ControlMessage::Event(event(2)));
+
+        assert!(matches!(client.request(ControlRequest::Subscribe), Ok(ControlResponse::Subscribed)));
+        for piece in 0..2 {
+            match client.read_message() {
+                Ok(ControlMessage::Event(ControlEvent::PieceDownloadStatus { piece: p, .. })) => assert_eq!(p, piece),
+                message => panic!("Unexpected message {message:?}"),
+            }
+        }
+        assert!(matches!(client.next_event(), Ok(ControlEvent::PieceDownloadStatus { piece: 2, .. })));
+    }
+}
@@ -0,0 +1,67 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_cbor::from_slice;
+use serde_cbor::ser::to_vec_packed;
+use crate::domain::enums::{DownloadStatus, PieceDownloadStatus};
+use crate::peer::download_manager::{DownloadEvent, DownloadInfo};
+use crate::peer::state::KnownPeer;
+
+/// Requests of the local clients, like the ui, to the peer. They are accepted only on the control
+/// socket, remote peers can't reach them.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ControlRequest {
+    GetInfo,
+    DownloadFile { file_id: String, priority: u8 },
+    PauseDownload { file_id: String },
+    ResumeDownload { file_id: String },
+    CancelDownload { file_id: String },
+    GetDownloads,
+    /// Subscribes the connection to the events of all downloads.
+    Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlRequestFrame {
+    pub request_id: u64,
+    pub request: ControlRequest,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ControlResponse {
+    /// Advertised address of the peer, the files it knows and its known peers.
+    Info { address: String, file_ids: Vec<String>, known_peers: Vec<KnownPeer> },
+    Download(DownloadInfo),
+    Downloads(Vec<DownloadInfo>),
+    Subscribed,
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ControlEvent {
+    PieceDownloadStatus { file_id: String, piece: u64, status: PieceDownloadStatus },
+    DownloadStatus { file_id: String, status: DownloadStatus },
+}
+
+impl From<DownloadEvent> for ControlEvent {
+    fn from(event: DownloadEvent) -> Self {
+        match event {
+            DownloadEvent::Piece { file_id, piece, status } => ControlEvent::PieceDownloadStatus { file_id, piece, status },
+            DownloadEvent::Status { file_id, status } => ControlEvent::DownloadStatus { file_id, status },
+        }
+    }
+}
+
+/// Message sent by the peer to the control client, either a response to a request or an event.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ControlMessage {
+    Response { request_id: u64, response: ControlResponse },
+    Event(ControlEvent),
+}
+
+pub fn encode_message<T: Serialize>(message: &T) -> Result<Vec<u8>, String> {
+    to_vec_packed(message).map_err(|err| format!("Failed to serialize control message {err}"))
+}
+
+pub fn decode_message<T: DeserializeOwned>(data: &[u8]) -> Result<T, String> {
+    from_slice(data).map_err(|err| format!("Failed to deserialize control message {err}"))
+}
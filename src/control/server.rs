@@ -0,0 +1,179 @@
+use std::fs::{DirBuilder, Permissions};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use crate::control::protocol::{decode_message, encode_message, ControlMessage, ControlRequest, ControlRequestFrame, ControlResponse};
+use crate::peer::codec::FrameCodec;
+use crate::peer::state::SharableStateContainer;
+
+/// Write half of the control connection, shared by the request loop and the events subscription.
+#[derive(Clone)]
+struct ControlWriter {
+    stream: Arc<Mutex<OwnedWriteHalf>>,
+    codec: Arc<FrameCodec>,
+}
+
+impl ControlWriter {
+    async fn write_message(&self, message: &ControlMessage) -> Result<(), String> {
+        let data = self.codec.encode(&encode_message(message)?)?;
+        self.stream.lock().await.write_all(&data).await
+            .map_err(|err| format!("Failed to send control message {err}"))
+    }
+}
+
+async fn process_request(container: &SharableStateContainer, request: ControlRequest) -> Result<ControlResponse, String> {
+    let manager = &container.download_manager;
+    Ok(match request {
+        ControlRequest::GetInfo => ControlResponse::Info {
+            address: container.connection_options.advertised_address().to_string(),
+            file_ids: container.file_manager.get_file_ids(),
+            known_peers: container.peers.get_known_peers(),
+        },
+        ControlRequest::DownloadFile { file_id, priority } => {
+            ControlResponse::Download(manager.enqueue(container, file_id, priority)?)
+        }
+        ControlRequest::PauseDownload { file_id } => ControlResponse::Download(manager.pause(container, &file_id).await?),
+        ControlRequest::ResumeDownload { file_id } => ControlResponse::Download(manager.resume(container, &file_id)?),
+        ControlRequest::CancelDownload { file_id } => ControlResponse::Download(manager.cancel(container, &file_id).await?),
+        ControlRequest::GetDownloads => ControlResponse::Downloads(manager.get_downloads()),
+        ControlRequest::Subscribe => ControlResponse::Subscribed,
+    })
+}
+
+/// Forwards the download events to the control client until the connection is closed.
+fn subscribe(container: &SharableStateContainer, writer: ControlWriter) -> JoinHandle<()> {
+    let mut events = container.download_manager.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(n)) => {
+                    println!("Control client skipped {n} download events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            if writer.write_message(&ControlMessage::Event(event.into())).await.is_err() {
+                return;
+            }
+        }
+    })
+}
+
+async fn process_control_connection(
+    stream: UnixStream,
+    container: &SharableStateContainer,
+    subscription: &mut Option<JoinHandle<()>>,
+) -> Result<(), String> {
+    let (mut reader, writer) = stream.into_split();
+    let mut codec = FrameCodec::new(container.connection_options.max_frame_size, container.connection_options.buffer_size);
+    let writer = ControlWriter {
+        stream: Arc::new(Mutex::new(writer)),
+        codec: Arc::new(FrameCodec::new(container.connection_options.max_frame_size, container.connection_options.buffer_size)),
+    };
+    loop {
+        let frame: ControlRequestFrame = decode_message(&codec.read_frame(&mut reader).await?)?;
+        if let ControlRequest::Subscribe = frame.request {
+            if subscription.is_none() {
+                *subscription = Some(subscribe(container, writer.clone()));
+            }
+        }
+        let response = process_request(container, frame.request).await.unwrap_or_else(ControlResponse::Error);
+        writer.write_message(&ControlMessage::Response { request_id: frame.request_id, response }).await?;
+    }
+}
+
+/// Creates the dir accessible only to the user running the peer. A socket is bound with the
+/// permissions of the umask, so it's created in such a dir to be private from the start.
+fn create_private_dir(dir: &Path) -> Result<(), String> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)
+        .map_err(|err| format!("Error when creating dir {}: {err}", dir.display()))?;
+    // the dir may already exist with other permissions
+    std::fs::set_permissions(dir, Permissions::from_mode(0o700))
+        .map_err(|err| format!("Error when setting permissions of dir {}: {err}", dir.display()))
+}
+
+/// Serves the control api for the local clients on a unix socket, the socket is accessible only
+/// to the user running the peer.
+pub async fn serve_control(path: String, container: SharableStateContainer) {
+    if let Some(dir) = Path::new(&path).parent() {
+        if let Err(err) = create_private_dir(dir) {
+            println!("Control api is disabled: {err}");
+            return;
+        }
+    }
+    // the socket of the previous run is left on the disk if the peer wasn't stopped gracefully
+    let _ = tokio::fs::remove_file(&path).await;
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Error when binding control socket {path}: {err}");
+            return;
+        }
+    };
+    if let Err(err) = std::fs::set_permissions(&path, Permissions::from_mode(0o600)) {
+        println!("Error when setting permissions of control socket {path}: {err}");
+    }
+    println!("Serving control api on {path}");
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                println!("Error when accepting control connection: {err}");
+                continue;
+            }
+        };
+        let container = container.clone();
+        tokio::spawn(async move {
+            let mut subscription = None;
+            if let Err(err) = process_control_connection(stream, &container, &mut subscription).await {
+                println!("Control connection closed: {err}");
+            }
+            if let Some(subscription) = subscription {
+                subscription.abort();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::control::client::ControlClient;
+    use crate::domain::config::FSConfig;
+    use crate::peer::state::State;
+    use super::*;
+
+    fn mode(path: &str) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn control_socket_is_private() {
+        let fs_config = FSConfig::temp("control_socket");
+        let path = fs_config.control_socket.clone();
+        let dir = Path::new(&path).parent().unwrap().to_string_lossy().to_string();
+        // the dir left with the default permissions is made private
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+
+        tokio::spawn(serve_control(path.clone(), Arc::new(State::new(fs_config))));
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while !Path::new(&path).exists() || mode(&path) != 0o600 {
+            assert!(tokio::time::Instant::now() < deadline, "control socket was not created in time");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(mode(&dir), 0o700);
+
+        let response = tokio::task::spawn_blocking(move || ControlClient::connect(&path)?.request(ControlRequest::GetDownloads))
+            .await.unwrap().unwrap();
+        assert!(matches!(response, ControlResponse::Downloads(downloads) if downloads.is_empty()));
+    }
+}
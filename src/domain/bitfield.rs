@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+/// Set of pieces of a file, one bit per piece. Bitfields come from the other peers and from the
+/// disk, so they are checked by `from_bytes` when they are decoded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "RawBitfield")]
+pub struct Bitfield {
+    // a byte string in CBOR, not an array of integers
+    #[serde(with = "serde_bytes")]
+    bits: Vec<u8>,
+    len: u64,
+}
+
+/// Bitfield as it's encoded, before its bits are checked against its length.
+#[derive(Deserialize)]
+struct RawBitfield {
+    #[serde(with = "serde_bytes")]
+    bits: Vec<u8>,
+    len: u64,
+}
+
+impl TryFrom<RawBitfield> for Bitfield {
+    type Error = String;
+
+    fn try_from(raw: RawBitfield) -> Result<Self, Self::Error> {
+        Bitfield::from_bytes(raw.bits, raw.len)
+    }
+}
+
+impl Bitfield {
+    pub fn new(len: u64) -> Self {
+        Bitfield {
+            bits: vec![0; len.div_ceil(8) as usize],
+            len,
+        }
+    }
+
+    pub fn full(len: u64) -> Self {
+        let mut bitfield = Bitfield::new(len);
+        for piece in 0..len {
+            bitfield.set(piece);
+        }
+        bitfield
+    }
+
+    /// Checks that there is a byte for every 8 pieces and the bits after the last piece are zero.
+    pub fn from_bytes(bits: Vec<u8>, len: u64) -> Result<Self, String> {
+        if bits.len() as u64 != len.div_ceil(8) {
+            return Err(format!("Bitfield of {} bytes doesn't match {len} pieces", bits.len()));
+        }
+        let padding = (8 - len % 8) % 8;
+        if bits.last().is_some_and(|last| last & ((1u16 << padding) - 1) as u8 != 0) {
+            return Err(format!("Bitfield of {len} pieces has bits set after the last piece"));
+        }
+        Ok(Bitfield { bits, len })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, piece: u64) -> bool {
+        piece < self.len && self.bits[(piece / 8) as usize] & (0x80 >> (piece % 8)) != 0
+    }
+
+    pub fn set(&mut self, piece: u64) {
+        if piece < self.len {
+            self.bits[(piece / 8) as usize] |= 0x80 >> (piece % 8);
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.bits.iter().map(|b| b.count_ones() as u64).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn missing(&self) -> Vec<u64> {
+        (0..self.len).filter(|piece| !self.has(*piece)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_must_match_length() {
+        assert!(Bitfield::from_bytes(vec![], 100).is_err());
+        assert!(Bitfield::from_bytes(vec![0; 14], 100).is_err());
+        assert!(Bitfield::from_bytes(vec![0; 13], 100).is_ok());
+        assert!(Bitfield::from_bytes(vec![], 0).is_ok());
+    }
+
+    #[test]
+    fn padding_bits_must_be_zero() {
+        // 10 pieces use the 2 high bits of the second byte
+        assert!(Bitfield::from_bytes(vec![0xff, 0xc0], 10).is_ok());
+        assert!(Bitfield::from_bytes(vec![0xff, 0xe0], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff, 0x01], 10).is_err());
+        assert!(Bitfield::from_bytes(vec![0xff], 8).is_ok());
+        assert_eq!(Bitfield::from_bytes(Bitfield::full(10).as_bytes().to_vec(), 10), Ok(Bitfield::full(10)));
+    }
+
+    #[test]
+    fn bits_are_checked_when_decoded() {
+        let bitfield = Bitfield::full(100);
+        let data = serde_cbor::ser::to_vec_packed(&bitfield).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Bitfield>(&data).unwrap(), bitfield);
+
+        let data = serde_cbor::ser::to_vec_packed(&Bitfield { bits: vec![], len: 100 }).unwrap();
+        assert!(serde_cbor::from_slice::<Bitfield>(&data).is_err());
+    }
+}
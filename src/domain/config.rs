@@ -1,28 +1,525 @@
-use crate::values::DEFAULT_RFS_DIR;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+use crate::domain::storage::StorageLayout;
+use crate::values::{DEFAULT_BUFFER_SIZE, DEFAULT_LISTEN_ADDRESS, DEFAULT_MAX_FRAME_SIZE, DEFAULT_PIECE_SIZE, DEFAULT_RFS_DIR, DHT_ALPHA, DHT_ANNOUNCE_SECS, DHT_BUCKET_SIZE, DHT_MAX_KEYS_PER_PROVIDER, DHT_MAX_PROVIDERS_PER_KEY, DHT_MAX_PROVIDER_KEYS, DHT_PROVIDER_TTL_SECS, DISCOVERY_GROUP, DISCOVERY_INTERVAL_SECS, DISCOVERY_PORT, ENDGAME_PIECES, MAX_CONCURRENT_DOWNLOADS, MAX_KNOWN_PEERS, MAX_OPEN_FILES, PEER_EXPIRY_SECS, PEX_INTERVAL_SECS, PIECE_CACHE_SIZE, PIECE_QUEUE_DEPTH, PIECE_TIMEOUT_SECS, PIECE_WAIT_SECS, PING_TIMEOUT_SECS, SYNC_DELAY_SECS};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+const ENV_PREFIX: &str = "RFS_";
+const CONFIG_PATH_ENV: &str = "RFS_CONFIG";
 
 #[derive(Default, Clone)]
 pub struct FSConfig {
     pub home_dir: String,
     pub rfs_dir: String,
-    pub metafiles_dir: String,
-    pub file_parts_dir: String,
-    pub files_dir: String,
+    pub storage: StorageLayout,
+    /// Unix socket the local clients, like the ui, control the peer through.
+    pub control_socket: String,
 }
 
 impl FSConfig {
+    /// Relative rfs dirs are placed in the home dir.
     pub fn new(rfs_dir: Option<String>) -> Self {
         let rfs_dir = rfs_dir.unwrap_or(DEFAULT_RFS_DIR.to_string());
         let home_dir = std::env::var("HOME").unwrap_or_else(|_| "".to_string());
-        let rfs_dir = home_dir.clone() + "/" + &rfs_dir;
-        let metafiles_dir = rfs_dir.clone() + "/metafiles";
-        let file_parts_dir = rfs_dir.clone() + "/file_parts";
-        let files_dir = rfs_dir.clone() + "/files";
+        let rfs_dir = if rfs_dir.starts_with('/') { rfs_dir } else { home_dir.clone() + "/" + &rfs_dir };
+        let storage = StorageLayout::new(&rfs_dir);
+        // the socket is in its own dir, only the dir can be made private before the socket is bound
+        let control_socket = rfs_dir.clone() + "/control/control.sock";
         FSConfig {
             home_dir,
             rfs_dir,
-            metafiles_dir,
-            file_parts_dir,
-            files_dir,
+            storage,
+            control_socket,
+        }
+    }
+}
+
+#[cfg(test)]
+impl FSConfig {
+    /// Config with the rfs dir in a new temp dir, the name must be unique among the tests.
+    pub fn temp(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rfs_test_{}_{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let fs_config = FSConfig::new(Some(dir.to_string_lossy().to_string()));
+        crate::domain::fs::check_folders(&fs_config);
+        fs_config
+    }
+}
+
+/// How the address the other peers connect to is chosen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdvertiseMode {
+    /// The advertised address if it's set, the listen address otherwise.
+    #[default]
+    Manual,
+    /// The address of the primary LAN interface with the listen port.
+    Lan,
+    /// The public host or address set by the user, with the listen port if no port is given.
+    Public,
+}
+
+#[derive(Clone, Debug)]
+pub struct AddressOptions {
+    pub listen_address: String,
+    pub advertise: AdvertiseMode,
+    /// Address written into the metafiles and sent to the other peers.
+    pub advertised_address: Option<String>,
+}
+
+/// Options of the LAN discovery, the group and the interface the announcements are sent on.
+#[derive(Clone, Debug)]
+pub struct DiscoveryOptions {
+    pub enabled: bool,
+    pub group: Ipv4Addr,
+    pub port: u16,
+    pub interface: Ipv4Addr,
+    pub interval: Duration,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        PeerConfig::default().discovery_options()
+    }
+}
+
+/// Timeouts of the piece requests, the waits of the downloads and the sizes of the piece queues.
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    pub piece_timeout: Duration,
+    /// How long the download waits for the missing pieces to appear on the sources.
+    pub piece_wait: Duration,
+    /// Interval of the checks for the new pieces of the sources.
+    pub poll_interval: Duration,
+    /// Number of the pieces requested from one source at a time.
+    pub queue_depth: usize,
+    /// Number of the missing pieces below which a piece is requested from several sources.
+    pub endgame_pieces: u64,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        PeerConfig::default().download_options()
+    }
+}
+
+/// Size of the k-buckets, parallelism of the lookups and the limits of the announced sources.
+#[derive(Clone, Debug)]
+pub struct DhtOptions {
+    pub bucket_size: usize,
+    pub alpha: usize,
+    pub provider_ttl: Duration,
+    pub max_providers_per_key: usize,
+    pub max_keys_per_provider: usize,
+    pub max_provider_keys: usize,
+}
+
+impl Default for DhtOptions {
+    fn default() -> Self {
+        PeerConfig::default().dht_options()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    pub enabled: bool,
+    pub group: Ipv4Addr,
+    pub port: u16,
+    pub interface: Ipv4Addr,
+    pub interval_secs: u64,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            group: DISCOVERY_GROUP,
+            port: DISCOVERY_PORT,
+            interface: Ipv4Addr::UNSPECIFIED,
+            interval_secs: DISCOVERY_INTERVAL_SECS,
+        }
+    }
+}
+
+/// Settings of the peer daemon. They are layered: the defaults, the config file, the `RFS_*`
+/// environment variables and the command line flags, every layer overrides the previous ones.
+/// Nested settings are set with the variables like `RFS_DISCOVERY_PORT`. The defaults are the
+/// constants in `values`, the options of the components are built from the config.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerConfig {
+    /// The rfs dir, relative to the home dir if not absolute.
+    pub dir: String,
+    pub listen_address: String,
+    pub advertise: AdvertiseMode,
+    /// Empty if not set.
+    pub advertised_address: String,
+    pub max_frame_size: usize,
+    pub buffer_size: usize,
+    /// Offers the encrypted transport to the other peers.
+    pub encryption: bool,
+    /// Refuses the peers that don't support the encrypted transport. The offer is made in the
+    /// plaintext Hello frames, so without this option a host on the path can strip it and
+    /// silently downgrade the connection to plaintext.
+    pub require_encryption: bool,
+    /// Refuses the metafiles that are not signed by a trusted publisher.
+    pub require_signed_metafiles: bool,
+    /// Piece size of the generated metafiles.
+    pub piece_size: u64,
+    pub max_downloads: usize,
+    pub piece_timeout_secs: u64,
+    /// Number of the pieces requested from one source at a time.
+    pub piece_queue_depth: usize,
+    /// Number of the missing pieces below which a piece is requested from several sources.
+    pub endgame_pieces: u64,
+    /// Time a download waits for the missing pieces to appear on the sources before it fails.
+    pub piece_wait_secs: u64,
+    /// Interval of the download checks for the new pieces of the sources.
+    pub download_poll_secs: u64,
+    pub piece_cache_size: usize,
+    pub max_open_files: usize,
+    pub max_known_peers: usize,
+    /// Time after which the known peers that don't respond are removed.
+    pub peer_expiry_secs: u64,
+    pub ping_interval_secs: u64,
+    /// Time given to connect to a known peer and get its ping.
+    pub ping_timeout_secs: u64,
+    pub pex: bool,
+    pub pex_interval_secs: u64,
+    pub dht: bool,
+    pub dht_announce_secs: u64,
+    pub dht_bucket_size: usize,
+    /// Number of the contacts queried in parallel in a lookup.
+    pub dht_alpha: usize,
+    /// Time the announced sources are kept without being announced again.
+    pub dht_provider_ttl_secs: u64,
+    pub dht_max_providers_per_key: usize,
+    pub dht_max_keys_per_provider: usize,
+    pub dht_max_provider_keys: usize,
+    pub discovery: DiscoveryConfig,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        Self {
+            dir: DEFAULT_RFS_DIR.to_string(),
+            listen_address: DEFAULT_LISTEN_ADDRESS.to_string(),
+            advertise: AdvertiseMode::Manual,
+            advertised_address: String::new(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            encryption: true,
+            require_encryption: false,
+            require_signed_metafiles: false,
+            piece_size: DEFAULT_PIECE_SIZE,
+            max_downloads: MAX_CONCURRENT_DOWNLOADS,
+            piece_timeout_secs: PIECE_TIMEOUT_SECS,
+            piece_queue_depth: PIECE_QUEUE_DEPTH,
+            endgame_pieces: ENDGAME_PIECES,
+            piece_wait_secs: PIECE_WAIT_SECS,
+            download_poll_secs: SYNC_DELAY_SECS,
+            piece_cache_size: PIECE_CACHE_SIZE,
+            max_open_files: MAX_OPEN_FILES,
+            max_known_peers: MAX_KNOWN_PEERS,
+            peer_expiry_secs: PEER_EXPIRY_SECS,
+            ping_interval_secs: SYNC_DELAY_SECS,
+            ping_timeout_secs: PING_TIMEOUT_SECS,
+            pex: true,
+            pex_interval_secs: PEX_INTERVAL_SECS,
+            dht: true,
+            dht_announce_secs: DHT_ANNOUNCE_SECS,
+            dht_bucket_size: DHT_BUCKET_SIZE,
+            dht_alpha: DHT_ALPHA,
+            dht_provider_ttl_secs: DHT_PROVIDER_TTL_SECS,
+            dht_max_providers_per_key: DHT_MAX_PROVIDERS_PER_KEY,
+            dht_max_keys_per_provider: DHT_MAX_KEYS_PER_PROVIDER,
+            dht_max_provider_keys: DHT_MAX_PROVIDER_KEYS,
+            discovery: Default::default(),
+        }
+    }
+}
+
+/// Overrides the values of the base table with the values of the other one, nested tables are merged.
+fn merge(base: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(other)) => merge(base, other),
+            (_, value) => { base.insert(key, value); }
+        }
+    }
+}
+
+/// Parses the value of the variable as a TOML value, the values that are not valid TOML, like
+/// the addresses, are taken as strings.
+fn parse_env_value(raw: &str) -> Value {
+    format!("value = {raw}").parse::<Table>().ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or(Value::String(raw.to_string()))
+}
+
+/// Collects the `RFS_*` variables of the settings present in the defaults table.
+fn env_overrides(defaults: &Table, prefix: &str) -> Table {
+    let mut overrides = Table::new();
+    for (key, value) in defaults {
+        let name = format!("{prefix}{}", key.to_uppercase());
+        if let Value::Table(nested) = value {
+            let nested = env_overrides(nested, &(name + "_"));
+            if !nested.is_empty() {
+                overrides.insert(key.clone(), Value::Table(nested));
+            }
+        } else if let Ok(raw) = std::env::var(&name) {
+            overrides.insert(key.clone(), parse_env_value(&raw));
+        }
+    }
+    overrides
+}
+
+fn to_table<T: Serialize>(value: &T) -> Result<Table, String> {
+    Table::try_from(value).map_err(|err| format!("Failed to serialize config {err}"))
+}
+
+impl PeerConfig {
+    /// Loads the layered config. The config file is taken from the given path, the `RFS_CONFIG`
+    /// variable or the rfs dir set by the other layers. `cli` holds the settings set by the flags.
+    pub fn load(config_path: Option<String>, cli: Table) -> Result<Self, String> {
+        let defaults = to_table(&PeerConfig::default())?;
+        let env = env_overrides(&defaults, ENV_PREFIX);
+
+        let config_path = match config_path.or(std::env::var(CONFIG_PATH_ENV).ok()) {
+            Some(path) => Some(path),
+            None => {
+                let mut table = defaults.clone();
+                merge(&mut table, env.clone());
+                merge(&mut table, cli.clone());
+                let rfs_dir = table.get("dir").and_then(|v| v.as_str()).map(|s| s.to_string());
+                let path = FSConfig::new(rfs_dir).rfs_dir + "/" + CONFIG_FILE_NAME;
+                // the default config file is optional
+                Path::new(&path).exists().then_some(path)
+            }
+        };
+
+        let mut table = defaults;
+        if let Some(path) = config_path {
+            let contents = std::fs::read_to_string(&path)
+                .map_err(|err| format!("Error when reading config file {path}: {err}"))?;
+            let file = contents.parse::<Table>()
+                .map_err(|err| format!("Error when parsing config file {path}: {err}"))?;
+            merge(&mut table, file);
         }
+        merge(&mut table, env);
+        merge(&mut table, cli);
+
+        let config: PeerConfig = Value::Table(table).try_into()
+            .map_err(|err| format!("Invalid config: {err}"))?;
+        config.validate()?;
+        Ok(config)
     }
-}
\ No newline at end of file
+
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = vec![];
+        if self.dir.is_empty() {
+            errors.push("dir should not be empty".to_string());
+        }
+        if let Err(err) = self.listen_address.parse::<SocketAddr>() {
+            errors.push(format!("listen_address {} is invalid: {err}", self.listen_address));
+        }
+        if self.advertise == AdvertiseMode::Public && self.advertised_address.is_empty() {
+            errors.push("advertised_address is required in the public advertise mode".to_string());
+        }
+        if self.require_encryption && !self.encryption {
+            errors.push("require_encryption needs the encryption to be enabled".to_string());
+        }
+        if self.piece_size == 0 {
+            errors.push("piece_size should be positive".to_string());
+        }
+        // a piece response carries the piece and a small header
+        if self.max_frame_size < self.piece_size as usize + 1024 {
+            errors.push(format!("max_frame_size should fit a piece of {} bytes with its header", self.piece_size));
+        }
+        for (name, value) in [
+            ("buffer_size", self.buffer_size as u64),
+            ("max_downloads", self.max_downloads as u64),
+            ("piece_timeout_secs", self.piece_timeout_secs),
+            ("piece_queue_depth", self.piece_queue_depth as u64),
+            ("piece_wait_secs", self.piece_wait_secs),
+            ("download_poll_secs", self.download_poll_secs),
+            ("max_open_files", self.max_open_files as u64),
+            ("max_known_peers", self.max_known_peers as u64),
+            ("peer_expiry_secs", self.peer_expiry_secs),
+            ("ping_interval_secs", self.ping_interval_secs),
+            ("ping_timeout_secs", self.ping_timeout_secs),
+            ("pex_interval_secs", self.pex_interval_secs),
+            ("dht_announce_secs", self.dht_announce_secs),
+            ("dht_bucket_size", self.dht_bucket_size as u64),
+            ("dht_alpha", self.dht_alpha as u64),
+            ("dht_provider_ttl_secs", self.dht_provider_ttl_secs),
+            ("dht_max_providers_per_key", self.dht_max_providers_per_key as u64),
+            ("dht_max_keys_per_provider", self.dht_max_keys_per_provider as u64),
+            ("dht_max_provider_keys", self.dht_max_provider_keys as u64),
+            ("discovery.interval_secs", self.discovery.interval_secs),
+        ] {
+            if value == 0 {
+                errors.push(format!("{name} should be positive"));
+            }
+        }
+        if !self.discovery.group.is_multicast() {
+            errors.push(format!("discovery.group {} is not a multicast address", self.discovery.group));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid config: {}", errors.join("; ")))
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string(self).map_err(|err| format!("Failed to serialize config {err}"))
+    }
+
+    pub fn fs_config(&self) -> FSConfig {
+        FSConfig::new(Some(self.dir.clone()))
+    }
+
+    pub fn address_options(&self) -> AddressOptions {
+        AddressOptions {
+            listen_address: self.listen_address.clone(),
+            advertise: self.advertise,
+            advertised_address: (!self.advertised_address.is_empty()).then(|| self.advertised_address.clone()),
+        }
+    }
+
+    pub fn download_options(&self) -> DownloadOptions {
+        DownloadOptions {
+            piece_timeout: Duration::from_secs(self.piece_timeout_secs),
+            piece_wait: Duration::from_secs(self.piece_wait_secs),
+            poll_interval: Duration::from_secs(self.download_poll_secs),
+            queue_depth: self.piece_queue_depth,
+            endgame_pieces: self.endgame_pieces,
+        }
+    }
+
+    pub fn dht_options(&self) -> DhtOptions {
+        DhtOptions {
+            bucket_size: self.dht_bucket_size,
+            alpha: self.dht_alpha,
+            provider_ttl: Duration::from_secs(self.dht_provider_ttl_secs),
+            max_providers_per_key: self.dht_max_providers_per_key,
+            max_keys_per_provider: self.dht_max_keys_per_provider,
+            max_provider_keys: self.dht_max_provider_keys,
+        }
+    }
+
+    pub fn discovery_options(&self) -> DiscoveryOptions {
+        DiscoveryOptions {
+            enabled: self.discovery.enabled,
+            group: self.discovery.group,
+            port: self.discovery.port,
+            interface: self.discovery.interface,
+            interval: Duration::from_secs(self.discovery.interval_secs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("rfs_test_{}_{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn layers_override_previous_ones() {
+        let dir = temp_dir("config_layers");
+        std::fs::write(format!("{dir}/{CONFIG_FILE_NAME}"), "
+            max_downloads = 5
+            piece_timeout_secs = 20
+            pex_interval_secs = 40
+            [discovery]
+            port = 9000
+        ").unwrap();
+        std::env::set_var("RFS_DIR", &dir);
+        std::env::set_var("RFS_PIECE_TIMEOUT_SECS", "25");
+        std::env::set_var("RFS_PEX_INTERVAL_SECS", "45");
+        std::env::set_var("RFS_DISCOVERY_PORT", "9001");
+        let mut cli = Table::new();
+        cli.insert("pex_interval_secs".to_string(), Value::Integer(50));
+
+        // the config file is found in the dir set by the environment
+        let config = PeerConfig::load(None, cli);
+        for name in ["RFS_DIR", "RFS_PIECE_TIMEOUT_SECS", "RFS_PEX_INTERVAL_SECS", "RFS_DISCOVERY_PORT"] {
+            std::env::remove_var(name);
+        }
+        let config = config.unwrap();
+        assert_eq!(config.dir, dir);
+        assert_eq!(config.max_downloads, 5);
+        assert_eq!(config.piece_timeout_secs, 25);
+        assert_eq!(config.pex_interval_secs, 50);
+        assert_eq!(config.discovery.port, 9001);
+        assert_eq!(config.discovery.group, DISCOVERY_GROUP);
+        assert_eq!(config.piece_queue_depth, PIECE_QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn options_are_built_from_config() {
+        let config = PeerConfig { piece_queue_depth: 2, dht_provider_ttl_secs: 5, ..Default::default() };
+        assert_eq!(config.download_options().queue_depth, 2);
+        assert_eq!(config.dht_options().provider_ttl, Duration::from_secs(5));
+        // the defaults of the options are the defaults of the config
+        assert_eq!(DownloadOptions::default().endgame_pieces, ENDGAME_PIECES);
+        assert_eq!(DhtOptions::default().bucket_size, DHT_BUCKET_SIZE);
+        assert_eq!(DiscoveryOptions::default().port, DISCOVERY_PORT);
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        assert!(PeerConfig::default().validate().is_ok());
+        let config = PeerConfig {
+            dir: String::new(),
+            listen_address: "localhost".to_string(),
+            advertise: AdvertiseMode::Public,
+            encryption: false,
+            require_encryption: true,
+            max_frame_size: DEFAULT_PIECE_SIZE as usize,
+            piece_queue_depth: 0,
+            dht_alpha: 0,
+            discovery: DiscoveryConfig { group: Ipv4Addr::new(10, 0, 0, 1), ..Default::default() },
+            ..Default::default()
+        };
+        let err = config.validate().unwrap_err();
+        for message in [
+            "dir should not be empty",
+            "listen_address localhost is invalid",
+            "advertised_address is required",
+            "require_encryption needs the encryption",
+            "max_frame_size should fit a piece",
+            "piece_queue_depth should be positive",
+            "dht_alpha should be positive",
+            "discovery.group 10.0.0.1 is not a multicast address",
+        ] {
+            assert!(err.contains(message), "{message} not in {err}");
+        }
+    }
+
+    #[test]
+    fn invalid_layers_are_rejected() {
+        let dir = temp_dir("config_invalid");
+        let path = format!("{dir}/{CONFIG_FILE_NAME}");
+        std::fs::write(&path, "unknown_setting = 1").unwrap();
+        assert!(PeerConfig::load(Some(path.clone()), Table::new()).unwrap_err().contains("unknown field"));
+        std::fs::write(&path, "max_downloads = ").unwrap();
+        assert!(PeerConfig::load(Some(path.clone()), Table::new()).unwrap_err().contains("Error when parsing config file"));
+        std::fs::write(&path, "max_downloads = 2").unwrap();
+        let mut cli = Table::new();
+        cli.insert("max_downloads".to_string(), Value::Integer(0));
+        assert!(PeerConfig::load(Some(path), cli).unwrap_err().contains("max_downloads should be positive"));
+    }
+}
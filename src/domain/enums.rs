@@ -6,3 +6,21 @@ pub enum PieceDownloadStatus {
     Downloading,
     Downloaded,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DownloadStatus {
+    Queued,
+    Running,
+    /// The task of the download is being aborted, the download is paused or cancelled once it stops.
+    Stopping,
+    Paused,
+    Completed,
+    Failed(String),
+    Cancelled,
+}
+
+impl DownloadStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, DownloadStatus::Completed | DownloadStatus::Failed(_) | DownloadStatus::Cancelled)
+    }
+}
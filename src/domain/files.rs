@@ -3,10 +3,12 @@ use base64::Engine;
 use base64::engine::general_purpose;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use uuid::Uuid;
+use crate::domain::config::FSConfig;
 use crate::domain::models::File;
+use crate::domain::names::sanitize_name;
+use crate::domain::storage::StorageLayout;
 use crate::peer::enums::FileStatus;
-use crate::values::DEFAULT_PIECE_SIZE;
+use crate::peer::part_file::{read_piece_at, PartFile};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RFSFile {
@@ -33,14 +35,14 @@ impl RFSFile {
         }
     }
 
-    pub async fn save_to_project_dir(&self) -> Result<(), String>{
-        let path = String::from("meta_files/")
-            + &self.data.name.split('.').next()
-            .ok_or("Failed to parse the file name, should be in format {name}.{extension}!")?
-            + ".rfs";
-        let contents = serde_json::to_string(&self.data).unwrap();
-        tokio::fs::write(path, contents).await.unwrap();
-        Ok(())
+    /// Saves the metafile into the metafiles dir of the layout, returns its path.
+    pub async fn save_to_storage(&self, storage: &StorageLayout) -> Result<String, String> {
+        let path = storage.metafile_path(&self.data.name)?;
+        let contents = serde_json::to_string(&self.data)
+            .map_err(|err| format!("Error when serializing metafile {err}"))?;
+        tokio::fs::write(&path, contents).await
+            .map_err(|err| format!("Error when writing metafile {path}: {err}"))?;
+        Ok(path)
     }
 
     pub fn save(&self, path: String) -> Result<(), String>{
@@ -49,40 +51,79 @@ impl RFSFile {
         Ok(())
     }
 
-    pub fn get_path(&self) -> String {
-        "files/".to_string() + &self.data.name
+    pub fn verify_piece(&self, piece: u64, content: &[u8]) -> bool {
+        match self.data.hashes.get(piece as usize) {
+            Some(hash) => hash_bytes(content).eq(hash),
+            None => false,
+        }
+    }
+
+    /// Checks the file on the disk against the length, the piece hashes and the hash of the whole
+    /// content. The file is read piece by piece, so it's never loaded into memory.
+    pub async fn verify_file(&self, path: &str) -> Result<bool, String> {
+        let mut handle = tokio::fs::File::open(path).await
+            .map_err(|err| format!("Error when opening file {path}: {err}"))?;
+        let length = handle.metadata().await
+            .map_err(|err| format!("Error when reading metadata of file {path}: {err}"))?.len();
+        let n_pieces = length.div_ceil(self.data.piece_size.max(1));
+        if length != self.data.length || n_pieces != self.data.hashes.len() as u64 {
+            return Ok(false);
+        }
+        let mut hasher = Sha256::new();
+        for piece in 0..n_pieces {
+            let content = read_piece_at(&mut handle, self.data.piece_size, length, piece).await?;
+            if !self.verify_piece(piece, &content) {
+                return Ok(false);
+            }
+            hasher.update(&content);
+        }
+        Ok(general_purpose::STANDARD.encode(hasher.finalize()) == self.data.hash)
+    }
+
+    pub fn content_id(&self) -> String {
+        content_id(self.data.piece_size, &self.data.hashes)
     }
+
+    /// Metafiles made by the old versions have random ids instead of the content ids.
+    pub fn is_legacy(&self) -> bool {
+        self.data.id != self.content_id()
+    }
+}
+
+/// Id of the file derived from the root of its piece hashes and the piece size, so the same
+/// content split into the same pieces always has the same id.
+pub fn content_id(piece_size: u64, hashes: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(piece_size.to_be_bytes());
+    for hash in hashes {
+        hasher.update((hash.len() as u64).to_be_bytes());
+        hasher.update(hash.as_bytes());
+    }
+    hasher.finalize().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn split_pieces(contents: &[u8], piece_size: u64) -> Vec<&[u8]> {
+    contents.chunks(piece_size.max(1) as usize).collect()
+}
+
+pub fn hash_bytes(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    general_purpose::STANDARD.encode(hasher.finalize())
 }
 
 
-pub fn generate_meta_file(host_address: String, path: &str) -> Result<RFSFile, String> {
-    let name = path
-        .split('/').last().ok_or("Unable to get name from path!")?
-        .to_owned();
+pub fn generate_meta_file(host_address: String, path: &str, piece_size: u64) -> Result<RFSFile, String> {
+    let name = sanitize_name(path.rsplit('/').next().ok_or("Unable to get name from path!")?);
     let contents = std::fs::read(path)
         .map_err(|err| format!("Error when reading file {err}"))?;
 
     let length = contents.len() as u64;
-    let mut hasher = Sha256::new();
-    hasher.update(&contents);
-
-    let file_id = Uuid::new_v4().to_string();
-    let hash = general_purpose::STANDARD.encode(hasher.finalize());
-
-    let hashes: Vec<String> = (0..f64::ceil(contents.len() as f64 / DEFAULT_PIECE_SIZE as f64) as usize)
-        .map(|i| {
-            let start = i*DEFAULT_PIECE_SIZE as usize;
-            let end = (i + 1)*DEFAULT_PIECE_SIZE as usize;
-            let piece = if end < contents.len() {
-                &contents[start..end]
-            } else {
-                &contents[start..]
-            };
-            let mut hasher = Sha256::new();
-            hasher.update(piece);
-            general_purpose::STANDARD.encode(hasher.finalize())
-        }
-        ).collect();
+
+    let hash = hash_bytes(&contents);
+
+    let hashes: Vec<String> = split_pieces(&contents, piece_size).into_iter().map(hash_bytes).collect();
+    let file_id = content_id(piece_size, &hashes);
 
     Ok(
         RFSFile {
@@ -92,15 +133,19 @@ pub fn generate_meta_file(host_address: String, path: &str) -> Result<RFSFile, S
                 name,
                 length,
                 peers: vec![host_address],
-                piece_size: DEFAULT_PIECE_SIZE,
+                piece_size,
                 hashes,
+                swarm_secret: None,
+                allowed_peers: vec![],
+                publisher: None,
+                signature: None,
             },
             status: Default::default(),
         })
 }
 
-pub fn refresh_file_status(file: &mut RFSFile, files_dir: String) {
-    match std::fs::read(files_dir + "/" + &file.data.name) {
+pub fn refresh_file_status(file: &mut RFSFile, fs_config: &FSConfig) {
+    match std::fs::read(fs_config.storage.file_path(&file.data)) {
         Ok(_) => {
             // todo: check if hash matches
             file.status = Some(FileStatus::Downloaded);
@@ -108,7 +153,9 @@ pub fn refresh_file_status(file: &mut RFSFile, files_dir: String) {
         Err(err) => {
             match err.kind() {
                 ErrorKind::NotFound => {
-                    if file.status != Some(FileStatus::Downloading) {
+                    if PartFile::exists(fs_config, file) {
+                        file.status = Some(FileStatus::Downloading);
+                    } else if file.status != Some(FileStatus::Downloading) {
                         file.status = Some(FileStatus::NotDownloaded);
                     }
                 }
@@ -119,3 +166,64 @@ pub fn refresh_file_status(file: &mut RFSFile, files_dir: String) {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "files/image.HEIC";
+
+    #[test]
+    fn republished_file_has_same_id() {
+        let first = generate_meta_file("127.0.0.1:8000".to_string(), PATH, 16384).unwrap();
+        let second = generate_meta_file("10.0.0.2:9000".to_string(), PATH, 16384).unwrap();
+        assert_eq!(first.data.id, second.data.id);
+        assert_eq!(first.data.id, first.content_id());
+        assert!(!first.is_legacy());
+    }
+
+    #[test]
+    fn id_depends_on_pieces() {
+        let file = generate_meta_file("127.0.0.1:8000".to_string(), PATH, 16384).unwrap();
+        let other_size = generate_meta_file("127.0.0.1:8000".to_string(), PATH, 32768).unwrap();
+        assert_ne!(file.data.id, other_size.data.id);
+        let mut hashes = file.data.hashes.clone();
+        hashes.swap(0, 1);
+        assert_ne!(content_id(16384, &hashes), file.data.id);
+        // hex, so the id is a valid file name
+        assert!(file.data.id.len() == 64 && file.data.id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn uuid_ids_are_legacy() {
+        let mut file = generate_meta_file("127.0.0.1:8000".to_string(), PATH, 16384).unwrap();
+        file.data.id = "0155d08b-609b-45fa-804d-53456c2a863d".to_string();
+        assert!(file.is_legacy());
+        let legacy = RFSFile::from_path_sync("meta_files/image.rfs");
+        assert!(legacy.is_legacy());
+        assert_eq!(legacy.content_id(), file.content_id());
+    }
+
+    #[tokio::test]
+    async fn file_is_verified_against_metafile() {
+        let file = generate_meta_file("127.0.0.1:8000".to_string(), PATH, 16384).unwrap();
+        assert!(file.verify_file(PATH).await.unwrap());
+
+        let path = std::env::temp_dir().join(format!("rfs_verify_file_{}", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let mut contents = std::fs::read(PATH).unwrap();
+        contents[20000] ^= 1;
+        std::fs::write(&path, &contents).unwrap();
+        assert!(!file.verify_file(&path).await.unwrap());
+        contents[20000] ^= 1;
+        std::fs::write(&path, &contents[..contents.len() - 1]).unwrap();
+        assert!(!file.verify_file(&path).await.unwrap());
+
+        // the pieces match, only the hash of the whole content doesn't
+        let mut other_hash = file.clone();
+        other_hash.data.hash = hash_bytes(b"other");
+        assert!(!other_hash.verify_file(PATH).await.unwrap());
+        let _ = std::fs::remove_file(&path);
+        assert!(file.verify_file(&path).await.is_err());
+    }
+}
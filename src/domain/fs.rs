@@ -11,7 +11,7 @@ fn check_folder(path: &str) {
 
 pub fn check_folders(config: &FSConfig) {
     check_folder(&config.rfs_dir);
-    check_folder(&config.metafiles_dir);
-    check_folder(&config.files_dir);
-    check_folder(&config.file_parts_dir);
+    for dir in config.storage.dirs() {
+        check_folder(dir);
+    }
 }
\ No newline at end of file
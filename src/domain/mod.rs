@@ -4,4 +4,8 @@ pub mod files;
 pub mod models;
 pub mod fs;
 pub mod config;
-pub mod enums;
\ No newline at end of file
+pub mod enums;
+pub mod bitfield;
+pub mod storage;
+pub mod signing;
+pub mod names;
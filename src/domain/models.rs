@@ -11,4 +11,16 @@ pub struct File {
     pub peers: Vec<String>,  // todo: rename to seeds
     pub piece_size: u64,
     pub hashes: Vec<String>,
+    /// Secret the peers prove the knowledge of to get the file, files with the same secret form a private swarm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swarm_secret: Option<String>,
+    /// Keys of the peers allowed to get the file, any peer may get it if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_peers: Vec<String>,
+    /// Ed25519 key of the publisher that signed the metafile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    /// Signature of the publisher over all the other fields.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
@@ -0,0 +1,90 @@
+use crate::domain::models::File;
+use crate::values::{MAX_FILE_ID_LENGTH, MAX_FILE_NAME_LENGTH};
+
+// Names and ids of the files come from metafiles made by other peers and are used as the names of
+// the files in the rfs dir. A name must be a single path component that is valid on all the
+// platforms, so a metafile can't make a peer write outside of its dirs.
+
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const REPLACEMENT: char = '_';
+const DEFAULT_NAME: &str = "file";
+
+/// Separators and the characters not allowed in the file names on some of the platforms.
+fn is_unsafe_char(c: char) -> bool {
+    c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|')
+}
+
+/// Reserved device names on Windows, with any extension.
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).trim_end();
+    RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err("File name is empty".to_string());
+    }
+    if name.len() > MAX_FILE_NAME_LENGTH {
+        return Err(format!("File name is longer than {MAX_FILE_NAME_LENGTH} bytes"));
+    }
+    if name == "." || name == ".." {
+        return Err(format!("File name {name:?} is not allowed"));
+    }
+    if let Some(c) = name.chars().find(|c| is_unsafe_char(*c)) {
+        return Err(format!("File name {name:?} contains the character {c:?}"));
+    }
+    if name.ends_with(['.', ' ']) {
+        return Err(format!("File name {name:?} ends with a dot or a space"));
+    }
+    if is_reserved(name) {
+        return Err(format!("File name {name:?} is a reserved name"));
+    }
+    Ok(())
+}
+
+/// Returns a valid name close to the given one, valid names are returned unchanged.
+pub fn sanitize_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if is_unsafe_char(c) { REPLACEMENT } else { c })
+        .collect();
+    let mut name = name.trim_end_matches(['.', ' ']).to_string();
+    if is_reserved(&name) {
+        name.insert(0, REPLACEMENT);
+    }
+    let mut end = name.len().min(MAX_FILE_NAME_LENGTH);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let name = name[..end].trim_end_matches(['.', ' ']);
+    match name.is_empty() {
+        true => DEFAULT_NAME.to_string(),
+        false => name.to_string(),
+    }
+}
+
+/// Ids name the part files, only ascii letters, digits, `-` and `_` are allowed.
+pub fn validate_id(id: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > MAX_FILE_ID_LENGTH {
+        return Err(format!("File id must have from 1 to {MAX_FILE_ID_LENGTH} characters"));
+    }
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("File id {id:?} contains not allowed characters"));
+    }
+    Ok(())
+}
+
+/// Checks the names of the metafile loaded from the untrusted source. The file with an unsafe name
+/// is renamed, the file with an invalid id is rejected since the peers refer to the file by its id.
+pub fn check_file_names(file: &mut File) -> Result<(), String> {
+    validate_id(&file.id)?;
+    if let Err(err) = validate_name(&file.name) {
+        let name = sanitize_name(&file.name);
+        println!("{err}, renaming the file to {name:?}");
+        file.name = name;
+    }
+    Ok(())
+}
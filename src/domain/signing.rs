@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use base64::Engine;
+use base64::engine::general_purpose;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde_cbor::ser::to_vec_packed;
+use crate::domain::config::FSConfig;
+use crate::domain::models::File;
+
+/// Bytes the metafile signature is made over, the packed CBOR encoding of the file data without
+/// the signature. The publisher key is a part of the signed data.
+pub fn canonical_bytes(file: &File) -> Result<Vec<u8>, String> {
+    let unsigned = File { signature: None, ..file.clone() };
+    to_vec_packed(&unsigned).map_err(|err| format!("Failed to serialize metafile {err}"))
+}
+
+pub fn encode_key(key: &VerifyingKey) -> String {
+    general_purpose::STANDARD.encode(key.as_bytes())
+}
+
+fn decode_key(key: &str) -> Result<VerifyingKey, String> {
+    let bytes = general_purpose::STANDARD.decode(key).map_err(|err| format!("Invalid publisher key {key}: {err}"))?;
+    let bytes = <[u8; 32]>::try_from(bytes.as_slice()).map_err(|_| format!("Invalid publisher key {key}"))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|err| format!("Invalid publisher key {key}: {err}"))
+}
+
+/// Sets the publisher of the file to the key and signs the file data.
+pub fn sign_file(file: &mut File, key: &SigningKey) -> Result<(), String> {
+    file.publisher = Some(encode_key(&key.verifying_key()));
+    let signature = key.sign(&canonical_bytes(file)?);
+    file.signature = Some(general_purpose::STANDARD.encode(signature.to_bytes()));
+    Ok(())
+}
+
+/// Checks the signature of the file, returns the publisher key of a signed file.
+pub fn verify_file(file: &File) -> Result<Option<String>, String> {
+    let (publisher, signature) = match (&file.publisher, &file.signature) {
+        (None, None) => return Ok(None),
+        (Some(publisher), Some(signature)) => (publisher, signature),
+        _ => return Err(format!("Metafile of {} has a publisher without a signature or the other way", file.name)),
+    };
+    let key = decode_key(publisher)?;
+    let signature = general_purpose::STANDARD.decode(signature)
+        .map_err(|err| format!("Invalid metafile signature {err}"))
+        .and_then(|bytes| Signature::from_slice(&bytes).map_err(|err| format!("Invalid metafile signature {err}")))?;
+    key.verify(&canonical_bytes(file)?, &signature)
+        .map_err(|_| format!("Metafile of {} has a wrong signature, it was changed after it was signed", file.name))?;
+    Ok(Some(publisher.clone()))
+}
+
+/// Publishers whose metafiles are accepted, stored in the rfs dir one per line as the key and an
+/// optional name. Lines starting with `#` are comments.
+#[derive(Default, Debug, Clone)]
+pub struct TrustStore {
+    path: String,
+    publishers: BTreeMap<String, String>,
+}
+
+impl TrustStore {
+    pub fn load(fs_config: &FSConfig) -> Self {
+        let path = fs_config.rfs_dir.clone() + "/trusted_publishers";
+        let publishers = std::fs::read_to_string(&path).unwrap_or_default()
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.split_once(char::is_whitespace) {
+                Some((key, name)) => (key.to_string(), name.trim().to_string()),
+                None => (line.to_string(), String::new()),
+            })
+            .collect();
+        Self { path, publishers }
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let contents = self.publishers.iter()
+            .map(|(key, name)| format!("{key} {name}").trim_end().to_string() + "\n")
+            .collect::<String>();
+        std::fs::write(&self.path, contents).map_err(|err| format!("Error when saving trust store {}: {err}", self.path))
+    }
+
+    pub fn add(&mut self, key: String, name: String) -> Result<(), String> {
+        decode_key(&key)?;
+        self.publishers.insert(key, name);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.publishers.remove(key).is_some()
+    }
+
+    pub fn publishers(&self) -> &BTreeMap<String, String> {
+        &self.publishers
+    }
+
+    pub fn is_trusted(&self, key: &str) -> bool {
+        self.publishers.contains_key(key)
+    }
+
+    /// Checks the metafile before it's accepted: signed metafiles must have a valid signature of a
+    /// trusted publisher or of the local peer itself, unsigned ones are accepted only if signatures
+    /// are not required.
+    pub fn check_file(&self, file: &File, own_key: Option<&VerifyingKey>, require_signed: bool) -> Result<(), String> {
+        match verify_file(file)? {
+            Some(publisher) if self.is_trusted(&publisher) || own_key.is_some_and(|key| encode_key(key) == publisher) => Ok(()),
+            Some(publisher) => Err(format!("Metafile of {} is signed by the untrusted publisher {publisher}", file.name)),
+            None if require_signed => Err(format!("Metafile of {} is not signed", file.name)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::files::generate_meta_file;
+    use super::*;
+
+    fn signed_file(key: &SigningKey) -> File {
+        let mut file = generate_meta_file("127.0.0.1:8000".to_string(), "files/image.HEIC", 16384).unwrap().data;
+        sign_file(&mut file, key).unwrap();
+        file
+    }
+
+    fn trust_store(keys: &[&SigningKey]) -> TrustStore {
+        let mut store = TrustStore::default();
+        for key in keys {
+            store.add(encode_key(&key.verifying_key()), "publisher".to_string()).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn tampered_metafile_is_rejected() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let store = trust_store(&[&key]);
+        let file = signed_file(&key);
+        assert_eq!(verify_file(&file), Ok(Some(encode_key(&key.verifying_key()))));
+        assert!(store.check_file(&file, None, true).is_ok());
+
+        let mut changed_hash = file.clone();
+        changed_hash.hashes[0] = changed_hash.hashes[1].clone();
+        let mut changed_name = file.clone();
+        changed_name.name = "other.HEIC".to_string();
+        let mut changed_peers = file.clone();
+        changed_peers.peers.push("10.0.0.1:8000".to_string());
+        for tampered in [changed_hash, changed_name, changed_peers] {
+            assert!(verify_file(&tampered).is_err());
+            assert!(store.check_file(&tampered, None, false).is_err());
+        }
+    }
+
+    #[test]
+    fn publisher_of_signature_cant_be_replaced() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let other_key = SigningKey::from_bytes(&[2; 32]);
+        let mut file = signed_file(&key);
+        file.publisher = Some(encode_key(&other_key.verifying_key()));
+        assert!(trust_store(&[&key, &other_key]).check_file(&file, None, false).is_err());
+
+        let mut unsigned = signed_file(&key);
+        unsigned.signature = None;
+        assert!(verify_file(&unsigned).is_err());
+    }
+
+    #[test]
+    fn untrusted_publisher_is_rejected() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let untrusted = SigningKey::from_bytes(&[2; 32]);
+        let store = trust_store(&[&key]);
+        let file = signed_file(&untrusted);
+        assert!(verify_file(&file).is_ok());
+        assert!(store.check_file(&file, None, false).unwrap_err().contains("untrusted publisher"));
+        // the metafiles published by the peer itself are trusted
+        assert!(store.check_file(&file, Some(&untrusted.verifying_key()), true).is_ok());
+    }
+
+    #[test]
+    fn unsigned_metafile_is_rejected_only_when_signatures_are_required() {
+        let file = generate_meta_file("127.0.0.1:8000".to_string(), "files/image.HEIC", 16384).unwrap().data;
+        let store = TrustStore::default();
+        assert!(store.check_file(&file, None, false).is_ok());
+        assert!(store.check_file(&file, None, true).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use crate::domain::models::File;
+use crate::domain::names::sanitize_name;
+
+/// Locations of the metafiles, the downloaded files and the partially downloaded files under the
+/// rfs dir. All the paths of the files a peer works with are built here.
+#[derive(Default, Clone, Debug)]
+pub struct StorageLayout {
+    pub metafiles_dir: String,
+    pub files_dir: String,
+    pub file_parts_dir: String,
+}
+
+impl StorageLayout {
+    pub fn new(rfs_dir: &str) -> Self {
+        Self {
+            metafiles_dir: rfs_dir.to_string() + "/metafiles",
+            files_dir: rfs_dir.to_string() + "/files",
+            file_parts_dir: rfs_dir.to_string() + "/file_parts",
+        }
+    }
+
+    pub fn dirs(&self) -> [&str; 3] {
+        [&self.metafiles_dir, &self.files_dir, &self.file_parts_dir]
+    }
+
+    /// Path of the downloaded or seeded file. Names and ids are sanitized in all the paths, so the
+    /// path stays in its dir whatever the metafile contains.
+    pub fn file_path(&self, file: &File) -> String {
+        self.files_dir.clone() + "/" + &sanitize_name(&file.name)
+    }
+
+    /// Checks the file doesn't take the path of another file. The files are kept by their names,
+    /// only the metafiles of the same content may share the path.
+    pub fn check_collision<'a>(&self, file: &File, files: impl IntoIterator<Item = &'a File>) -> Result<(), String> {
+        let path = self.file_path(file);
+        match files.into_iter().find(|other| other.id != file.id && other.hash != file.hash && self.file_path(other) == path) {
+            Some(other) => Err(format!("File {} has the same name {} as file {}", file.id, file.name, other.id)),
+            None => Ok(()),
+        }
+    }
+
+    /// Path of the metafile for the file with the given name, the extension is replaced with `.rfs`.
+    pub fn metafile_path(&self, name: &str) -> Result<String, String> {
+        let name = sanitize_name(name.rsplit('/').next().unwrap_or(name));
+        let stem = name.split('.').next()
+            .filter(|stem| !stem.is_empty())
+            .ok_or("Failed to parse the file name, should be in format {name}.{extension}!")?;
+        Ok(self.metafiles_dir.clone() + "/" + stem + ".rfs")
+    }
+
+    pub fn part_path(&self, file: &File) -> String {
+        self.file_parts_dir.clone() + "/" + &sanitize_name(&file.id) + ".part"
+    }
+
+    pub fn bitfield_path(&self, file: &File) -> String {
+        self.file_parts_dir.clone() + "/" + &sanitize_name(&file.id) + ".bitfield"
+    }
+
+    /// Moves the metafiles and the files from the `meta_files` and `files` dirs of the project dir,
+    /// where the old versions kept them, into this layout. Files already present in the layout are
+    /// left in place. Returns the number of moved files.
+    pub fn migrate_project_dir(&self, project_dir: &Path) -> Result<usize, String> {
+        let mut moved = 0;
+        for (source, target, extension) in [
+            ("meta_files", &self.metafiles_dir, Some("rfs")),
+            ("files", &self.files_dir, None),
+        ] {
+            moved += move_dir_files(&project_dir.join(source), Path::new(target), extension)?;
+        }
+        if project_dir.join("file_pieces").exists() {
+            // the pieces were stored one per file, the downloads have to be started again
+            println!("Skipping {:?}, downloads in the old format can't be resumed", project_dir.join("file_pieces"));
+        }
+        Ok(moved)
+    }
+}
+
+/// Moves the regular files of the dir, only the ones with the extension if it's given.
+fn move_dir_files(source: &Path, target: &Path, extension: Option<&str>) -> Result<usize, String> {
+    let entries = match std::fs::read_dir(source) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(format!("Error when reading dir {source:?}: {err}")),
+    };
+    std::fs::create_dir_all(target)
+        .map_err(|err| format!("Error when creating dir {target:?}: {err}"))?;
+    let mut moved = 0;
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("Error when reading dir {source:?}: {err}"))?;
+        let path = entry.path();
+        let skipped_extension = extension.is_some_and(|e| path.extension().and_then(|e| e.to_str()) != Some(e));
+        if !entry.file_type().is_ok_and(|t| t.is_file()) || skipped_extension {
+            continue;
+        }
+        let destination = target.join(entry.file_name());
+        if destination.exists() {
+            println!("Skipping {:?}, {:?} already exists", entry.path(), destination);
+            continue;
+        }
+        move_file(&entry.path(), &destination)?;
+        println!("Moved {:?} to {:?}", entry.path(), destination);
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// Renames the file, or copies and removes it when the target is on another filesystem.
+fn move_file(source: &Path, destination: &Path) -> Result<(), String> {
+    if std::fs::rename(source, destination).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(source, destination)
+        .map_err(|err| format!("Error when copying {source:?} to {destination:?}: {err}"))?;
+    std::fs::remove_file(source)
+        .map_err(|err| format!("Error when removing {source:?}: {err}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::domain::config::FSConfig;
+    use super::*;
+
+    fn file(id: &str, hash: &str, name: &str) -> File {
+        File {
+            id: id.to_string(),
+            hash: hash.to_string(),
+            name: name.to_string(),
+            length: 0,
+            peers: vec![],
+            piece_size: 1,
+            hashes: vec![],
+            swarm_secret: None,
+            allowed_peers: vec![],
+            publisher: None,
+            signature: None,
+        }
+    }
+
+    /// Project dir of an old version, with a metafile and a file the layout already has.
+    fn project_dir(storage: &StorageLayout) -> PathBuf {
+        let project_dir = PathBuf::from(&storage.files_dir).parent().unwrap().join("project");
+        for dir in ["meta_files", "files", "files/nested", "file_pieces"] {
+            fs::create_dir_all(project_dir.join(dir)).unwrap();
+        }
+        fs::write(project_dir.join("meta_files/a.rfs"), "a").unwrap();
+        fs::write(project_dir.join("meta_files/b.rfs"), "new b").unwrap();
+        fs::write(project_dir.join("meta_files/notes.txt"), "notes").unwrap();
+        fs::write(project_dir.join("files/a.pdf"), "a content").unwrap();
+        fs::write(project_dir.join("file_pieces/a.0"), "piece").unwrap();
+        fs::write(storage.metafiles_dir.clone() + "/b.rfs", "b").unwrap();
+        project_dir
+    }
+
+    #[test]
+    fn project_dir_is_migrated() {
+        let storage = FSConfig::temp("storage_migration").storage;
+        let project_dir = project_dir(&storage);
+
+        assert_eq!(storage.migrate_project_dir(&project_dir).unwrap(), 2);
+        assert_eq!(fs::read_to_string(storage.metafiles_dir.clone() + "/a.rfs").unwrap(), "a");
+        assert_eq!(fs::read_to_string(storage.files_dir.clone() + "/a.pdf").unwrap(), "a content");
+        assert!(!project_dir.join("meta_files/a.rfs").exists());
+        assert!(!project_dir.join("files/a.pdf").exists());
+        // files that are not metafiles, dirs, pieces and files already in the layout stay in place
+        assert!(!Path::new(&(storage.metafiles_dir.clone() + "/notes.txt")).exists());
+        assert!(project_dir.join("files/nested").exists());
+        assert!(project_dir.join("file_pieces/a.0").exists());
+        assert_eq!(fs::read_to_string(storage.metafiles_dir.clone() + "/b.rfs").unwrap(), "b");
+        assert!(project_dir.join("meta_files/b.rfs").exists());
+
+        // migrating again doesn't move anything
+        assert_eq!(storage.migrate_project_dir(&project_dir).unwrap(), 0);
+    }
+
+    #[test]
+    fn missing_project_dirs_are_skipped() {
+        let storage = FSConfig::temp("storage_migration_missing").storage;
+        assert_eq!(storage.migrate_project_dir(Path::new("/nonexistent/project")).unwrap(), 0);
+    }
+
+    #[test]
+    fn files_with_same_name_collide() {
+        let storage = StorageLayout::new("/rfs");
+        let files = [file("1", "hash 1", "report.pdf")];
+        assert!(storage.check_collision(&file("2", "hash 2", "report.pdf"), &files).is_err());
+        // sanitized names are compared
+        assert!(storage.check_collision(&file("2", "hash 2", "report.pdf. "), &files).is_err());
+        // the same file and the same content may share the path
+        assert!(storage.check_collision(&file("1", "hash 1", "report.pdf"), &files).is_ok());
+        assert!(storage.check_collision(&file("2", "hash 1", "report.pdf"), &files).is_ok());
+        assert!(storage.check_collision(&file("2", "hash 2", "other.pdf"), &files).is_ok());
+    }
+}
@@ -2,4 +2,5 @@ pub mod values;
 pub mod domain;
 pub mod utils;
 pub mod peer;
+pub mod control;
 pub mod ui;
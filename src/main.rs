@@ -1,5 +1,4 @@
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tokio::time::Instant;
 use distributed_fs::domain::config::FSConfig;
 use distributed_fs::domain::fs::check_folders;
@@ -11,7 +10,7 @@ async fn main() {
     let fs_config = FSConfig::new(None);
     check_folders(&fs_config);
     
-    let sharable_state_container = Arc::new(Mutex::new(State::new(fs_config.clone())));
+    let sharable_state_container = Arc::new(State::new(fs_config.clone()));
     
     let address = "127.0.0.1:8003".to_string();
     let mut client = Client::new(address.clone(), sharable_state_container.clone());
@@ -0,0 +1,117 @@
+use base64::Engine;
+use base64::engine::general_purpose;
+use hmac::{Hmac, KeyInit, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use crate::domain::models::File;
+use crate::peer::noise::encode_public_key;
+
+// Access control of the private files. A metafile may carry a swarm secret, the files with the same
+// secret form a private swarm. The peers prove the knowledge of the secret with the HMAC of the
+// nonce of the connection and the file id, so the proof can't be replayed on another connection and
+// the secret is never sent. A metafile may also list the keys of the peers allowed to get the file,
+// the key of a peer is known only on the encrypted connections.
+
+pub const NONCE_SIZE: usize = 32;
+
+/// Random secret for a new private swarm.
+pub fn generate_swarm_secret() -> String {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    general_purpose::STANDARD.encode(secret)
+}
+
+pub fn generate_nonce() -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+fn access_mac(secret: &str, nonce: &[u8], file_id: &str) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as KeyInit>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(nonce);
+    mac.update(file_id.as_bytes());
+    mac
+}
+
+pub fn prove_access(secret: &str, nonce: &[u8], file_id: &str) -> Vec<u8> {
+    access_mac(secret, nonce, file_id).finalize().into_bytes().to_vec()
+}
+
+/// Checks the proof in constant time.
+pub fn verify_access(secret: &str, nonce: &[u8], file_id: &str, proof: &[u8]) -> bool {
+    access_mac(secret, nonce, file_id).verify_slice(proof).is_ok()
+}
+
+/// Whether the file is served only to some peers.
+pub fn is_private(file: &File) -> bool {
+    file.swarm_secret.is_some() || !file.allowed_peers.is_empty()
+}
+
+/// Whether the peer with the key, which proved the knowledge of the swarm secret or not, may get the file.
+pub fn is_allowed(file: &File, remote_key: Option<&[u8]>, proved_secret: bool) -> bool {
+    let allowed_key = file.allowed_peers.is_empty()
+        || remote_key.is_some_and(|key| file.allowed_peers.contains(&encode_public_key(key)));
+    allowed_key && (file.swarm_secret.is_none() || proved_secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::files::generate_meta_file;
+    use super::*;
+
+    const FILE_ID: &str = "file";
+
+    fn file(swarm_secret: Option<String>, allowed_peers: Vec<String>) -> File {
+        let mut file = generate_meta_file("127.0.0.1:8000".to_string(), "files/image.HEIC", 16384).unwrap().data;
+        file.swarm_secret = swarm_secret;
+        file.allowed_peers = allowed_peers;
+        file
+    }
+
+    #[test]
+    fn proof_is_bound_to_secret_nonce_and_file() {
+        let secret = generate_swarm_secret();
+        let nonce = generate_nonce();
+        let proof = prove_access(&secret, &nonce, FILE_ID);
+        assert!(verify_access(&secret, &nonce, FILE_ID, &proof));
+
+        assert!(!verify_access(&secret, &nonce, FILE_ID, &prove_access(&generate_swarm_secret(), &nonce, FILE_ID)));
+        assert!(!verify_access(&secret, &generate_nonce(), FILE_ID, &proof));
+        assert!(!verify_access(&secret, &nonce, "other", &proof));
+        assert!(!verify_access(&secret, &nonce, FILE_ID, &proof[..proof.len() - 1]));
+        assert!(!verify_access(&secret, &nonce, FILE_ID, &[]));
+        let mut wrong_proof = proof.clone();
+        wrong_proof[0] ^= 1;
+        assert!(!verify_access(&secret, &nonce, FILE_ID, &wrong_proof));
+    }
+
+    #[test]
+    fn secret_must_be_proved() {
+        let public = file(None, vec![]);
+        assert!(!is_private(&public));
+        assert!(is_allowed(&public, None, false));
+
+        let private = file(Some(generate_swarm_secret()), vec![]);
+        assert!(is_private(&private));
+        assert!(!is_allowed(&private, None, false));
+        assert!(!is_allowed(&private, Some(&[1; 32]), false));
+        assert!(is_allowed(&private, None, true));
+    }
+
+    #[test]
+    fn key_must_be_allowed() {
+        let key = [1u8; 32];
+        let private = file(None, vec![encode_public_key(&key)]);
+        assert!(is_private(&private));
+        // the key is known only on the encrypted connections
+        assert!(!is_allowed(&private, None, true));
+        assert!(!is_allowed(&private, Some(&[2; 32]), true));
+        assert!(is_allowed(&private, Some(&key), false));
+
+        let both = file(Some(generate_swarm_secret()), vec![encode_public_key(&key)]);
+        assert!(!is_allowed(&both, Some(&key), false));
+        assert!(!is_allowed(&both, Some(&[2; 32]), true));
+        assert!(is_allowed(&both, Some(&key), true));
+    }
+}
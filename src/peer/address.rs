@@ -0,0 +1,79 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use crate::domain::config::{AddressOptions, AdvertiseMode};
+
+impl AddressOptions {
+    /// Address the other peers should connect to.
+    pub fn resolve_advertised_address(&self) -> Result<String, String> {
+        let listen_address: SocketAddr = self.listen_address.parse()
+            .map_err(|err| format!("Invalid listen address {}: {err}", self.listen_address))?;
+        let address = match self.advertise {
+            AdvertiseMode::Manual => match &self.advertised_address {
+                Some(address) => address.clone(),
+                None if listen_address.ip().is_unspecified() => {
+                    return Err(format!(
+                        "Listen address {listen_address} can't be advertised, set the advertised address or use the lan mode"
+                    ));
+                }
+                None => listen_address.to_string(),
+            },
+            AdvertiseMode::Lan => SocketAddr::new(detect_lan_ip()?, listen_address.port()).to_string(),
+            AdvertiseMode::Public => {
+                let address = self.advertised_address.as_ref()
+                    .ok_or("Public mode requires the advertised address")?;
+                with_default_port(address, listen_address.port())
+            }
+        };
+        let valid = address.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+        if !valid {
+            return Err(format!("Advertised address {address} should be in format host:port"));
+        }
+        Ok(address)
+    }
+}
+
+/// Checks the host of the address reported by the remote peer is the host the connection came
+/// from. Host names can't be checked without resolving them and are not accepted.
+pub fn is_same_host(address: &str, remote_address: &str) -> bool {
+    match (address.parse::<SocketAddr>(), remote_address.parse::<SocketAddr>()) {
+        (Ok(address), Ok(remote_address)) => address.ip().to_canonical() == remote_address.ip().to_canonical(),
+        _ => false,
+    }
+}
+
+/// Appends the port to the host if it has none, IPv6 addresses are put in brackets.
+fn with_default_port(address: &str, port: u16) -> String {
+    if address.parse::<SocketAddr>().is_ok() {
+        return address.to_string();
+    }
+    match address.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, port).to_string(),
+        Err(_) if address.contains(':') => address.to_string(),
+        Err(_) => format!("{address}:{port}"),
+    }
+}
+
+/// Address of the interface the default route goes through. Connecting a UDP socket only selects
+/// the route, nothing is sent.
+pub fn detect_lan_ip() -> Result<IpAddr, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|err| format!("Failed to detect LAN address: {err}"))?;
+    socket.connect("8.8.8.8:80").map_err(|err| format!("Failed to detect LAN address: {err}"))?;
+    let ip = socket.local_addr().map_err(|err| format!("Failed to detect LAN address: {err}"))?.ip();
+    if ip.is_unspecified() || ip.is_loopback() {
+        return Err(format!("No LAN interface found, detected address {ip}"));
+    }
+    Ok(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_are_compared_without_ports() {
+        assert!(is_same_host("10.0.0.1:8000", "10.0.0.1:51000"));
+        assert!(is_same_host("[::ffff:10.0.0.1]:8000", "10.0.0.1:51000"));
+        assert!(!is_same_host("10.0.0.2:8000", "10.0.0.1:51000"));
+        assert!(!is_same_host("localhost:8000", "127.0.0.1:51000"));
+        assert!(!is_same_host("10.0.0.1", "10.0.0.1:51000"));
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Least recently used cache bounded by the total size of the entries, the least recently used
+/// entries are evicted when a new entry doesn't fit.
+pub struct LruCache<K, V> {
+    entries: HashMap<K, (V, usize, u64)>,
+    // entries by the tick of their last use
+    order: BTreeMap<u64, K>,
+    tick: u64,
+    size: usize,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Default::default(),
+            order: Default::default(),
+            tick: 0,
+            size: 0,
+            capacity,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&mut V> {
+        let (_, _, tick) = self.entries.get_mut(key)?;
+        self.order.remove(tick);
+        self.tick += 1;
+        *tick = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.entries.get_mut(key).map(|(value, _, _)| value)
+    }
+
+    /// Inserts the value, entries larger than the whole cache are not stored.
+    pub fn insert(&mut self, key: K, value: V, size: usize) {
+        self.remove(&key);
+        if size > self.capacity {
+            return;
+        }
+        while self.size + size > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            if let Some((_, oldest_size, _)) = self.entries.remove(&oldest) {
+                self.size -= oldest_size;
+            }
+        }
+        self.tick += 1;
+        self.size += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, size, self.tick));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, size, tick) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        self.size -= size;
+        Some(value)
+    }
+
+    pub fn retain(&mut self, f: impl Fn(&K) -> bool) {
+        let keys = self.entries.keys().filter(|k| !f(k)).cloned().collect::<Vec<K>>();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(cache: &LruCache<u32, u32>) -> Vec<u32> {
+        let mut keys = cache.entries.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn evicts_least_recently_used_by_size() {
+        let mut cache = LruCache::new(10);
+        cache.insert(1, 1, 4);
+        cache.insert(2, 2, 4);
+        assert_eq!(cache.size, 8);
+        // only the oldest entry is evicted when it frees enough space
+        cache.insert(3, 3, 6);
+        assert_eq!(keys(&cache), vec![2, 3]);
+        assert_eq!(cache.size, 10);
+        // needs the space of both entries
+        cache.insert(4, 4, 7);
+        assert_eq!(keys(&cache), vec![4]);
+        assert_eq!(cache.size, 7);
+    }
+
+    #[test]
+    fn hit_moves_entry_to_front() {
+        let mut cache = LruCache::new(3);
+        cache.insert(1, 1, 1);
+        cache.insert(2, 2, 1);
+        cache.insert(3, 3, 1);
+        assert_eq!(cache.get(&1), Some(&mut 1));
+        cache.insert(4, 4, 1);
+        assert_eq!(keys(&cache), vec![1, 3, 4]);
+        assert!(cache.get(&2).is_none());
+    }
+
+    #[test]
+    fn reinsert_replaces_entry_size() {
+        let mut cache = LruCache::new(10);
+        cache.insert(1, 1, 8);
+        cache.insert(1, 2, 2);
+        assert_eq!(cache.size, 2);
+        assert_eq!(cache.get(&1), Some(&mut 2));
+        cache.insert(2, 2, 8);
+        assert_eq!(keys(&cache), vec![1, 2]);
+    }
+
+    #[test]
+    fn entries_larger_than_capacity_are_not_stored() {
+        let mut cache = LruCache::new(10);
+        cache.insert(1, 1, 5);
+        cache.insert(2, 2, 11);
+        assert_eq!(keys(&cache), vec![1]);
+        assert_eq!(cache.size, 5);
+    }
+
+    #[test]
+    fn retain_and_remove_free_space() {
+        let mut cache = LruCache::new(10);
+        for key in 0..5 {
+            cache.insert(key, key, 2);
+        }
+        cache.retain(|key| key % 2 == 0);
+        assert_eq!(keys(&cache), vec![0, 2, 4]);
+        assert_eq!(cache.remove(&2), Some(2));
+        assert_eq!((cache.len(), cache.size, cache.order.len()), (2, 4, 2));
+    }
+}
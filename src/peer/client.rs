@@ -3,10 +3,25 @@ use crate::peer::state::{KnownPeer, SharableStateContainer};
 use tokio::fs;
 use crate::domain::config::FSConfig;
 use crate::domain::files::{generate_meta_file, RFSFile};
+use crate::domain::names::check_file_names;
+use crate::domain::signing::{sign_file, TrustStore};
+use tokio::sync::broadcast::error::RecvError;
+use crate::domain::enums::DownloadStatus;
+use crate::peer::download_manager::DownloadEvent;
+use crate::values::DEFAULT_DOWNLOAD_PRIORITY;
 
 #[derive(Clone)]
 pub struct LocalFSInfo {}
 
+/// Options of a generated metafile, files with a swarm secret or allowed peers are private.
+#[derive(Default, Debug)]
+pub struct MetaFileOptions {
+    pub swarm_secret: Option<String>,
+    pub allowed_peers: Vec<String>,
+    /// Signs the metafile with the identity key of the peer.
+    pub sign: bool,
+}
+
 pub struct Client {
     pub address: String,
     pub state_container: SharableStateContainer,
@@ -20,9 +35,14 @@ impl Client {
         }
     }
 
-    pub async fn generate_meta_file(&self, path: &str) -> Result<(), String> {
-        let rfs_file = generate_meta_file(self.address.clone(), path)?;
-        rfs_file.save_to_project_dir().await?;
+    pub async fn generate_meta_file(&self, path: &str, options: MetaFileOptions) -> Result<(), String> {
+        let mut rfs_file = generate_meta_file(self.address.clone(), path, self.state_container.config.piece_size)?;
+        rfs_file.data.swarm_secret = options.swarm_secret;
+        rfs_file.data.allowed_peers = options.allowed_peers;
+        if options.sign {
+            sign_file(&mut rfs_file.data, &self.state_container.identity_key)?;
+        }
+        rfs_file.save_to_storage(&self.state_container.file_manager.fs_config().storage).await?;
         Ok(())
     }
 
@@ -32,32 +52,77 @@ pub async fn load_state(&mut self, own_address: String, fs_config: &FSConfig) ->
         Ok(())
     }
     
+    /// Loads the metafiles of the metafiles dir, the ones with a wrong signature, from an untrusted
+    /// publisher or with an invalid id are skipped. Files with unsafe names are renamed.
     pub async fn load_metafiles(&mut self, fs_config: &FSConfig) -> Result<(), String> {
-        let mut locked_state_container = self.state_container.lock().await;
-        let mut entries = fs::read_dir(fs_config.metafiles_dir.clone()).await.unwrap();
+        let trust_store = TrustStore::load(fs_config);
+        let own_key = self.state_container.identity_key.verifying_key();
+        let require_signed = self.state_container.config.require_signed_metafiles;
+        let mut entries = fs::read_dir(fs_config.storage.metafiles_dir.clone()).await.unwrap();
         while let Some(entry) = entries.next_entry().await.map_err(|_| "Failed to read entry")? {
             let path = entry.path();
             let path = path.to_str().unwrap();
             if path.split('.').last() == Some("rfs") {
-                let file = RFSFile::from_path(path).await;
-                locked_state_container.file_manager.add_file(file);
+                let mut file = RFSFile::from_path(path).await;
+                let checked = trust_store.check_file(&file.data, Some(&own_key), require_signed)
+                    .and_then(|_| check_file_names(&mut file.data))
+                    .and_then(|_| self.state_container.file_manager.add_file(file));
+                if let Err(err) = checked {
+                    println!("Skipping metafile {path}: {err}");
+                }
             }
         }
         Ok(())
     }
 
     pub async fn set_known_peers_from_files(&self, own_address: String) -> Result<(), String> {
-        let mut locked_state_container = self.state_container.lock().await;
         let mut peers: HashSet<String> = HashSet::new();
-        for file in locked_state_container.file_manager.get_files() {
+        for file in self.state_container.file_manager.get_files() {
             for peer in file.data.peers {
                 if !peer.eq(&own_address) {
                     peers.insert(peer);
                 }
             }
         }
-        locked_state_container.known_peers =
-            peers.into_iter().map(|address| KnownPeer { address, ping: None }).collect();
+        self.state_container.peers.set_known_peers(
+            peers.into_iter().map(KnownPeer::new).collect()
+        );
+        Ok(())
+    }
+
+    /// Maps the content ids of the downloaded files from the legacy metafiles.
+    pub async fn link_legacy_files(&self) -> Result<(), String> {
+        self.state_container.file_manager.link_legacy_files().await
+    }
+
+    /// Continues the downloads interrupted by a restart of the peer, the partially downloaded data
+    /// is verified first, so only the missing pieces are requested from the peers.
+    pub async fn resume_downloads(&self) -> Result<(), String> {
+        self.state_container.file_manager.scan_partial_files().await?;
+        let file_ids = self.state_container.file_manager.get_partial_file_ids();
+        for file_id in file_ids {
+            println!("Resuming download of the file {file_id}");
+            self.state_container.download_manager.enqueue(&self.state_container, file_id, DEFAULT_DOWNLOAD_PRIORITY)?;
+        }
         Ok(())
     }
+
+    /// Queues the download of the file and waits until it's finished.
+    pub async fn download_file(&self, file_id: String) -> Result<(), String> {
+        let manager = &self.state_container.download_manager;
+        let mut events = manager.subscribe();
+        manager.enqueue(&self.state_container, file_id.clone(), DEFAULT_DOWNLOAD_PRIORITY)?;
+        loop {
+            match events.recv().await {
+                Ok(DownloadEvent::Status { file_id: id, status }) if id == file_id => match status {
+                    DownloadStatus::Completed => return Ok(()),
+                    DownloadStatus::Failed(err) => return Err(err),
+                    DownloadStatus::Cancelled => return Err(format!("Download of the file {file_id} was cancelled")),
+                    _ => {}
+                },
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Err("Download manager stopped".to_string()),
+            }
+        }
+    }
 }
@@ -0,0 +1,197 @@
+use std::io;
+use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Size of the big-endian length prefix written before every frame.
+pub const FRAME_LENGTH_SIZE: usize = 8;
+
+#[derive(Debug)]
+pub enum CodecError {
+    WouldBlock,
+    Closed,
+    Generic(String),
+}
+
+impl From<CodecError> for String {
+    fn from(err: CodecError) -> Self {
+        match err {
+            CodecError::WouldBlock => "Operation would block".to_string(),
+            CodecError::Closed => "No bytes received from connection, closing".to_string(),
+            CodecError::Generic(err) => err,
+        }
+    }
+}
+
+/// Length-delimited frame codec. Bytes read from the stream are buffered until the whole frame
+/// is present, so frames may arrive in any number of reads and may be larger than a single read.
+pub struct FrameCodec {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+    // size of a single read from the stream
+    buffer_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: usize, buffer_size: usize) -> Self {
+        FrameCodec {
+            buffer: Vec::with_capacity(buffer_size),
+            max_frame_size,
+            buffer_size,
+        }
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, CodecError> {
+        if payload.len() > self.max_frame_size {
+            return Err(CodecError::Generic(format!(
+                "Frame of size {} exceeds the maximum frame size {}", payload.len(), self.max_frame_size,
+            )));
+        }
+        let mut data = Vec::with_capacity(FRAME_LENGTH_SIZE + payload.len());
+        data.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        data.extend_from_slice(payload);
+        Ok(data)
+    }
+
+    /// Returns the next frame if it is fully buffered, `None` if more bytes are needed.
+    pub fn decode(&mut self) -> Result<Option<Vec<u8>>, CodecError> {
+        if self.buffer.len() < FRAME_LENGTH_SIZE {
+            return Ok(None);
+        }
+        let size = u64::from_be_bytes(self.buffer[..FRAME_LENGTH_SIZE].try_into().unwrap());
+        if size > self.max_frame_size as u64 {
+            return Err(CodecError::Generic(format!(
+                "Frame of size {size} exceeds the maximum frame size {}", self.max_frame_size,
+            )));
+        }
+        let end = FRAME_LENGTH_SIZE + size as usize;
+        if self.buffer.len() < end {
+            self.buffer.reserve(end - self.buffer.len());
+            return Ok(None);
+        }
+        let frame = self.buffer[FRAME_LENGTH_SIZE..end].to_vec();
+        self.buffer.drain(..end);
+        Ok(Some(frame))
+    }
+
+    pub async fn read_frame<R: AsyncRead + Unpin>(&mut self, stream: &mut R) -> Result<Vec<u8>, CodecError> {
+        loop {
+            if let Some(frame) = self.decode()? {
+                return Ok(frame);
+            }
+            let n = stream.read_buf(&mut self.buffer).await
+                .map_err(|err| CodecError::Generic(format!("Failed to read from socket; err = {:?}", err)))?;
+            if n == 0 {
+                return Err(CodecError::Closed);
+            }
+        }
+    }
+
+    /// Reads from a blocking or non-blocking stream. For non-blocking streams the partially read
+    /// frame stays buffered and `WouldBlock` is returned until the rest of the frame arrives.
+    pub fn read_frame_sync<R: Read>(&mut self, stream: &mut R) -> Result<Vec<u8>, CodecError> {
+        let mut chunk = vec![0u8; self.buffer_size];
+        loop {
+            if let Some(frame) = self.decode()? {
+                return Ok(frame);
+            }
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(CodecError::Closed),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Err(CodecError::WouldBlock),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(CodecError::Generic(format!("Failed to read from socket; err = {:?}", err))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_FRAME_SIZE: usize = 1024;
+
+    /// Stream returning at most the given number of bytes per read, then `WouldBlock`.
+    struct ChunkedStream {
+        data: Vec<u8>,
+        chunk: usize,
+    }
+
+    impl Read for ChunkedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    fn codec() -> FrameCodec {
+        FrameCodec::new(MAX_FRAME_SIZE, 16)
+    }
+
+    #[test]
+    fn decodes_partial_frame_once_complete() {
+        let mut codec = codec();
+        let data = codec.encode(b"hello world").unwrap();
+        for (i, byte) in data.iter().enumerate() {
+            assert!(codec.decode().unwrap().is_none(), "frame decoded after {i} bytes");
+            codec.buffer.push(*byte);
+        }
+        assert_eq!(codec.decode().unwrap().unwrap(), b"hello world");
+        assert!(codec.buffer.is_empty());
+    }
+
+    #[test]
+    fn reads_frame_split_over_reads() {
+        let mut codec = codec();
+        let payload = vec![7u8; 100];
+        let mut stream = ChunkedStream { data: codec.encode(&payload).unwrap(), chunk: 3 };
+        assert_eq!(codec.read_frame_sync(&mut stream).unwrap(), payload);
+    }
+
+    #[test]
+    fn keeps_partial_frame_on_would_block() {
+        let mut codec = codec();
+        let data = codec.encode(b"partial").unwrap();
+        let mut stream = ChunkedStream { data: data[..10].to_vec(), chunk: 4 };
+        assert!(matches!(codec.read_frame_sync(&mut stream), Err(CodecError::WouldBlock)));
+        stream.data = data[10..].to_vec();
+        assert_eq!(codec.read_frame_sync(&mut stream).unwrap(), b"partial");
+    }
+
+    #[test]
+    fn decodes_several_frames_from_one_buffer() {
+        let mut codec = codec();
+        for payload in [&b"first"[..], b"", b"third"] {
+            let data = codec.encode(payload).unwrap();
+            codec.buffer.extend_from_slice(&data);
+        }
+        assert_eq!(codec.decode().unwrap().unwrap(), b"first");
+        assert_eq!(codec.decode().unwrap().unwrap(), b"");
+        assert_eq!(codec.decode().unwrap().unwrap(), b"third");
+        assert!(codec.decode().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_several_frames_from_one_read() {
+        let mut codec = codec();
+        let data = [codec.encode(b"one").unwrap(), codec.encode(b"two").unwrap()].concat();
+        let mut stream = tokio_test::io::Builder::new().read(&data).build();
+        assert_eq!(codec.read_frame(&mut stream).await.unwrap(), b"one");
+        assert_eq!(codec.read_frame(&mut stream).await.unwrap(), b"two");
+    }
+
+    #[test]
+    fn rejects_frames_over_max_size() {
+        let mut codec = codec();
+        assert!(matches!(codec.encode(&vec![0; MAX_FRAME_SIZE + 1]), Err(CodecError::Generic(_))));
+        assert!(codec.encode(&vec![0; MAX_FRAME_SIZE]).is_ok());
+        // the size is checked before the frame is buffered
+        codec.buffer.extend_from_slice(&(MAX_FRAME_SIZE as u64 + 1).to_be_bytes());
+        assert!(matches!(codec.decode(), Err(CodecError::Generic(_))));
+    }
+}
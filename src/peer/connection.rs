@@ -1,182 +1,706 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use futures::future::join_all;
 use serde::{Deserialize, Serialize};
-// todo: cbor serialization still produces 31Kb size for the frame with 16Kb of contents. 
-// Maybe check some other available formats, or write own binary protocol?
-use serde_cbor::{from_slice, to_vec};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
 use tokio::time::{Instant};
-use crate::domain::enums::PieceDownloadStatus;
+use crate::domain::bitfield::Bitfield;
+use crate::peer::codec::FrameCodec;
+use crate::domain::models::File;
+use crate::peer::access::{generate_nonce, is_allowed, prove_access, NONCE_SIZE};
+use crate::peer::dht::{Contact, NodeId};
+use crate::peer::protocol::{decode_frame, encode_frame, PROTOCOL_VERSION, SUPPORTED_FEATURES};
 use crate::peer::enums::ConnectionState;
+use crate::peer::noise::{sealed_size, NoiseKey, Opener, Sealer, NOISE_FEATURE};
 use crate::peer::state::KnownPeer;
-use crate::values::DEFAULT_BUFFER_SIZE;
+use crate::values::{DEFAULT_BUFFER_SIZE, DEFAULT_MAX_FRAME_SIZE};
+
+/// First frame sent by both sides of a connection. Its encoding is the same in all protocol versions,
+/// so peers running different builds can tell each other about the version mismatch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloFrame {
+    pub protocol_version: u8,
+    pub features: Vec<String>,
+    pub listen_address: String,
+    pub peer_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloRejectFrame {
+    pub reason: String,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct GetInfoFrame {}
+pub struct GetInfoFrame {
+    pub request_id: u64,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct InfoResponseFrame {
+    pub request_id: u64,
+    /// Advertised address of the responding peer.
+    #[serde(default)]
+    pub address: String,
     pub file_ids: Vec<String>,
     pub known_peers: Vec<KnownPeer>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct GetPingFrame {}
+pub struct GetPingFrame {
+    pub request_id: u64,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct PingResponseFrame {}
+pub struct PingResponseFrame {
+    pub request_id: u64,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetFilePieceFrame {
+    pub request_id: u64,
     pub file_id: String,
     pub piece: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct GetFileFrame {
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FilePieceResponseFrame {
+    pub request_id: u64,
     pub file_id: String,
+    pub piece: u64,
+    pub content: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct FilePieceResponseFrame {
+pub struct GetBitfieldFrame {
+    pub request_id: u64,
+    pub file_id: String,
+}
+
+/// Pieces of the file the peer can serve. After sending it, the peer notifies about every new
+/// piece it receives with a Have frame.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BitfieldFrame {
+    pub request_id: u64,
+    pub file_id: String,
+    pub bitfield: Bitfield,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HaveFrame {
     pub file_id: String,
     pub piece: u64,
-    pub content: Vec<u8>,
 }
 
+/// Cancels the piece request with the given id, the response is dropped if it's not sent yet.
 #[derive(Serialize, Deserialize, Debug)]
-pub struct FilePieceDownloadStatusResponseFrame {
+pub struct CancelFrame {
+    pub request_id: u64,
     pub file_id: String,
     pub piece: u64,
-    pub status: PieceDownloadStatus,
 }
 
-impl FilePieceResponseFrame {
-    pub fn get_piece_id(&self) -> String {
-        self.file_id.clone() + ":" + &self.piece.to_string()
-    }
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FindNodeFrame {
+    pub request_id: u64,
+    pub target: NodeId,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "kind")]
-pub enum ConnectionFrame {
-    #[serde(rename = "GetInfo")]
-    GetInfo(GetInfoFrame),
+pub struct NodesFrame {
+    pub request_id: u64,
+    pub nodes: Vec<Contact>,
+}
 
-    #[serde(rename = "InfoResponse")]
-    InfoResponse(InfoResponseFrame),
+/// Asks for the sources of the key, the file id or the content hash.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FindValueFrame {
+    pub request_id: u64,
+    pub key: String,
+}
 
-    #[serde(rename = "GetPing")]
-    GetPing(GetPingFrame),
+/// Sources of the key known to the peer and the contacts closest to the key.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ValueFrame {
+    pub request_id: u64,
+    pub providers: Vec<String>,
+    pub nodes: Vec<Contact>,
+}
 
-    #[serde(rename = "PingResponse")]
-    PingResponse(PingResponseFrame),
+/// Announces the sender as a source of the key, the listen address from the handshake is stored.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnnounceFrame {
+    pub request_id: u64,
+    pub key: String,
+}
 
-    #[serde(rename = "GetFilePiece")]
-    GetFilePiece(GetFilePieceFrame),
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnnounceResponseFrame {
+    pub request_id: u64,
+}
 
-    #[serde(rename = "GetFile")]
-    GetFile(GetFileFrame),
+/// Peer exchange, the sender shares a sample of its accessible peers and receives a sample of the
+/// receiver's ones.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PexFrame {
+    pub request_id: u64,
+    pub peers: Vec<String>,
+}
 
-    #[serde(rename = "FilePieceResponse")]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PexResponseFrame {
+    pub request_id: u64,
+    pub peers: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetChallengeFrame {
+    pub request_id: u64,
+}
+
+/// Nonce of the connection the proofs of the swarm secrets are made for.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChallengeFrame {
+    pub request_id: u64,
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+}
+
+/// Proves the knowledge of the swarm secret of the file, the file is served on the connection after.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProveAccessFrame {
+    pub request_id: u64,
+    pub file_id: String,
+    #[serde(with = "serde_bytes")]
+    pub proof: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccessGrantedFrame {
+    pub request_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResponseFrame {
+    pub request_id: u64,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub enum ConnectionFrame {
+    Hello(HelloFrame),
+    HelloReject(HelloRejectFrame),
+    GetInfo(GetInfoFrame),
+    InfoResponse(InfoResponseFrame),
+    GetPing(GetPingFrame),
+    PingResponse(PingResponseFrame),
+    GetFilePiece(GetFilePieceFrame),
     FilePieceResponse(FilePieceResponseFrame),
+    ErrorResponse(ErrorResponseFrame),
+    GetBitfield(GetBitfieldFrame),
+    Bitfield(BitfieldFrame),
+    Have(HaveFrame),
+    Cancel(CancelFrame),
+    FindNode(FindNodeFrame),
+    Nodes(NodesFrame),
+    FindValue(FindValueFrame),
+    Value(ValueFrame),
+    Announce(AnnounceFrame),
+    AnnounceResponse(AnnounceResponseFrame),
+    Pex(PexFrame),
+    PexResponse(PexResponseFrame),
+    GetChallenge(GetChallengeFrame),
+    Challenge(ChallengeFrame),
+    ProveAccess(ProveAccessFrame),
+    AccessGranted(AccessGrantedFrame),
+}
 
-    #[serde(rename = "FilePieceDownloadStatusResponse")]
-    FilePieceDownloadStatusResponse(FilePieceDownloadStatusResponseFrame),
+impl ConnectionFrame {
+    /// Id of the request this frame belongs to. Responses carry the id of the request they answer.
+    pub fn request_id(&self) -> u64 {
+        match self {
+            ConnectionFrame::Hello(_) | ConnectionFrame::HelloReject(_) | ConnectionFrame::Have(_) => 0,
+            ConnectionFrame::GetInfo(f) => f.request_id,
+            ConnectionFrame::InfoResponse(f) => f.request_id,
+            ConnectionFrame::GetPing(f) => f.request_id,
+            ConnectionFrame::PingResponse(f) => f.request_id,
+            ConnectionFrame::GetFilePiece(f) => f.request_id,
+            ConnectionFrame::FilePieceResponse(f) => f.request_id,
+            ConnectionFrame::ErrorResponse(f) => f.request_id,
+            ConnectionFrame::GetBitfield(f) => f.request_id,
+            ConnectionFrame::Bitfield(f) => f.request_id,
+            ConnectionFrame::Cancel(f) => f.request_id,
+            ConnectionFrame::FindNode(f) => f.request_id,
+            ConnectionFrame::Nodes(f) => f.request_id,
+            ConnectionFrame::FindValue(f) => f.request_id,
+            ConnectionFrame::Value(f) => f.request_id,
+            ConnectionFrame::Announce(f) => f.request_id,
+            ConnectionFrame::AnnounceResponse(f) => f.request_id,
+            ConnectionFrame::Pex(f) => f.request_id,
+            ConnectionFrame::PexResponse(f) => f.request_id,
+            ConnectionFrame::GetChallenge(f) => f.request_id,
+            ConnectionFrame::Challenge(f) => f.request_id,
+            ConnectionFrame::ProveAccess(f) => f.request_id,
+            ConnectionFrame::AccessGranted(f) => f.request_id,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ConnectionInfo {
     pub ping: i64,
+    pub address: String,
     pub file_ids: Vec<String>,
     pub known_peers: Vec<KnownPeer>,
 }
 
 
+/// Settings shared by all connections of the peer.
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    pub max_frame_size: usize,
+    pub buffer_size: usize,
+    pub peer_id: String,
+    pub listen_address: String,
+    /// Address the other peers connect to, the listen address if it's not set.
+    pub advertised_address: Option<String>,
+    /// Static key of the encrypted connections, the encryption is not offered without it.
+    pub noise_key: Option<NoiseKey>,
+    /// Refuses the connections with the peers that don't support the encryption.
+    pub require_encryption: bool,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        ConnectionOptions {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            peer_id: Default::default(),
+            listen_address: Default::default(),
+            advertised_address: None,
+            noise_key: None,
+            require_encryption: false,
+        }
+    }
+}
+
+impl ConnectionOptions {
+    pub fn advertised_address(&self) -> &str {
+        self.advertised_address.as_deref().unwrap_or(&self.listen_address)
+    }
+
+    pub fn hello(&self) -> HelloFrame {
+        HelloFrame {
+            protocol_version: PROTOCOL_VERSION,
+            features: SUPPORTED_FEATURES.iter()
+                .filter(|f| **f != NOISE_FEATURE || self.noise_key.is_some())
+                .map(|f| f.to_string())
+                .collect(),
+            listen_address: self.advertised_address().to_string(),
+            peer_id: self.peer_id.clone(),
+        }
+    }
+}
+
+pub fn check_hello(hello: &HelloFrame) -> Result<(), String> {
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err(format!(
+            "Protocol version mismatch: local version is {PROTOCOL_VERSION}, peer {} uses version {}",
+            hello.peer_id, hello.protocol_version,
+        ));
+    }
+    Ok(())
+}
+
+/// Features offered by both sides of the connection.
+fn negotiate_features(local: &HelloFrame, remote: &HelloFrame) -> Vec<String> {
+    remote.features.iter().filter(|f| local.features.contains(f)).cloned().collect()
+}
+
+/// Checks that the connection is encrypted if the encryption is required.
+fn check_encryption(options: &ConnectionOptions, features: &[String]) -> Result<(), String> {
+    if options.require_encryption && !features.iter().any(|f| f == NOISE_FEATURE) {
+        return Err("Encryption is required, but it's not supported by both peers".to_string());
+    }
+    Ok(())
+}
+
+type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+type WriteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Splits the stream of the connection, so the frames are read and written independently.
+fn split_stream<S: AsyncRead + AsyncWrite + Send + 'static>(stream: S) -> (ReadStream, WriteStream) {
+    let (reader, writer) = tokio::io::split(stream);
+    (Box::new(reader), Box::new(writer))
+}
+
+pub struct FrameReader {
+    stream: ReadStream,
+    codec: FrameCodec,
+    opener: Option<Opener>,
+}
+
+impl FrameReader {
+    fn new(stream: ReadStream, options: &ConnectionOptions) -> Self {
+        FrameReader {
+            stream,
+            codec: FrameCodec::new(sealed_size(options.max_frame_size), options.buffer_size),
+            opener: None,
+        }
+    }
+
+    /// Reads the next frame, decrypting it if the connection is encrypted.
+    async fn read_payload(&mut self) -> Result<Vec<u8>, String> {
+        let data = self.codec.read_frame(&mut self.stream).await?;
+        match self.opener.as_mut() {
+            Some(opener) => opener.open(&data),
+            None => Ok(data),
+        }
+    }
+
+    pub async fn read_frame(&mut self) -> Result<ConnectionFrame, String> {
+        decode_frame(&self.read_payload().await?)
+    }
+}
+
+struct WriteHalf {
+    stream: WriteStream,
+    sealer: Option<Sealer>,
+}
+
+/// Write half of the connection, can be cloned to write frames from several tasks.
+#[derive(Clone)]
+pub struct FrameWriter {
+    // frames are encrypted under the lock, so they are sent in the order of their nonces
+    stream: Arc<Mutex<WriteHalf>>,
+    codec: Arc<FrameCodec>,
+}
+
+impl FrameWriter {
+    fn new(stream: WriteStream, options: &ConnectionOptions) -> Self {
+        FrameWriter {
+            stream: Arc::new(Mutex::new(WriteHalf { stream, sealer: None })),
+            codec: Arc::new(FrameCodec::new(sealed_size(options.max_frame_size), options.buffer_size)),
+        }
+    }
+
+    async fn write_payload(&self, payload: Vec<u8>) -> Result<(), String> {
+        let mut half = self.stream.lock().await;
+        let payload = match half.sealer.as_mut() {
+            Some(sealer) => sealer.seal(&payload)?,
+            None => payload,
+        };
+        let data = self.codec.encode(&payload)?;
+        half.stream.write_all(data.as_ref()).await
+            .map_err(|err| format!("Failed to send frame to the peer {err}"))
+    }
+
+    pub async fn write_frame(&self, frame: ConnectionFrame) -> Result<(), String> {
+        let frame_data = encode_frame(&frame)?;
+        println!("Writing frame with size {}", frame_data.len());
+        self.write_payload(frame_data).await
+    }
+}
+
+/// Runs the noise handshake over the connection and encrypts all the following frames, returns
+/// the static key of the remote peer. The prologue is the Hello frames as they were sent.
+async fn secure(
+    initiator: bool,
+    key: &NoiseKey,
+    prologue: &[u8],
+    reader: &mut FrameReader,
+    writer: &FrameWriter,
+) -> Result<Vec<u8>, String> {
+    let mut handshake = key.handshake(initiator, prologue)?;
+    while !handshake.is_finished() {
+        if handshake.is_my_turn() {
+            writer.write_payload(handshake.write_message()?).await?;
+        } else {
+            handshake.read_message(&reader.read_payload().await?)?;
+        }
+    }
+    let (sealer, opener, remote_key) = handshake.into_transport()?;
+    writer.stream.lock().await.sealer = Some(sealer);
+    reader.opener = Some(opener);
+    Ok(remote_key)
+}
+
+/// Encrypts the connection if both peers offered the encryption.
+async fn secure_if_negotiated(
+    initiator: bool,
+    options: &ConnectionOptions,
+    features: &[String],
+    prologue: &[u8],
+    reader: &mut FrameReader,
+    writer: &FrameWriter,
+) -> Result<Option<Vec<u8>>, String> {
+    match options.noise_key.as_ref() {
+        Some(key) if features.iter().any(|f| f == NOISE_FEATURE) => {
+            Ok(Some(secure(initiator, key, prologue, reader, writer).await?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Connection accepted by the listener. Frames are read by the listener loop, responses are
+/// written through the cloneable handle, so requests can be processed concurrently.
+#[derive(Clone)]
+pub struct InboundConnection {
+    pub address: String,
+    pub remote: HelloFrame,
+    pub features: Vec<String>,
+    /// Static key of the remote peer if the connection is encrypted.
+    pub remote_key: Option<Vec<u8>>,
+    /// Nonce the remote peer proves the knowledge of the swarm secrets with.
+    pub nonce: [u8; NONCE_SIZE],
+    // files the remote peer proved the swarm secret of
+    unlocked_files: Arc<std::sync::Mutex<HashSet<String>>>,
+    writer: FrameWriter,
+    // piece requests that are not answered yet, the flag is set when the request is cancelled
+    queued_pieces: Arc<std::sync::Mutex<HashMap<u64, bool>>>,
+}
+
+impl InboundConnection {
+    /// Waits for the Hello frame of the connecting peer and answers with its own one, peers with an
+    /// incompatible protocol version, or without the encryption when it's required, are refused
+    /// with a HelloReject frame.
+    pub async fn accept<S: AsyncRead + AsyncWrite + Send + 'static>(
+        stream: S,
+        address: String,
+        options: &ConnectionOptions,
+    ) -> Result<(Self, FrameReader), String> {
+        let (reader, writer) = split_stream(stream);
+        let (mut reader, writer) = (FrameReader::new(reader, options), FrameWriter::new(writer, options));
+
+        let remote_hello = reader.read_payload().await?;
+        let remote = match decode_frame(&remote_hello)? {
+            ConnectionFrame::Hello(hello) => hello,
+            frame => return Err(format!("Expected Hello frame from {address}, received {:?}", frame)),
+        };
+        let local = options.hello();
+        let features = negotiate_features(&local, &remote);
+        if let Err(reason) = check_hello(&remote).and_then(|_| check_encryption(options, &features)) {
+            writer.write_frame(ConnectionFrame::HelloReject(HelloRejectFrame { reason: reason.clone() })).await?;
+            return Err(reason);
+        }
+        let local_hello = encode_frame(&ConnectionFrame::Hello(local))?;
+        writer.write_payload(local_hello.clone()).await?;
+        let prologue = [remote_hello, local_hello].concat();
+        let remote_key = secure_if_negotiated(false, options, &features, &prologue, &mut reader, &writer).await?;
+
+        Ok((
+            InboundConnection {
+                address,
+                features,
+                remote_key,
+                nonce: generate_nonce(),
+                unlocked_files: Default::default(),
+                remote,
+                writer,
+                queued_pieces: Default::default(),
+            },
+            reader,
+        ))
+    }
+
+    pub async fn write_frame(&self, frame: ConnectionFrame) -> Result<(), String> {
+        self.writer.write_frame(frame).await
+    }
+
+    pub fn unlock_file(&self, file_id: String) {
+        self.unlocked_files.lock().unwrap().insert(file_id);
+    }
+
+    /// Whether the remote peer may get the file on this connection.
+    pub fn is_allowed(&self, file: &File) -> bool {
+        is_allowed(file, self.remote_key.as_deref(), self.unlocked_files.lock().unwrap().contains(&file.id))
+    }
+
+    pub fn queue_piece_request(&self, request_id: u64) {
+        self.queued_pieces.lock().unwrap().insert(request_id, false);
+    }
+
+    pub fn cancel_piece_request(&self, request_id: u64) {
+        if let Some(cancelled) = self.queued_pieces.lock().unwrap().get_mut(&request_id) {
+            *cancelled = true;
+        }
+    }
+
+    /// Removes the request from the queue, returns false if the request was cancelled and the
+    /// response should be dropped.
+    pub fn dequeue_piece_request(&self, request_id: u64) -> bool {
+        !self.queued_pieces.lock().unwrap().remove(&request_id).unwrap_or(false)
+    }
+
+}
+
+type PendingRequests = Arc<std::sync::Mutex<HashMap<u64, oneshot::Sender<ConnectionFrame>>>>;
+
+/// Removes the pending request when the awaiting caller goes away before the response arrives.
+struct PendingRequestGuard {
+    pending: PendingRequests,
+    request_id: u64,
+}
+
+impl Drop for PendingRequestGuard {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+/// Reads frames from the connection and routes each response to the caller awaiting its request id.
+/// Frames that don't belong to any request, like Have notifications, are sent to the events channel.
+async fn dispatch_frames(
+    address: String,
+    mut reader: FrameReader,
+    pending: PendingRequests,
+    events: mpsc::UnboundedSender<ConnectionFrame>,
+) {
+    loop {
+        let frame = match reader.read_frame().await {
+            Ok(frame) => frame,
+            Err(err) => {
+                println!("Stopping dispatcher for connection {address}: {err}");
+                break;
+            }
+        };
+        let sender = pending.lock().unwrap().remove(&frame.request_id());
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(frame);
+            }
+            None => {
+                if let Err(err) = events.send(frame) {
+                    println!("Received frame for unknown request from {address}: {:?}", err.0);
+                }
+            }
+        }
+    }
+    // dropping the senders wakes up all callers that still wait for the responses
+    pending.lock().unwrap().clear();
+}
+
 // todo: refactor with state pattern https://www.youtube.com/watch?v=_ccDqRTx-JU&t=10s
-// define different methods for outbound peer connections and inbound connections
+/// Outbound connection to a peer. Requests are multiplexed, so many of them can be in flight
+/// on the same connection at once.
 pub struct Connection {
-    stream: TcpStream,
+    pub address: String,
+    pub remote: HelloFrame,
+    pub features: Vec<String>,
+    /// Static key of the remote peer if the connection is encrypted.
+    pub remote_key: Option<Vec<u8>>,
+    writer: FrameWriter,
+    pending: PendingRequests,
+    next_request_id: AtomicU64,
+    dispatcher: JoinHandle<()>,
+    events: std::sync::Mutex<Option<mpsc::UnboundedReceiver<ConnectionFrame>>>,
     state: ConnectionState,
     pub info: Option<ConnectionInfo>,
-    buffer: [u8; DEFAULT_BUFFER_SIZE],
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
 }
 
 impl Connection {
-    pub async fn from_address(address: &String) -> Option<Self> {
-        match TcpStream::connect(address).await {
-            Ok(stream) => Some(
-                Connection {
-                    stream,
-                    state: ConnectionState::Connected,
-                    buffer: [0; DEFAULT_BUFFER_SIZE],
-                    info: None,
-                }
-            ),
+    pub async fn from_address(address: &String, options: &ConnectionOptions) -> Option<Self> {
+        let stream = match TcpStream::connect(address).await {
+            Ok(stream) => stream,
             Err(err) => { 
                 println!("Exception when connecting to the address {}: {}", address, err);
-                None
+                return None
             },
+        };
+        match Connection::from_stream(address.clone(), stream, options).await {
+            Ok(connection) => Some(connection),
+            Err(err) => {
+                println!("Handshake with the address {} failed: {}", address, err);
+                None
+            }
         }
     }
 
-    pub async fn from_addresses(addresses: Vec<String>) -> Vec<Option<Connection>> {
+    pub async fn from_addresses(addresses: Vec<String>, options: &ConnectionOptions) -> Vec<Option<Connection>> {
         join_all(addresses.iter().map(|addr| async move {
-            Connection::from_address(&addr.clone()).await
+            Connection::from_address(&addr.clone(), options).await
         })).await
     }
 
-    pub async fn from_stream(stream: TcpStream) -> Self {
-        Connection {
-            stream,
+    pub(crate) async fn from_stream<S: AsyncRead + AsyncWrite + Send + 'static>(
+        address: String,
+        stream: S,
+        options: &ConnectionOptions,
+    ) -> Result<Self, String> {
+        let (reader, writer) = split_stream(stream);
+        let (mut reader, writer) = (FrameReader::new(reader, options), FrameWriter::new(writer, options));
+
+        let local = options.hello();
+        let local_hello = encode_frame(&ConnectionFrame::Hello(local.clone()))?;
+        writer.write_payload(local_hello.clone()).await?;
+        let remote_hello = reader.read_payload().await?;
+        let remote = match decode_frame(&remote_hello)? {
+            ConnectionFrame::Hello(hello) => hello,
+            ConnectionFrame::HelloReject(frame) => return Err(format!("Peer refused the connection: {}", frame.reason)),
+            frame => return Err(format!("Expected Hello frame, received {:?}", frame)),
+        };
+        check_hello(&remote)?;
+        let features = negotiate_features(&local, &remote);
+        check_encryption(options, &features)?;
+        let prologue = [local_hello, remote_hello].concat();
+        let remote_key = secure_if_negotiated(true, options, &features, &prologue, &mut reader, &writer).await?;
+
+        let pending: PendingRequests = Default::default();
+        let (events_sender, events) = mpsc::unbounded_channel();
+        let dispatcher = tokio::spawn(dispatch_frames(address.clone(), reader, pending.clone(), events_sender));
+        Ok(Connection {
+            address,
+            features,
+            remote_key,
+            remote,
+            writer,
+            pending,
+            next_request_id: AtomicU64::new(1),
+            dispatcher,
+            events: std::sync::Mutex::new(Some(events)),
             state: ConnectionState::Connected,
-            buffer: [0; DEFAULT_BUFFER_SIZE],
             info: None,
-        }
+        })
     }
 
-    // todo: connection may return more bytes than buffer can load. To rewrite it with an ability
-    // for the buffer to store loaded bytes and read from stream again.
-    pub async fn read_frame(&mut self) -> Result<ConnectionFrame, String> {
-        let size = match self.stream.read_u64().await {
-            Ok(v) => Ok(v),
-            Err(_) => Err("No bytes received from connection, closing".to_string())
-        }?;
-        
-        if size >= DEFAULT_BUFFER_SIZE as u64 {
-            return Err("Frame doesn't fit into the buffer".to_string())
-        };
+    /// Channel of the frames sent by the peer without a request, can be taken only once.
+    pub fn take_events(&self) -> Option<mpsc::UnboundedReceiver<ConnectionFrame>> {
+        self.events.lock().unwrap().take()
+    }
 
-        let frame_buffer = &mut self.buffer[..size as usize];
+    pub fn next_request_id(&self) -> u64 {
+        self.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
 
-        let _ = match self.stream.read(frame_buffer).await {
-            Ok(0) => {
-                Err("No bytes received from connection, closing".to_string())
-            }
-            Ok(n) => Ok(n),
-            Err(e) => {
-                Err(format!("Failed to read from socket; err = {:?}", e).to_string())
-            }
-        }?;
+    /// Sends the request frame and waits for the response with the same request id.
+    pub async fn send_request(&self, frame: ConnectionFrame) -> Result<ConnectionFrame, String> {
+        let request_id = frame.request_id();
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request_id, sender);
+        let _guard = PendingRequestGuard { pending: self.pending.clone(), request_id };
 
-        from_slice(&frame_buffer).map_err(|err| format!("Error when parsing frame {err}"))
-    }
+        self.writer.write_frame(frame).await?;
 
-    pub async fn write_frame(&mut self, frame: ConnectionFrame) {
-        let frame_data = to_vec(&frame).expect("Failed to serialize GetInfo frame!");
-        let frame_size: [u8; 8] = (frame_data.len() as u64).to_be_bytes();
-        let mut data = Vec::with_capacity(4 + frame_data.len());
-        data.extend_from_slice(frame_size.as_ref());
-        data.extend_from_slice(frame_data.as_ref());
-        println!("Writing frame with size {}", frame_data.len());
-        self.stream.write_all(data.as_ref()).await.expect("Failed to send GetInfo frame to the peer");
+        match receiver.await {
+            Ok(ConnectionFrame::ErrorResponse(frame)) => Err(frame.message),
+            Ok(frame) => Ok(frame),
+            Err(_) => Err(format!("Connection to {} closed before the response was received", self.address)),
+        }
     }
 
-    pub async fn get_ping(&mut self) -> Result<u128, String> {
-        self.write_frame(ConnectionFrame::GetPing(GetPingFrame {})).await;
-
+    pub async fn get_ping(&self) -> Result<u128, String> {
+        let request_id = self.next_request_id();
         let start = Instant::now();
-        match self.read_frame().await? {
+        match self.send_request(ConnectionFrame::GetPing(GetPingFrame { request_id })).await? {
             ConnectionFrame::PingResponse(_) => {},
             _ => {
                 return Err("Wrong frame received!".to_string());
@@ -190,9 +714,8 @@ impl Connection {
             return Err("Failed to retrieve info, connection is not in connected state!".to_string());
         }
 
-        self.write_frame(ConnectionFrame::GetInfo(GetInfoFrame {})).await;
-
-        let info_response = match self.read_frame().await? {
+        let request_id = self.next_request_id();
+        let info_response = match self.send_request(ConnectionFrame::GetInfo(GetInfoFrame { request_id })).await? {
             ConnectionFrame::InfoResponse(frame) => frame,
             _ => {
                 return Err("Wrong frame received!".to_string());
@@ -204,37 +727,185 @@ impl Connection {
         self.state = ConnectionState::InfoRetrieved;
         self.info = Some(ConnectionInfo {
             ping: ping as i64,
+            address: info_response.address,
             file_ids: info_response.file_ids,
             known_peers: info_response.known_peers,
         });
         Ok(())
     }
-    
-    // todo: currently there is a problem, that when request frame is sent through the open connection
-    // the response frame from other request may appear in between. The solution is to create a task with 
-    // channel to return the result to
-    pub async fn get_file_piece(&mut self, file_id: String, piece: u64) -> Result<FilePieceResponseFrame, String> {
-        self.write_frame(ConnectionFrame::GetFilePiece(GetFilePieceFrame { file_id, piece })).await;
-
-        loop {
-            let res: Option<FilePieceResponseFrame> = match self.read_frame().await? {
-                ConnectionFrame::FilePieceResponse(r) => Some(r),
-                f => {
-                    println!("Wrong frame received: {:?}", f);
-                    None
-                },
-            };
-            if let Some(r) = res {
-                return Ok(r)
-            }
+
+    pub async fn get_file_piece(&self, file_id: String, piece: u64) -> Result<FilePieceResponseFrame, String> {
+        self.request_file_piece(self.next_request_id(), file_id, piece).await
+    }
+
+    /// Requests the piece with the given request id, so the request can be cancelled later.
+    pub async fn request_file_piece(&self, request_id: u64, file_id: String, piece: u64) -> Result<FilePieceResponseFrame, String> {
+        match self.send_request(ConnectionFrame::GetFilePiece(GetFilePieceFrame { request_id, file_id, piece })).await? {
+            ConnectionFrame::FilePieceResponse(frame) => Ok(frame),
+            f => Err(format!("Wrong frame received: {:?}", f)),
+        }
+    }
+
+    pub async fn get_bitfield(&self, file_id: String) -> Result<Bitfield, String> {
+        let request_id = self.next_request_id();
+        match self.send_request(ConnectionFrame::GetBitfield(GetBitfieldFrame { request_id, file_id })).await? {
+            ConnectionFrame::Bitfield(frame) => Ok(frame.bitfield),
+            f => Err(format!("Wrong frame received: {:?}", f)),
         }
     }
-    
-    pub async fn send_file_piece_download_status(&mut self, file_id: String, piece: u64, status: PieceDownloadStatus) {
-        self.write_frame(ConnectionFrame::FilePieceDownloadStatusResponse(FilePieceDownloadStatusResponseFrame {
-            file_id,
-            piece,
-            status,
-        })).await;
+
+    /// Sends the sample of the local peers, returns the sample of the remote peers.
+    pub async fn exchange_peers(&self, peers: Vec<String>) -> Result<Vec<String>, String> {
+        let request_id = self.next_request_id();
+        match self.send_request(ConnectionFrame::Pex(PexFrame { request_id, peers })).await? {
+            ConnectionFrame::PexResponse(frame) => Ok(frame.peers),
+            f => Err(format!("Wrong frame received: {:?}", f)),
+        }
+    }
+
+    /// Proves the knowledge of the swarm secret of the file, so the peer serves it on this connection.
+    pub async fn prove_access(&self, file_id: String, secret: &str) -> Result<(), String> {
+        let request_id = self.next_request_id();
+        let nonce = match self.send_request(ConnectionFrame::GetChallenge(GetChallengeFrame { request_id })).await? {
+            ConnectionFrame::Challenge(frame) => frame.nonce,
+            f => return Err(format!("Wrong frame received: {:?}", f)),
+        };
+        let request_id = self.next_request_id();
+        let proof = prove_access(secret, &nonce, &file_id);
+        match self.send_request(ConnectionFrame::ProveAccess(ProveAccessFrame { request_id, file_id, proof })).await? {
+            ConnectionFrame::AccessGranted(_) => Ok(()),
+            f => Err(format!("Wrong frame received: {:?}", f)),
+        }
+    }
+
+    pub async fn cancel_file_piece(&self, request_id: u64, file_id: String, piece: u64) -> Result<(), String> {
+        self.writer.write_frame(ConnectionFrame::Cancel(CancelFrame { request_id, file_id, piece })).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+    use super::*;
+
+    fn options(peer_id: &str, encryption: bool, require_encryption: bool) -> ConnectionOptions {
+        ConnectionOptions {
+            peer_id: peer_id.to_string(),
+            listen_address: "127.0.0.1:8000".to_string(),
+            noise_key: encryption.then(|| NoiseKey::generate().unwrap()),
+            require_encryption,
+            ..Default::default()
+        }
+    }
+
+    /// Connects the peers over an in-memory stream, returns the results of both sides.
+    async fn connect(client: &ConnectionOptions, server: &ConnectionOptions) -> (Result<Connection, String>, Result<InboundConnection, String>) {
+        let (client_stream, server_stream) = duplex(64 * 1024);
+        let (connection, inbound) = tokio::join!(
+            Connection::from_stream("server".to_string(), client_stream, client),
+            InboundConnection::accept(server_stream, "client".to_string(), server),
+        );
+        (connection, inbound.map(|(inbound, _)| inbound))
+    }
+
+    #[tokio::test]
+    async fn peers_with_keys_encrypt_connection() {
+        let (client, server) = (options("client", true, true), options("server", true, true));
+        let (connection, inbound) = connect(&client, &server).await;
+        let (connection, inbound) = (connection.unwrap(), inbound.unwrap());
+        assert_eq!(connection.remote_key, server.noise_key.map(|key| key.public));
+        assert_eq!(inbound.remote_key, client.noise_key.map(|key| key.public));
+    }
+
+    #[tokio::test]
+    async fn plaintext_peer_is_refused_when_encryption_is_required() {
+        let (connection, inbound) = connect(&options("client", false, false), &options("server", true, true)).await;
+        assert!(connection.err().unwrap().contains("Encryption is required"));
+        assert!(inbound.is_err());
+
+        // the client that requires the encryption leaves before sending any request
+        let (connection, _) = connect(&options("client", true, true), &options("server", false, false)).await;
+        assert!(connection.err().unwrap().contains("Encryption is required"));
+    }
+
+    #[tokio::test]
+    async fn plaintext_is_used_when_encryption_is_not_required() {
+        let (connection, inbound) = connect(&options("client", false, false), &options("server", true, false)).await;
+        assert_eq!(connection.unwrap().remote_key, None);
+        assert_eq!(inbound.unwrap().remote_key, None);
+    }
+
+    /// Frame reader and writer of the side of the stream that doesn't run the handshake.
+    fn raw_side(stream: tokio::io::DuplexStream, options: &ConnectionOptions) -> (FrameReader, FrameWriter) {
+        let (reader, writer) = split_stream(stream);
+        (FrameReader::new(reader, options), FrameWriter::new(writer, options))
+    }
+
+    #[tokio::test]
+    async fn responses_are_routed_by_request_id() {
+        let (client_stream, server_stream) = duplex(64 * 1024);
+        let (client, server) = (options("client", false, false), options("server", false, false));
+        let (connection, inbound) = tokio::join!(
+            Connection::from_stream("server".to_string(), client_stream, &client),
+            InboundConnection::accept(server_stream, "client".to_string(), &server),
+        );
+        let (connection, (inbound, mut reader)) = (connection.unwrap(), inbound.unwrap());
+        let mut events = connection.take_events().unwrap();
+
+        // the server answers the requests in the reverse order
+        let server = async move {
+            let mut requests = vec![];
+            for _ in 0..3 {
+                match reader.read_frame().await.unwrap() {
+                    ConnectionFrame::Pex(frame) => requests.push(frame),
+                    frame => panic!("Unexpected frame {frame:?}"),
+                }
+            }
+            inbound.write_frame(ConnectionFrame::Have(HaveFrame { file_id: "file".to_string(), piece: 1 })).await.unwrap();
+            for frame in requests.into_iter().rev() {
+                inbound.write_frame(ConnectionFrame::PexResponse(PexResponseFrame { request_id: frame.request_id, peers: frame.peers })).await.unwrap();
+            }
+        };
+        let (a, b, c, _) = tokio::join!(
+            connection.exchange_peers(vec!["a".to_string()]),
+            connection.exchange_peers(vec!["b".to_string()]),
+            connection.exchange_peers(vec!["c".to_string()]),
+            server,
+        );
+        assert_eq!((a.unwrap(), b.unwrap(), c.unwrap()), (vec!["a".to_string()], vec!["b".to_string()], vec!["c".to_string()]));
+        // frames that don't answer a request are events
+        assert!(matches!(events.recv().await, Some(ConnectionFrame::Have(frame)) if frame.piece == 1));
+        assert!(connection.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn protocol_version_mismatch_is_rejected() {
+        let options = options("peer", false, false);
+        let old_hello = HelloFrame { protocol_version: PROTOCOL_VERSION + 1, ..options.hello() };
+
+        // the accepting peer answers the hello of another version with a HelloReject
+        let (client_stream, server_stream) = duplex(64 * 1024);
+        let (mut reader, writer) = raw_side(client_stream, &options);
+        writer.write_frame(ConnectionFrame::Hello(old_hello.clone())).await.unwrap();
+        let err = InboundConnection::accept(server_stream, "client".to_string(), &options).await.err().unwrap();
+        assert!(err.contains("Protocol version mismatch"), "{err}");
+        match reader.read_frame().await.unwrap() {
+            ConnectionFrame::HelloReject(frame) => assert_eq!(frame.reason, err),
+            frame => panic!("Expected HelloReject, received {frame:?}"),
+        }
+
+        // the connecting peer checks the version of the hello it receives
+        let (client_stream, server_stream) = duplex(64 * 1024);
+        let (_reader, writer) = raw_side(server_stream, &options);
+        writer.write_frame(ConnectionFrame::Hello(old_hello)).await.unwrap();
+        let err = Connection::from_stream("server".to_string(), client_stream, &options).await.err().unwrap();
+        assert!(err.contains("Protocol version mismatch"), "{err}");
+
+        // and reports the reason of the refusal
+        let (client_stream, server_stream) = duplex(64 * 1024);
+        let (_reader, writer) = raw_side(server_stream, &options);
+        writer.write_frame(ConnectionFrame::HelloReject(HelloRejectFrame { reason: "old version".to_string() })).await.unwrap();
+        let err = Connection::from_stream("server".to_string(), client_stream, &options).await.err().unwrap();
+        assert_eq!(err, "Peer refused the connection: old version");
     }
 }
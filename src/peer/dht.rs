@@ -0,0 +1,494 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::peer::access::is_private;
+use crate::peer::address::is_same_host;
+use crate::peer::connection::{AnnounceFrame, Connection, ConnectionFrame, FindNodeFrame, FindValueFrame, HelloFrame, InboundConnection};
+use crate::peer::state::SharableStateContainer;
+use crate::domain::config::DhtOptions;
+
+// Kademlia-style distributed hash table. Peers and keys (file ids and content hashes) share the
+// same 256-bit id space, the sources of a file are stored on the peers whose ids are the closest
+// to the id of the key by the XOR distance.
+
+const ID_BITS: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId([u8; 32]);
+
+impl NodeId {
+    /// Id of the peer or the key, the sha256 of its string representation.
+    pub fn from_key(key: &str) -> Self {
+        NodeId(Sha256::digest(key.as_bytes()).into())
+    }
+
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0u8; 32];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        NodeId(distance)
+    }
+
+    fn leading_zeros(&self) -> usize {
+        let mut zeros = 0;
+        for byte in self.0 {
+            zeros += byte.leading_zeros() as usize;
+            if byte != 0 {
+                break;
+            }
+        }
+        zeros
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0[..8] {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Contact {
+    pub node_id: NodeId,
+    pub address: String,
+}
+
+impl Contact {
+    /// Contact of the peer that sent the hello, peers without a listen address can't be contacted.
+    pub fn from_hello(hello: &HelloFrame) -> Option<Self> {
+        if hello.listen_address.is_empty() {
+            return None;
+        }
+        Some(Contact { node_id: NodeId::from_key(&hello.peer_id), address: hello.listen_address.clone() })
+    }
+
+    /// Contact of the peer that connected to this peer. The listen address in the hello is not
+    /// verified, so it's taken only if its host is the one the connection came from.
+    pub fn from_inbound(connection: &InboundConnection) -> Option<Self> {
+        Self::from_hello(&connection.remote).filter(|contact| is_same_host(&contact.address, &connection.address))
+    }
+}
+
+/// Contacts grouped in k-buckets by the length of the common prefix with the local id. Like in
+/// Kademlia, the long-known contacts are preferred, a full bucket takes new contacts only after
+/// some of its contacts stop responding and are removed.
+struct RoutingTable {
+    local_id: NodeId,
+    // least recently seen contacts first
+    buckets: Vec<VecDeque<Contact>>,
+    bucket_size: usize,
+}
+
+impl RoutingTable {
+    fn new(local_id: NodeId, bucket_size: usize) -> Self {
+        Self { local_id, buckets: vec![VecDeque::new(); ID_BITS], bucket_size }
+    }
+
+    fn bucket_index(&self, node_id: &NodeId) -> Option<usize> {
+        let zeros = self.local_id.distance(node_id).leading_zeros();
+        (zeros < ID_BITS).then_some(zeros)
+    }
+
+    fn update(&mut self, contact: Contact) {
+        let Some(index) = self.bucket_index(&contact.node_id) else { return };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|c| c.node_id == contact.node_id) {
+            bucket.remove(position);
+            bucket.push_back(contact);
+        } else if bucket.len() < self.bucket_size {
+            bucket.push_back(contact);
+        }
+    }
+
+    fn remove(&mut self, node_id: &NodeId) {
+        if let Some(index) = self.bucket_index(node_id) {
+            self.buckets[index].retain(|c| c.node_id != *node_id);
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts = self.buckets.iter().flatten().cloned().collect::<Vec<_>>();
+        contacts.sort_by_key(|c| c.node_id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+}
+
+/// Routing table of the local node and the sources announced to it by the other peers.
+pub struct Dht {
+    local_id: NodeId,
+    routing_table: RwLock<RoutingTable>,
+    // addresses of the sources by the key, with the time of the last announce
+    providers: Mutex<HashMap<String, HashMap<String, Instant>>>,
+    options: DhtOptions,
+}
+
+impl Dht {
+    pub fn new(peer_id: &str, options: DhtOptions) -> Self {
+        let local_id = NodeId::from_key(peer_id);
+        Self {
+            local_id,
+            routing_table: RwLock::new(RoutingTable::new(local_id, options.bucket_size)),
+            providers: Default::default(),
+            options,
+        }
+    }
+
+    pub fn local_id(&self) -> NodeId {
+        self.local_id
+    }
+
+    pub fn options(&self) -> &DhtOptions {
+        &self.options
+    }
+
+    pub fn add_contact(&self, contact: Contact) {
+        self.routing_table.write().unwrap().update(contact);
+    }
+
+    pub fn remove_contact(&self, node_id: &NodeId) {
+        self.routing_table.write().unwrap().remove(node_id);
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        self.routing_table.read().unwrap().closest(target, count)
+    }
+
+    pub fn contacts_count(&self) -> usize {
+        self.routing_table.read().unwrap().len()
+    }
+
+    /// Records the source of the key. The number of the keys, the keys of one source and the
+    /// sources of one key are limited, so a peer can't grow the table without bound.
+    pub fn add_provider(&self, key: String, address: String) -> Result<(), String> {
+        let mut providers = self.providers.lock().unwrap();
+        if let Some(announced) = providers.get_mut(&key).and_then(|addresses| addresses.get_mut(&address)) {
+            *announced = Instant::now();
+            return Ok(());
+        }
+        remove_expired(&mut providers, self.options.provider_ttl);
+        if providers.values().filter(|addresses| addresses.contains_key(&address)).count() >= self.options.max_keys_per_provider {
+            return Err(format!("Peer {address} announced too many keys"));
+        }
+        if !providers.contains_key(&key) && providers.len() >= self.options.max_provider_keys {
+            return Err("Too many keys are announced".to_string());
+        }
+        let addresses = providers.entry(key).or_default();
+        if addresses.len() >= self.options.max_providers_per_key {
+            return Err("Key has too many sources".to_string());
+        }
+        addresses.insert(address, Instant::now());
+        Ok(())
+    }
+
+    /// Sources of the key announced within the provider ttl.
+    pub fn get_providers(&self, key: &str) -> Vec<String> {
+        let mut providers = self.providers.lock().unwrap();
+        let Some(addresses) = providers.get_mut(key) else { return vec![] };
+        addresses.retain(|_, announced| announced.elapsed() < self.options.provider_ttl);
+        addresses.keys().cloned().collect()
+    }
+}
+
+fn remove_expired(providers: &mut HashMap<String, HashMap<String, Instant>>, ttl: Duration) {
+    for addresses in providers.values_mut() {
+        addresses.retain(|_, announced| announced.elapsed() < ttl);
+    }
+    providers.retain(|_, addresses| !addresses.is_empty());
+}
+
+/// Connects to the contact and refreshes it in the routing table, contacts that can't be reached
+/// are removed.
+async fn connect(container: &SharableStateContainer, contact: &Contact) -> Option<Connection> {
+    match Connection::from_address(&contact.address, &container.connection_options).await {
+        Some(connection) => {
+            if let Some(contact) = Contact::from_hello(&connection.remote) {
+                container.dht.add_contact(contact);
+            }
+            Some(connection)
+        }
+        None => {
+            container.dht.remove_contact(&contact.node_id);
+            None
+        }
+    }
+}
+
+/// Asks the contact for the contacts closest to the target and, if the key is given, for its sources.
+async fn query(container: &SharableStateContainer, contact: &Contact, target: NodeId, key: Option<&str>) -> Result<(Vec<Contact>, Vec<String>), String> {
+    let connection = connect(container, contact).await.ok_or(format!("Failed to connect to {}", contact.address))?;
+    let request_id = connection.next_request_id();
+    let frame = match key {
+        Some(key) => ConnectionFrame::FindValue(FindValueFrame { request_id, key: key.to_string() }),
+        None => ConnectionFrame::FindNode(FindNodeFrame { request_id, target }),
+    };
+    match connection.send_request(frame).await? {
+        ConnectionFrame::Nodes(frame) => Ok((frame.nodes, vec![])),
+        ConnectionFrame::Value(frame) => Ok((frame.nodes, frame.providers)),
+        f => Err(format!("Wrong frame received: {:?}", f)),
+    }
+}
+
+/// Iterative lookup of the contacts closest to the target. Up to alpha closest contacts that are
+/// not queried yet are queried in parallel, until no closer contacts are returned. When the key is
+/// given, the lookup stops after the round that found sources of the key.
+async fn lookup(container: &SharableStateContainer, target: NodeId, key: Option<&str>) -> (Vec<Contact>, Vec<String>) {
+    let local_id = container.dht.local_id();
+    let options = container.dht.options();
+    let mut closest = container.dht.closest(&target, options.bucket_size);
+    let mut queried: HashSet<NodeId> = HashSet::new();
+    let mut providers: HashSet<String> = HashSet::new();
+    loop {
+        let round = closest.iter()
+            .filter(|c| !queried.contains(&c.node_id))
+            .take(options.alpha)
+            .cloned()
+            .collect::<Vec<_>>();
+        if round.is_empty() {
+            break;
+        }
+        queried.extend(round.iter().map(|c| c.node_id));
+        let responses = join_all(round.iter().map(|contact| query(container, contact, target, key))).await;
+        for (contact, response) in round.iter().zip(responses) {
+            let (nodes, found) = match response {
+                Ok(response) => response,
+                Err(err) => {
+                    println!("DHT query to {} failed: {err}", contact.address);
+                    closest.retain(|c| c.node_id != contact.node_id);
+                    continue;
+                }
+            };
+            providers.extend(found);
+            for node in nodes {
+                if node.node_id != local_id && !closest.iter().any(|c| c.node_id == node.node_id) {
+                    closest.push(node);
+                }
+            }
+        }
+        closest.sort_by_key(|c| c.node_id.distance(&target));
+        closest.truncate(options.bucket_size);
+        if !providers.is_empty() {
+            break;
+        }
+    }
+    (closest, providers.into_iter().collect())
+}
+
+/// Addresses of the peers announced as the sources of the key, the file id or the content hash.
+pub async fn find_providers(container: &SharableStateContainer, key: &str) -> Vec<String> {
+    // the download may start before the periodic bootstrap filled the routing table
+    if container.dht.contacts_count() == 0 {
+        bootstrap(container).await;
+    }
+    let mut providers = container.dht.get_providers(key);
+    let (_, found) = lookup(container, NodeId::from_key(key), Some(key)).await;
+    for address in found {
+        if !providers.contains(&address) {
+            providers.push(address);
+        }
+    }
+    providers
+}
+
+/// Announces the local peer as a source of the key to the peers closest to the key.
+pub async fn announce(container: &SharableStateContainer, key: &str) {
+    let (closest, _) = lookup(container, NodeId::from_key(key), None).await;
+    join_all(closest.iter().map(|contact| async move {
+        let Some(connection) = connect(container, contact).await else { return };
+        let request_id = connection.next_request_id();
+        if let Err(err) = connection.send_request(ConnectionFrame::Announce(AnnounceFrame { request_id, key: key.to_string() })).await {
+            println!("Failed to announce {key} to {}: {err}", contact.address);
+        }
+    })).await;
+}
+
+/// Fills the routing table from the known peers and the lookup of the local id.
+pub async fn bootstrap(container: &SharableStateContainer) {
+    let addresses = container.peers.get_known_peers().into_iter().map(|p| p.address).collect();
+    for connection in Connection::from_addresses(addresses, &container.connection_options).await.into_iter().flatten() {
+        if let Some(contact) = Contact::from_hello(&connection.remote) {
+            container.dht.add_contact(contact);
+        }
+    }
+    lookup(container, container.dht.local_id(), None).await;
+    println!("DHT routing table has {} contacts", container.dht.contacts_count());
+}
+
+/// Ids and content hashes of the public files this peer has pieces of.
+async fn announced_keys(container: &SharableStateContainer) -> Vec<String> {
+    let mut keys = vec![];
+    for file in container.file_manager.get_files() {
+        // the DHT is public, the private files are found through the peers listed in the metafile
+        if is_private(&file.data) {
+            continue;
+        }
+        match container.file_manager.get_bitfield(&file.data.id).await {
+            Ok(bitfield) if bitfield.count() > 0 => {}
+            _ => continue,
+        }
+        keys.extend(container.file_manager.file_ids_of(&file));
+        keys.push(file.data.hash.clone());
+    }
+    keys
+}
+
+/// Periodically refreshes the routing table and announces the public files this peer has pieces
+/// of, by their ids and content hashes.
+pub async fn run_dht(container: SharableStateContainer) {
+    loop {
+        bootstrap(&container).await;
+        for key in announced_keys(&container).await {
+            announce(&container, &key).await;
+        }
+        tokio::time::sleep(Duration::from_secs(container.config.dht_announce_secs)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::io::duplex;
+    use crate::domain::config::FSConfig;
+    use crate::domain::files::generate_meta_file;
+    use crate::peer::access::generate_swarm_secret;
+    use crate::peer::connection::ConnectionOptions;
+    use crate::peer::state::State;
+    use super::*;
+
+    /// Contact in the first bucket of the zero local id.
+    fn contact(n: u8) -> Contact {
+        let mut node_id = [0u8; 32];
+        node_id[0] = 0x80;
+        node_id[31] = n;
+        Contact { node_id: NodeId(node_id), address: format!("10.0.0.{n}:8000") }
+    }
+
+    fn bucket(table: &RoutingTable) -> Vec<u8> {
+        table.buckets[0].iter().map(|c| c.node_id.0[31]).collect()
+    }
+
+    #[test]
+    fn full_bucket_keeps_known_contacts() {
+        let mut table = RoutingTable::new(NodeId([0; 32]), 2);
+        for n in 1..=3 {
+            table.update(contact(n));
+        }
+        assert_eq!(bucket(&table), vec![1, 2]);
+        // a contact seen again is moved to the end
+        table.update(contact(1));
+        assert_eq!(bucket(&table), vec![2, 1]);
+        // new contacts are taken only after a contact is removed
+        table.remove(&contact(2).node_id);
+        table.update(contact(3));
+        assert_eq!(bucket(&table), vec![1, 3]);
+        // the local id is not a contact
+        table.update(Contact { node_id: NodeId([0; 32]), address: "10.0.0.9:8000".to_string() });
+        assert_eq!(table.len(), 2);
+    }
+
+    fn dht(provider_ttl: Duration) -> Dht {
+        let options = DhtOptions {
+            provider_ttl,
+            max_providers_per_key: 2,
+            max_keys_per_provider: 2,
+            max_provider_keys: 3,
+            ..Default::default()
+        };
+        Dht::new("local", options)
+    }
+
+    #[test]
+    fn providers_are_limited() {
+        let dht = dht(Duration::from_secs(60));
+        dht.add_provider("a".to_string(), "10.0.0.1:8000".to_string()).unwrap();
+        dht.add_provider("a".to_string(), "10.0.0.2:8000".to_string()).unwrap();
+        let err = dht.add_provider("a".to_string(), "10.0.0.3:8000".to_string()).unwrap_err();
+        assert!(err.contains("too many sources"), "{err}");
+        // a source announced again is refreshed
+        dht.add_provider("a".to_string(), "10.0.0.1:8000".to_string()).unwrap();
+
+        dht.add_provider("b".to_string(), "10.0.0.1:8000".to_string()).unwrap();
+        let err = dht.add_provider("c".to_string(), "10.0.0.1:8000".to_string()).unwrap_err();
+        assert!(err.contains("too many keys"), "{err}");
+
+        dht.add_provider("c".to_string(), "10.0.0.3:8000".to_string()).unwrap();
+        let err = dht.add_provider("d".to_string(), "10.0.0.4:8000".to_string()).unwrap_err();
+        assert!(err.contains("Too many keys are announced"), "{err}");
+
+        let mut providers = dht.get_providers("a");
+        providers.sort();
+        assert_eq!(providers, vec!["10.0.0.1:8000", "10.0.0.2:8000"]);
+    }
+
+    #[test]
+    fn expired_providers_are_removed() {
+        let dht = dht(Duration::ZERO);
+        dht.add_provider("a".to_string(), "10.0.0.1:8000".to_string()).unwrap();
+        assert!(dht.get_providers("a").is_empty());
+        // the expired sources don't count to the limits
+        for n in 1..=3 {
+            dht.add_provider(format!("key {n}"), "10.0.0.1:8000".to_string()).unwrap();
+        }
+    }
+
+    /// Connection accepted from the address, the connecting peer listens on the listen address.
+    async fn accept(listen_address: &str, address: &str) -> (InboundConnection, Connection) {
+        let (client_stream, server_stream) = duplex(64 * 1024);
+        let client_options = ConnectionOptions { peer_id: "client".to_string(), listen_address: listen_address.to_string(), ..Default::default() };
+        let server_options = ConnectionOptions { peer_id: "server".to_string(), ..Default::default() };
+        let (inbound, connection) = tokio::join!(
+            InboundConnection::accept(server_stream, address.to_string(), &server_options),
+            Connection::from_stream("server".to_string(), client_stream, &client_options),
+        );
+        (inbound.unwrap().0, connection.unwrap())
+    }
+
+    #[tokio::test]
+    async fn inbound_contact_must_listen_on_connecting_host() {
+        let (inbound, _connection) = accept("10.0.0.1:8000", "10.0.0.1:51000").await;
+        let contact = Contact::from_inbound(&inbound).unwrap();
+        assert_eq!(contact.address, "10.0.0.1:8000");
+        assert_eq!(contact.node_id, NodeId::from_key("client"));
+
+        let (inbound, _connection) = accept("10.0.0.2:8000", "10.0.0.1:51000").await;
+        assert!(Contact::from_inbound(&inbound).is_none());
+        let (inbound, _connection) = accept("peer.local:8000", "10.0.0.1:51000").await;
+        assert!(Contact::from_inbound(&inbound).is_none());
+        let (inbound, _connection) = accept("", "10.0.0.1:51000").await;
+        assert!(Contact::from_inbound(&inbound).is_none());
+    }
+
+    #[tokio::test]
+    async fn only_public_files_with_pieces_are_announced() {
+        let state = State::new(FSConfig::temp("dht_announced_keys"));
+        let public = generate_meta_file("127.0.0.1:8000".to_string(), "files/image.HEIC", 16384).unwrap();
+        let mut private = generate_meta_file("127.0.0.1:8000".to_string(), "files/0050MSS-061-2008.pdf", 16384).unwrap();
+        private.data.swarm_secret = Some(generate_swarm_secret());
+        let mut allowed = private.clone();
+        allowed.data.swarm_secret = None;
+        allowed.data.allowed_peers = vec!["key".to_string()];
+        allowed.data.id = "0155d08b-609b-45fa-804d-53456c2a863d".to_string();
+        for (file, path) in [(&public, "files/image.HEIC"), (&private, "files/0050MSS-061-2008.pdf"), (&allowed, "files/0050MSS-061-2008.pdf")] {
+            std::fs::copy(path, state.file_manager.fs_config().storage.file_path(&file.data)).unwrap();
+            state.file_manager.add_file(file.clone()).unwrap();
+        }
+        let container = Arc::new(state);
+        assert_eq!(announced_keys(&container).await, vec![public.data.id.clone(), public.data.hash.clone()]);
+
+        // files without any downloaded pieces are not announced
+        std::fs::remove_file(container.file_manager.fs_config().storage.file_path(&public.data)).unwrap();
+        assert!(announced_keys(&container).await.is_empty());
+    }
+}
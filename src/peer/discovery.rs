@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_cbor::from_slice;
+use serde_cbor::ser::to_vec_packed;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use crate::domain::config::DiscoveryOptions;
+use crate::peer::address::is_same_host;
+use crate::peer::protocol::PROTOCOL_VERSION;
+use crate::peer::state::SharableStateContainer;
+use crate::values::{DISCOVERY_KEY_EXPIRY_SECS, DISCOVERY_MAX_AGE_SECS, DISCOVERY_MAX_PINNED_KEYS};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Announcement {
+    pub peer_id: String,
+    pub listen_address: String,
+    pub protocol_version: u8,
+    pub public_key: [u8; 32],
+    /// Seconds since the unix epoch, old announcements are dropped, so they can't be replayed later.
+    pub timestamp: u64,
+}
+
+/// Announcement with the ed25519 signature of its packed CBOR encoding by the key in the announcement.
+#[derive(Serialize, Deserialize, Debug)]
+struct SignedAnnouncement {
+    announcement: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+pub fn sign_announcement(announcement: &Announcement, key: &SigningKey) -> Result<Vec<u8>, String> {
+    let announcement = to_vec_packed(announcement).map_err(|err| format!("Failed to serialize announcement {err}"))?;
+    let signature = key.sign(&announcement).to_bytes().to_vec();
+    to_vec_packed(&SignedAnnouncement { announcement, signature })
+        .map_err(|err| format!("Failed to serialize announcement {err}"))
+}
+
+/// Decodes the announcement and checks its signature, version and age.
+pub fn verify_announcement(data: &[u8]) -> Result<Announcement, String> {
+    let signed: SignedAnnouncement = from_slice(data).map_err(|err| format!("Error when parsing announcement {err}"))?;
+    let announcement: Announcement = from_slice(&signed.announcement)
+        .map_err(|err| format!("Error when parsing announcement {err}"))?;
+    let key = VerifyingKey::from_bytes(&announcement.public_key)
+        .map_err(|err| format!("Invalid announcement key {err}"))?;
+    let signature = Signature::from_slice(&signed.signature)
+        .map_err(|err| format!("Invalid announcement signature {err}"))?;
+    key.verify(&signed.announcement, &signature)
+        .map_err(|_| format!("Announcement of {} has a wrong signature", announcement.peer_id))?;
+    if announcement.protocol_version != PROTOCOL_VERSION {
+        return Err(format!("Announcement of {} has protocol version {}", announcement.peer_id, announcement.protocol_version));
+    }
+    if now_secs().abs_diff(announcement.timestamp) > DISCOVERY_MAX_AGE_SECS {
+        return Err(format!("Announcement of {} is too old", announcement.peer_id));
+    }
+    Ok(announcement)
+}
+
+/// Keys of the announced peer ids with the time they were last announced.
+struct PinnedKeys {
+    keys: HashMap<String, ([u8; 32], Instant)>,
+    max_keys: usize,
+    expiry: Duration,
+}
+
+impl PinnedKeys {
+    fn new(max_keys: usize, expiry: Duration) -> Self {
+        Self { keys: HashMap::new(), max_keys, expiry }
+    }
+
+    /// Pins the key of the peer id if it's not pinned or the pin expired, returns false if the id
+    /// has another key.
+    /// When the map is full the expired keys are removed, then the least recently announced one.
+    fn check(&mut self, peer_id: &str, key: [u8; 32], now: Instant) -> bool {
+        let expiry = self.expiry;
+        let pin = self.keys.get_mut(peer_id).filter(|(_, last_seen)| now.duration_since(*last_seen) < expiry);
+        if let Some((pinned, last_seen)) = pin {
+            if *pinned != key {
+                return false;
+            }
+            *last_seen = now;
+            return true;
+        }
+        if self.keys.len() >= self.max_keys {
+            self.keys.retain(|_, (_, last_seen)| now.duration_since(*last_seen) < expiry);
+        }
+        if self.keys.len() >= self.max_keys {
+            let oldest = self.keys.iter().min_by_key(|(_, (_, last_seen))| *last_seen).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.keys.remove(&oldest);
+            }
+        }
+        self.keys.insert(peer_id.to_string(), (key, now));
+        true
+    }
+}
+
+fn bind_socket(options: &DiscoveryOptions) -> Result<UdpSocket, String> {
+    let map_err = |err: std::io::Error| format!("Error when binding discovery socket: {err}");
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).map_err(map_err)?;
+    // several peers on the same host listen on the same port
+    socket.set_reuse_address(true).map_err(map_err)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true).map_err(map_err)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, options.port)).into()).map_err(map_err)?;
+    socket.join_multicast_v4(&options.group, &options.interface).map_err(map_err)?;
+    socket.set_multicast_if_v4(&options.interface).map_err(map_err)?;
+    socket.set_multicast_loop_v4(true).map_err(map_err)?;
+    socket.set_nonblocking(true).map_err(map_err)?;
+    UdpSocket::from_std(socket.into()).map_err(map_err)
+}
+
+/// Periodically multicasts the signed announcement of this peer and adds the peers announced by
+/// the others to the known peers. Only the addresses on the host the announcement came from are
+/// accepted, so a host can't make the peers connect to other hosts. The key of a peer id is trusted on first use: it's pinned on the
+/// first announcement, so another host can't take over the id, but nothing proves that the first
+/// key belongs to the peer. The pins of the peers that stopped announcing expire, after that the id
+/// can be announced with a new key.
+pub async fn run_discovery(container: SharableStateContainer, options: DiscoveryOptions) {
+    if !options.enabled {
+        return;
+    }
+    let socket = match bind_socket(&options) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("LAN discovery is disabled: {err}");
+            return;
+        }
+    };
+    println!("Running LAN discovery on {}:{}", options.group, options.port);
+    let own_options = &container.connection_options;
+    let group = SocketAddrV4::new(options.group, options.port);
+    let mut keys = PinnedKeys::new(DISCOVERY_MAX_PINNED_KEYS, Duration::from_secs(DISCOVERY_KEY_EXPIRY_SECS));
+    let mut interval = tokio::time::interval(options.interval);
+    let mut buffer = vec![0u8; 2048];
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let announcement = Announcement {
+                    peer_id: own_options.peer_id.clone(),
+                    listen_address: own_options.advertised_address().to_string(),
+                    protocol_version: PROTOCOL_VERSION,
+                    public_key: container.identity_key.verifying_key().to_bytes(),
+                    timestamp: now_secs(),
+                };
+                let result = match sign_announcement(&announcement, &container.identity_key) {
+                    Ok(data) => socket.send_to(&data, group).await.map(|_| ()).map_err(|err| err.to_string()),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    println!("Failed to send discovery announcement: {err}");
+                }
+            }
+            received = socket.recv_from(&mut buffer) => {
+                let (n, from) = match received {
+                    Ok(received) => received,
+                    Err(err) => {
+                        println!("Failed to receive discovery announcement: {err}");
+                        continue;
+                    }
+                };
+                let announcement = match verify_announcement(&buffer[..n]) {
+                    Ok(announcement) => announcement,
+                    Err(err) => {
+                        println!("Dropping discovery announcement from {from}: {err}");
+                        continue;
+                    }
+                };
+                if announcement.peer_id == own_options.peer_id {
+                    continue;
+                }
+                if !is_same_host(&announcement.listen_address, &from.to_string()) {
+                    println!("Dropping discovery announcement from {from}: it announces {}", announcement.listen_address);
+                    continue;
+                }
+                if !keys.check(&announcement.peer_id, announcement.public_key, Instant::now()) {
+                    println!("Dropping discovery announcement from {from}: peer {} changed its key", announcement.peer_id);
+                    continue;
+                }
+                if container.peers.add_peers(vec![announcement.listen_address.clone()], own_options.advertised_address()) > 0 {
+                    println!("Discovered peer {} on {}", announcement.peer_id, announcement.listen_address);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pinned_key_is_kept_until_it_expires() {
+        let mut keys = PinnedKeys::new(10, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(keys.check("peer", [1; 32], start));
+        assert!(!keys.check("peer", [2; 32], start + Duration::from_secs(30)));
+        assert!(keys.check("peer", [1; 32], start + Duration::from_secs(50)));
+        // the pin is refreshed by the announcements of the pinned key
+        assert!(!keys.check("peer", [2; 32], start + Duration::from_secs(100)));
+        assert!(keys.check("peer", [2; 32], start + Duration::from_secs(200)));
+        assert!(!keys.check("peer", [1; 32], start + Duration::from_secs(210)));
+    }
+
+    #[test]
+    fn full_map_drops_expired_and_oldest_keys() {
+        let mut keys = PinnedKeys::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        keys.check("a", [1; 32], start);
+        keys.check("b", [2; 32], start + Duration::from_secs(10));
+        keys.check("c", [3; 32], start + Duration::from_secs(50));
+        assert_eq!(keys.keys.len(), 2);
+        assert!(!keys.keys.contains_key("a"));
+
+        keys.check("d", [4; 32], start + Duration::from_secs(100));
+        assert_eq!(keys.keys.len(), 2);
+        assert!(keys.keys.contains_key("c") && keys.keys.contains_key("d"));
+        // the key of an expired and dropped pin can be replaced
+        assert!(keys.check("b", [5; 32], start + Duration::from_secs(110)));
+    }
+}
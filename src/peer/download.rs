@@ -0,0 +1,427 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::future::{join_all, AbortHandle, Abortable};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{timeout, Instant};
+use crate::domain::bitfield::Bitfield;
+use crate::domain::config::{DownloadOptions, FSConfig};
+use crate::domain::enums::PieceDownloadStatus;
+use crate::domain::files::RFSFile;
+use crate::peer::connection::{Connection, ConnectionFrame, ConnectionOptions, FilePieceResponseFrame, HaveFrame, InboundConnection};
+use crate::peer::download_manager::DownloadEvent;
+use crate::peer::part_file::PartFile;
+use crate::peer::scheduler::PieceScheduler;
+use crate::peer::access::is_private;
+use crate::peer::dht::find_providers;
+use crate::peer::state::SharableStateContainer;
+
+#[derive(Default)]
+struct DownloadRegistryInner {
+    bitfields: HashMap<String, Bitfield>,
+    // inbound connections that requested the bitfield of the file, they are notified about new pieces
+    interested: HashMap<String, Vec<InboundConnection>>,
+}
+
+/// Registry of the running downloads with the verified pieces of their files. Shared between the
+/// downloads and the listener, so the pieces can be served to other peers before the download is finished.
+#[derive(Clone, Default)]
+pub struct DownloadRegistry {
+    inner: Arc<Mutex<DownloadRegistryInner>>,
+}
+
+impl DownloadRegistry {
+    /// Registers the running download, it's removed from the registry when the guard is dropped,
+    /// also when the download task is aborted.
+    pub fn start(&self, file_id: &str, bitfield: Bitfield) -> Result<RunningDownload, String> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.bitfields.contains_key(file_id) {
+            return Err(format!("File {file_id} is already being downloaded"));
+        }
+        inner.bitfields.insert(file_id.to_string(), bitfield);
+        Ok(RunningDownload { registry: self.clone(), file_id: file_id.to_string() })
+    }
+
+    fn finish(&self, file_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.bitfields.remove(file_id);
+        inner.interested.remove(file_id);
+    }
+
+    pub fn get_bitfield(&self, file_id: &str) -> Option<Bitfield> {
+        self.inner.lock().unwrap().bitfields.get(file_id).cloned()
+    }
+
+    /// Subscribes the connection to Have notifications, if the file is being downloaded.
+    pub fn subscribe(&self, file_id: &str, connection: &InboundConnection) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.bitfields.contains_key(file_id) {
+            return;
+        }
+        let interested = inner.interested.entry(file_id.to_string()).or_default();
+        if !interested.iter().any(|c| c.address == connection.address) {
+            interested.push(connection.clone());
+        }
+    }
+
+    /// Records the verified piece and notifies the interested peers about it. Connections that
+    /// can't be written to anymore are unsubscribed.
+    pub async fn add_piece(&self, file_id: &str, piece: u64) {
+        let interested = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(bitfield) = inner.bitfields.get_mut(file_id) {
+                bitfield.set(piece);
+            }
+            inner.interested.get(file_id).cloned().unwrap_or_default()
+        };
+
+        let mut closed = HashSet::new();
+        for connection in interested {
+            let frame = ConnectionFrame::Have(HaveFrame { file_id: file_id.to_string(), piece });
+            if let Err(err) = connection.write_frame(frame).await {
+                println!("Error when notifying {} about piece {piece}: {err}", connection.address);
+                closed.insert(connection.address);
+            }
+        }
+        if !closed.is_empty() {
+            if let Some(interested) = self.inner.lock().unwrap().interested.get_mut(file_id) {
+                interested.retain(|c| !closed.contains(&c.address));
+            }
+        }
+    }
+}
+
+pub struct RunningDownload {
+    registry: DownloadRegistry,
+    file_id: String,
+}
+
+impl Drop for RunningDownload {
+    fn drop(&mut self) {
+        self.registry.finish(&self.file_id);
+    }
+}
+
+/// Peer the pieces are downloaded from, its bitfield is kept by the scheduler.
+struct Source {
+    connection: Arc<Connection>,
+    events: Option<UnboundedReceiver<ConnectionFrame>>,
+}
+
+enum PieceResult {
+    Received(FilePieceResponseFrame),
+    Failed(String),
+    TimedOut,
+}
+
+// request id and abort handle of the piece requested from the source
+type InFlightRequests = HashMap<(usize, u64), (u64, AbortHandle)>;
+
+async fn request_piece(connection: Arc<Connection>, source: usize, request_id: u64, file_id: String, piece: u64, piece_timeout: Duration) -> (usize, u64, PieceResult) {
+    let request = connection.request_file_piece(request_id, file_id, piece);
+    let result = match timeout(piece_timeout, request).await {
+        Ok(Ok(frame)) => PieceResult::Received(frame),
+        Ok(Err(err)) => PieceResult::Failed(err),
+        Err(_) => PieceResult::TimedOut,
+    };
+    (source, piece, result)
+}
+
+/// Download of a single file, peers that sent corrupted pieces are collected in `misbehaving`.
+pub struct Download {
+    pub file: RFSFile,
+    pub fs_config: FSConfig,
+    pub options: ConnectionOptions,
+    pub download_options: DownloadOptions,
+    pub downloads: DownloadRegistry,
+    pub peers: Vec<String>,
+    pub events: broadcast::Sender<DownloadEvent>,
+    pub misbehaving: HashSet<String>,
+}
+
+impl Download {
+    fn send_download_status(&self, piece: u64, status: PieceDownloadStatus) {
+        // there may be no subscribers for the events
+        let _ = self.events.send(DownloadEvent::Piece { file_id: self.file.data.id.clone(), piece, status });
+    }
+
+    /// Connects to the peers and retrieves the bitfields of the file, peers that don't know the
+    /// file are skipped. The swarm secret of a private file is proved first.
+    async fn connect_sources(&self, scheduler: &mut PieceScheduler) -> Vec<Source> {
+        let file_id = &self.file.data.id;
+        let n_pieces = self.file.data.hashes.len() as u64;
+        let connections = Connection::from_addresses(self.peers.clone(), &self.options).await;
+
+        let swarm_secret = self.file.data.swarm_secret.as_deref();
+
+        let sources = join_all(connections.into_iter().flatten().map(|connection| async move {
+            if let Some(secret) = swarm_secret {
+                if let Err(err) = connection.prove_access(file_id.clone(), secret).await {
+                    println!("Peer {} refused access to {file_id}: {err}", connection.address);
+                    return None;
+                }
+            }
+            match connection.get_bitfield(file_id.clone()).await {
+                Ok(bitfield) if bitfield.len() == n_pieces => Some((connection, bitfield)),
+                Ok(_) => {
+                    println!("Peer {} sent a bitfield of wrong length for {file_id}", connection.address);
+                    None
+                }
+                Err(err) => {
+                    println!("Error when getting bitfield of {file_id} from {}: {err}", connection.address);
+                    None
+                }
+            }
+        })).await;
+
+        sources.into_iter().flatten().map(|(connection, bitfield)| {
+            scheduler.add_source(bitfield);
+            Source { events: connection.take_events(), connection: Arc::new(connection) }
+        }).collect()
+    }
+
+    /// Applies the Have frames received from the sources to the scheduler.
+    fn receive_haves(&self, sources: &mut [Source], scheduler: &mut PieceScheduler) {
+        for (i, source) in sources.iter_mut().enumerate() {
+            let Some(events) = source.events.as_mut() else { continue };
+            while let Ok(frame) = events.try_recv() {
+                match frame {
+                    ConnectionFrame::Have(frame) if frame.file_id == self.file.data.id => scheduler.have(i, frame.piece),
+                    // late response to a cancelled or timed out request
+                    ConnectionFrame::FilePieceResponse(_) => {}
+                    frame => println!("Unexpected frame from {}: {:?}", source.connection.address, frame),
+                }
+            }
+        }
+    }
+
+    /// Cancels the duplicate requests of the downloaded piece made in the endgame.
+    async fn cancel_duplicates(&self, sources: &[Source], in_flight: &mut InFlightRequests, duplicates: Vec<usize>, piece: u64) {
+        for i in duplicates {
+            let Some((request_id, abort_handle)) = in_flight.remove(&(i, piece)) else { continue };
+            abort_handle.abort();
+            let connection = &sources[i].connection;
+            if let Err(err) = connection.cancel_file_piece(request_id, self.file.data.id.clone(), piece).await {
+                println!("Error when cancelling piece {piece} request to {}: {err}", connection.address);
+            }
+        }
+    }
+
+    async fn process_piece_result(
+        &mut self,
+        scheduler: &mut PieceScheduler,
+        part_file: &mut PartFile,
+        sources: &[Source],
+        in_flight: &mut InFlightRequests,
+        (i, piece, result): (usize, u64, PieceResult),
+    ) -> Result<(), String> {
+        let request = in_flight.remove(&(i, piece));
+        let connection = &sources[i].connection;
+        let address = &connection.address;
+        match result {
+            PieceResult::Received(_) if part_file.bitfield.has(piece) => {}
+            PieceResult::Received(frame) if frame.piece == piece && self.file.verify_piece(piece, &frame.content) => {
+                part_file.write_piece(piece, &frame.content).await?;
+                let duplicates = scheduler.completed(i, piece);
+                self.cancel_duplicates(sources, in_flight, duplicates, piece).await;
+                self.downloads.add_piece(&self.file.data.id, piece).await;
+                self.send_download_status(piece, PieceDownloadStatus::Downloaded);
+            }
+            PieceResult::Received(_) => {
+                println!("Peer {address} sent a corrupted piece {piece}, re-requesting it from another peer");
+                self.misbehaving.insert(address.clone());
+                scheduler.remove_source(i);
+            }
+            PieceResult::Failed(err) => {
+                println!("Error when getting piece {piece} from {address}: {err}");
+                scheduler.remove_source(i);
+            }
+            PieceResult::TimedOut => {
+                println!("Request for piece {piece} to {address} timed out");
+                // the slow peer shouldn't keep sending the piece that is requested from another one
+                if let Some((request_id, _)) = request {
+                    if let Err(err) = connection.cancel_file_piece(request_id, self.file.data.id.clone(), piece).await {
+                        println!("Error when cancelling piece {piece} request to {address}: {err}");
+                    }
+                }
+                scheduler.timed_out(i, piece);
+            }
+        }
+        Ok(())
+    }
+
+    async fn download_pieces(&mut self, mut part_file: PartFile) -> Result<(), String> {
+        let file_id = self.file.data.id.clone();
+        for piece in 0..part_file.bitfield.len() {
+            if part_file.bitfield.has(piece) {
+                self.send_download_status(piece, PieceDownloadStatus::Downloaded);
+            }
+        }
+
+        let mut scheduler = PieceScheduler::new(&part_file.bitfield, &self.download_options);
+        if !scheduler.is_finished() {
+            let mut sources = self.connect_sources(&mut scheduler).await;
+            let mut requests = FuturesUnordered::new();
+            let mut in_flight = InFlightRequests::new();
+            let mut waiting_since = None;
+
+            while !scheduler.is_finished() {
+                if (0..sources.len()).all(|i| scheduler.is_removed(i)) {
+                    return Err(format!("No accessible peers for file {file_id}"));
+                }
+                self.receive_haves(&mut sources, &mut scheduler);
+                for (i, source) in sources.iter().enumerate() {
+                    while let Some(piece) = scheduler.next_piece(i) {
+                        self.send_download_status(piece, PieceDownloadStatus::Downloading);
+                        let request_id = source.connection.next_request_id();
+                        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+                        in_flight.insert((i, piece), (request_id, abort_handle));
+                        let request = request_piece(source.connection.clone(), i, request_id, file_id.clone(), piece, self.download_options.piece_timeout);
+                        requests.push(Abortable::new(request, abort_registration));
+                    }
+                }
+
+                if requests.is_empty() {
+                    // the missing pieces may still arrive to the sources that are downloading the file
+                    let since = *waiting_since.get_or_insert(Instant::now());
+                    if since.elapsed() >= self.download_options.piece_wait {
+                        return Err(format!("No peer has the pieces {:?} of file {file_id}", scheduler.missing()));
+                    }
+                    tokio::time::sleep(self.download_options.poll_interval).await;
+                    continue;
+                }
+                waiting_since = None;
+
+                tokio::select! {
+                    Some(response) = requests.next() => {
+                        // aborted requests are the endgame duplicates of the downloaded pieces
+                        if let Ok(response) = response {
+                            self.process_piece_result(&mut scheduler, &mut part_file, &sources, &mut in_flight, response).await?;
+                        }
+                    }
+                    // wakes up to pick the new pieces of the sources
+                    _ = tokio::time::sleep(self.download_options.poll_interval) => {}
+                }
+            }
+        }
+
+        if part_file.hash().await? != self.file.data.hash {
+            part_file.remove().await?;
+            return Err(format!("Downloaded file {} doesn't match the metafile hash", self.file.data.name));
+        }
+        part_file.complete(&self.fs_config.storage.file_path(&self.file.data)).await
+    }
+
+    /// Downloads the missing pieces of the file. Download status of every piece is published to
+    /// the events channel.
+    pub async fn run(&mut self) -> Result<(), String> {
+        let part_file = PartFile::open(&self.fs_config, &self.file).await?;
+        let _running = self.downloads.start(&self.file.data.id, part_file.bitfield.clone())?;
+        self.download_pieces(part_file).await
+    }
+}
+
+/// Downloads the file from the peers listed in the metafile, the known peers and the sources
+/// found in the DHT by the file id and the content hash.
+pub async fn download_file(
+    container: &SharableStateContainer,
+    file_id: String,
+    events: broadcast::Sender<DownloadEvent>,
+) -> Result<(), String> {
+    let file = container.file_manager.get_file(&file_id).ok_or("No file with such name")?;
+    let options = &container.connection_options;
+
+    // the file may be present on the known peers that are not listed in the metafile
+    let mut peers: Vec<String> = vec![];
+    let known_peers = container.peers.get_known_peers();
+    // the keys of the private files are not revealed to the DHT nodes
+    let mut providers = vec![];
+    if !is_private(&file.data) {
+        providers.extend(find_providers(container, &file.data.id).await);
+        providers.extend(find_providers(container, &file.data.hash).await);
+    }
+    for address in file.data.peers.iter().chain(known_peers.iter().map(|p| &p.address)).chain(providers.iter()) {
+        if address != options.advertised_address() && !container.peers.is_misbehaving_peer(address) && !peers.contains(address) {
+            peers.push(address.clone());
+        }
+    }
+
+    let mut download = Download {
+        file: file.as_ref().clone(),
+        fs_config: container.file_manager.fs_config().clone(),
+        options: options.clone(),
+        download_options: container.config.download_options(),
+        downloads: container.downloads.clone(),
+        peers,
+        events,
+        misbehaving: HashSet::new(),
+    };
+    let result = download.run().await;
+    container.peers.record_misbehaving_peers(download.misbehaving);
+    if result.is_ok() {
+        // the downloaded content matched the hashes
+        container.file_manager.link_content_id(&file.data.id);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use crate::domain::files::generate_meta_file;
+    use crate::peer::listener::process_inbound_connection;
+    use crate::peer::state::State;
+    use super::*;
+
+    const PATH: &str = "files/image.HEIC";
+
+    /// Serves the file with the given content on a new local port, returns the address.
+    async fn serve(name: &str, file: &RFSFile, content: &[u8]) -> String {
+        let state = State::new(FSConfig::temp(&format!("download_{name}")));
+        std::fs::write(state.file_manager.fs_config().storage.file_path(&file.data), content).unwrap();
+        state.file_manager.add_file(file.clone()).unwrap();
+        let container = Arc::new(state);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (socket, address) = listener.accept().await.unwrap();
+                let mut container = container.clone();
+                tokio::spawn(async move {
+                    let _ = process_inbound_connection(socket, address.to_string(), &mut container).await;
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn corrupted_pieces_are_rejected_and_requested_again() {
+        let file = generate_meta_file("127.0.0.1:1".to_string(), PATH, 16384).unwrap();
+        let content = std::fs::read(PATH).unwrap();
+        let corrupted = content.iter().map(|b| !b).collect::<Vec<_>>();
+        // the corrupting peer is the first source, it's asked for the first pieces
+        let bad = serve("bad_source", &file, &corrupted).await;
+        let good = serve("good_source", &file, &content).await;
+
+        let fs_config = FSConfig::temp("download_corrupted_pieces");
+        let mut download = Download {
+            file: file.clone(),
+            fs_config: fs_config.clone(),
+            options: ConnectionOptions { peer_id: "downloader".to_string(), ..Default::default() },
+            download_options: DownloadOptions::default(),
+            downloads: DownloadRegistry::default(),
+            peers: vec![bad.clone(), good],
+            events: broadcast::channel(1024).0,
+            misbehaving: HashSet::new(),
+        };
+        download.run().await.unwrap();
+
+        assert_eq!(download.misbehaving, HashSet::from([bad]));
+        assert_eq!(std::fs::read(fs_config.storage.file_path(&file.data)).unwrap(), content);
+        assert!(!PartFile::exists(&fs_config, &file));
+    }
+}
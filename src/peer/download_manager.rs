@@ -0,0 +1,377 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use crate::domain::enums::{DownloadStatus, PieceDownloadStatus};
+use crate::peer::download::download_file;
+use crate::peer::part_file::PartFile;
+use crate::peer::state::SharableStateContainer;
+use crate::values::DOWNLOAD_EVENTS_CAPACITY;
+
+#[derive(Clone, Debug)]
+pub enum DownloadEvent {
+    Piece { file_id: String, piece: u64, status: PieceDownloadStatus },
+    Status { file_id: String, status: DownloadStatus },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DownloadInfo {
+    pub file_id: String,
+    pub priority: u8,
+    pub status: DownloadStatus,
+}
+
+struct DownloadEntry {
+    priority: u8,
+    status: DownloadStatus,
+    task: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct DownloadManagerInner {
+    downloads: HashMap<String, DownloadEntry>,
+    // queued downloads by priority and then by the order they were queued in, entries of the
+    // downloads that were paused or cancelled after queueing are skipped
+    queue: BinaryHeap<(u8, Reverse<u64>, String)>,
+    next_seq: u64,
+    running: usize,
+}
+
+impl DownloadManagerInner {
+    fn push(&mut self, file_id: String, priority: u8) {
+        self.next_seq += 1;
+        self.queue.push((priority, Reverse(self.next_seq), file_id));
+    }
+
+    fn info(&self, file_id: &str) -> Option<DownloadInfo> {
+        self.downloads.get(file_id).map(|entry| DownloadInfo {
+            file_id: file_id.to_string(),
+            priority: entry.priority,
+            status: entry.status.clone(),
+        })
+    }
+}
+
+/// Runs the download, a panic fails the download instead of leaving it running forever.
+async fn catch_panic(download: impl Future<Output = Result<(), String>>) -> Result<(), String> {
+    match AssertUnwindSafe(download).catch_unwind().await {
+        Ok(result) => result,
+        Err(panic) => {
+            let message = panic.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Err(format!("Download task panicked: {message}"))
+        }
+    }
+}
+
+/// Owns the download tasks. Downloads are started by priority, at most `max_concurrent` of them
+/// run at once, the rest wait in the queue. Progress of the downloads is published as events.
+#[derive(Clone)]
+pub struct DownloadManager {
+    inner: Arc<Mutex<DownloadManagerInner>>,
+    events: broadcast::Sender<DownloadEvent>,
+    max_concurrent: usize,
+}
+
+impl DownloadManager {
+    pub fn new(max_concurrent: usize) -> Self {
+        let (events, _) = broadcast::channel(DOWNLOAD_EVENTS_CAPACITY);
+        Self {
+            inner: Default::default(),
+            events,
+            max_concurrent,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DownloadEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish_status(&self, file_id: &str, status: DownloadStatus) {
+        // there may be no subscribers for the events
+        let _ = self.events.send(DownloadEvent::Status { file_id: file_id.to_string(), status });
+    }
+
+    pub fn get_downloads(&self) -> Vec<DownloadInfo> {
+        let inner = self.inner.lock().unwrap();
+        let mut downloads = inner.downloads.keys().filter_map(|file_id| inner.info(file_id)).collect::<Vec<_>>();
+        downloads.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.file_id.cmp(&b.file_id)));
+        downloads
+    }
+
+    /// Queues the download of the file. Downloads that are already queued, running or paused are
+    /// left as they are, finished ones are queued again.
+    pub fn enqueue(&self, container: &SharableStateContainer, file_id: String, priority: u8) -> Result<DownloadInfo, String> {
+        if container.file_manager.get_file(&file_id).is_none() {
+            return Err(format!("File not found by id {:?}", file_id));
+        }
+        let info = {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(info) = inner.info(&file_id).filter(|info| !info.status.is_finished()) {
+                return Ok(info);
+            }
+            inner.downloads.insert(file_id.clone(), DownloadEntry { priority, status: DownloadStatus::Queued, task: None });
+            inner.push(file_id.clone(), priority);
+            inner.info(&file_id)
+        };
+        self.publish_status(&file_id, DownloadStatus::Queued);
+        self.schedule(container);
+        info.ok_or(format!("Download of the file {file_id} is not found"))
+    }
+
+    /// Starts the queued downloads with the highest priority while there are free slots.
+    fn schedule(&self, container: &SharableStateContainer) {
+        let mut started = vec![];
+        {
+            let mut inner = self.inner.lock().unwrap();
+            while inner.running < self.max_concurrent {
+                let Some((_, _, file_id)) = inner.queue.pop() else { break };
+                let task = match inner.downloads.get(&file_id) {
+                    Some(entry) if entry.status == DownloadStatus::Queued => self.spawn(container.clone(), file_id.clone()),
+                    _ => continue,
+                };
+                if let Some(entry) = inner.downloads.get_mut(&file_id) {
+                    entry.status = DownloadStatus::Running;
+                    entry.task = Some(task);
+                }
+                inner.running += 1;
+                started.push(file_id);
+            }
+        }
+        for file_id in started {
+            self.publish_status(&file_id, DownloadStatus::Running);
+        }
+    }
+
+    fn spawn(&self, container: SharableStateContainer, file_id: String) -> JoinHandle<()> {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            let result = catch_panic(download_file(&container, file_id.clone(), manager.events.clone())).await;
+            manager.finished(&container, &file_id, result);
+        })
+    }
+
+    fn finished(&self, container: &SharableStateContainer, file_id: &str, result: Result<(), String>) {
+        let status = match result {
+            Ok(()) => DownloadStatus::Completed,
+            Err(err) => {
+                println!("Error when downloading the file {file_id}: {err}");
+                DownloadStatus::Failed(err)
+            }
+        };
+        {
+            let mut inner = self.inner.lock().unwrap();
+            // the download may be paused or cancelled while it was finishing
+            match inner.downloads.get_mut(file_id) {
+                Some(entry) if entry.status == DownloadStatus::Running => {
+                    entry.status = status.clone();
+                    entry.task = None;
+                }
+                _ => return,
+            }
+            inner.running -= 1;
+        }
+        self.publish_status(file_id, status);
+        self.schedule(container);
+    }
+
+    /// Stops the download and sets the new status once its task has stopped. Until then the
+    /// download is `Stopping` and keeps its slot, so it can't be resumed or stopped again while the
+    /// task still holds the file.
+    async fn stop(
+        &self,
+        file_id: &str,
+        status: DownloadStatus,
+        allowed: impl Fn(&DownloadStatus) -> bool,
+    ) -> Result<DownloadInfo, String> {
+        let task = {
+            let mut inner = self.inner.lock().unwrap();
+            let entry = inner.downloads.get_mut(file_id).ok_or(format!("No download of the file {file_id}"))?;
+            if entry.status == DownloadStatus::Stopping || !allowed(&entry.status) {
+                return Err(format!("Download of the file {file_id} is {:?}", entry.status));
+            }
+            if entry.status != DownloadStatus::Running {
+                entry.status = status;
+                return inner.info(file_id).ok_or(format!("No download of the file {file_id}"));
+            }
+            entry.status = DownloadStatus::Stopping;
+            entry.task.take()
+        };
+        if let Some(task) = task {
+            task.abort();
+            let _ = task.await;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.downloads.get_mut(file_id) {
+            entry.status = status;
+        }
+        inner.running -= 1;
+        inner.info(file_id).ok_or(format!("No download of the file {file_id}"))
+    }
+
+    /// Stops the download keeping the downloaded pieces, so it can be resumed later.
+    pub async fn pause(&self, container: &SharableStateContainer, file_id: &str) -> Result<DownloadInfo, String> {
+        let info = self.stop(file_id, DownloadStatus::Paused, |status| {
+            matches!(status, DownloadStatus::Queued | DownloadStatus::Running)
+        }).await?;
+        self.publish_status(file_id, DownloadStatus::Paused);
+        self.schedule(container);
+        Ok(info)
+    }
+
+    /// Queues the paused or failed download again.
+    pub fn resume(&self, container: &SharableStateContainer, file_id: &str) -> Result<DownloadInfo, String> {
+        let info = {
+            let mut inner = self.inner.lock().unwrap();
+            let entry = inner.downloads.get_mut(file_id).ok_or(format!("No download of the file {file_id}"))?;
+            if !matches!(entry.status, DownloadStatus::Paused | DownloadStatus::Failed(_)) {
+                return Err(format!("Download of the file {file_id} is {:?}", entry.status));
+            }
+            entry.status = DownloadStatus::Queued;
+            let priority = entry.priority;
+            inner.push(file_id.to_string(), priority);
+            inner.info(file_id).ok_or(format!("No download of the file {file_id}"))?
+        };
+        self.publish_status(file_id, DownloadStatus::Queued);
+        self.schedule(container);
+        Ok(info)
+    }
+
+    /// Stops the download and removes the downloaded pieces.
+    pub async fn cancel(&self, container: &SharableStateContainer, file_id: &str) -> Result<DownloadInfo, String> {
+        // the task is stopped first, so it doesn't write into the part file after it's removed
+        let info = self.stop(file_id, DownloadStatus::Cancelled, |status| !status.is_finished()).await?;
+        if let Some(file) = container.file_manager.get_file(file_id) {
+            PartFile::discard(container.file_manager.fs_config(), &file).await?;
+        }
+        self.publish_status(file_id, DownloadStatus::Cancelled);
+        self.schedule(container);
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::domain::config::FSConfig;
+    use crate::domain::files::generate_meta_file;
+    use crate::peer::state::State;
+    use super::*;
+
+    /// Peer state in a new temp dir with a file that can be queued for download.
+    fn container(name: &str) -> (SharableStateContainer, String) {
+        let state = State::new(FSConfig::temp(&format!("download_manager_{name}")));
+        let file = generate_meta_file("127.0.0.1:1".to_string(), "files/image.HEIC", 16384).unwrap();
+        let file_id = file.data.id.clone();
+        state.file_manager.add_file(file).unwrap();
+        (Arc::new(state), file_id)
+    }
+
+    /// Sets the flag when the task holding it is dropped.
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Marks the download as running with a task that never finishes, returns the flag set when
+    /// the task is dropped.
+    fn start_endless_task(manager: &DownloadManager, file_id: &str) -> Arc<AtomicBool> {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = DropFlag(dropped.clone());
+        let task = tokio::spawn(async move {
+            let _flag = flag;
+            std::future::pending::<()>().await
+        });
+        let mut inner = manager.inner.lock().unwrap();
+        let entry = inner.downloads.get_mut(file_id).unwrap();
+        entry.status = DownloadStatus::Running;
+        entry.task = Some(task);
+        inner.running += 1;
+        dropped
+    }
+
+    fn status(manager: &DownloadManager, file_id: &str) -> DownloadStatus {
+        manager.inner.lock().unwrap().info(file_id).unwrap().status
+    }
+
+    #[tokio::test]
+    async fn queued_download_is_paused_resumed_and_cancelled() {
+        let (container, file_id) = container("queued");
+        // no free slots, so the download stays queued
+        let manager = DownloadManager::new(0);
+        assert!(manager.enqueue(&container, "unknown".to_string(), 1).is_err());
+        assert_eq!(manager.enqueue(&container, file_id.clone(), 1).unwrap().status, DownloadStatus::Queued);
+
+        assert_eq!(manager.pause(&container, &file_id).await.unwrap().status, DownloadStatus::Paused);
+        assert!(manager.pause(&container, &file_id).await.is_err());
+        assert_eq!(manager.resume(&container, &file_id).unwrap().status, DownloadStatus::Queued);
+        assert!(manager.resume(&container, &file_id).is_err());
+
+        assert_eq!(manager.cancel(&container, &file_id).await.unwrap().status, DownloadStatus::Cancelled);
+        assert!(manager.pause(&container, &file_id).await.is_err());
+        assert!(manager.cancel(&container, &file_id).await.is_err());
+        // finished downloads are queued again
+        assert_eq!(manager.enqueue(&container, file_id.clone(), 1).unwrap().status, DownloadStatus::Queued);
+    }
+
+    #[tokio::test]
+    async fn running_download_is_paused_after_its_task_stops() {
+        let (container, file_id) = container("running");
+        let manager = DownloadManager::new(0);
+        manager.enqueue(&container, file_id.clone(), 1).unwrap();
+        let dropped = start_endless_task(&manager, &file_id);
+
+        assert_eq!(manager.pause(&container, &file_id).await.unwrap().status, DownloadStatus::Paused);
+        assert!(dropped.load(Ordering::SeqCst));
+        assert_eq!(manager.inner.lock().unwrap().running, 0);
+    }
+
+    #[tokio::test]
+    async fn download_is_stopped_once() {
+        let (container, file_id) = container("stopped_once");
+        let manager = DownloadManager::new(0);
+        manager.enqueue(&container, file_id.clone(), 1).unwrap();
+        let dropped = start_endless_task(&manager, &file_id);
+
+        let (paused, cancelled, resumed) = tokio::join!(
+            manager.pause(&container, &file_id),
+            manager.cancel(&container, &file_id),
+            async { manager.resume(&container, &file_id) },
+        );
+        assert_eq!(paused.unwrap().status, DownloadStatus::Paused);
+        assert!(cancelled.unwrap_err().contains("Stopping"));
+        assert!(resumed.unwrap_err().contains("Stopping"));
+        assert!(dropped.load(Ordering::SeqCst));
+        assert_eq!(manager.inner.lock().unwrap().running, 0);
+        assert_eq!(status(&manager, &file_id), DownloadStatus::Paused);
+    }
+
+    #[tokio::test]
+    async fn finished_download_frees_its_slot() {
+        let (container, file_id) = container("finished");
+        let manager = DownloadManager::new(0);
+        manager.enqueue(&container, file_id.clone(), 1).unwrap();
+        start_endless_task(&manager, &file_id);
+
+        manager.finished(&container, &file_id, Err("Download task panicked".to_string()));
+        assert_eq!(status(&manager, &file_id), DownloadStatus::Failed("Download task panicked".to_string()));
+        assert_eq!(manager.inner.lock().unwrap().running, 0);
+        assert_eq!(manager.resume(&container, &file_id).unwrap().status, DownloadStatus::Queued);
+    }
+
+    #[tokio::test]
+    async fn panicking_download_fails() {
+        let result = catch_panic(async { panic!("index out of bounds") }).await;
+        assert_eq!(result, Err("Download task panicked: index out of bounds".to_string()));
+        assert_eq!(catch_panic(async { Ok(()) }).await, Ok(()));
+    }
+}
@@ -1,160 +1,265 @@
 use std::collections::HashMap;
-use tokio::fs;
-use futures::future::join_all;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use tokio;
-use tokio::fs::OpenOptions;
-use tokio::io::AsyncWriteExt;
+use tokio::fs::File;
+use crate::domain::bitfield::Bitfield;
 use crate::domain::config::FSConfig;
-use crate::domain::enums::PieceDownloadStatus;
 use crate::domain::files::RFSFile;
-use crate::peer::connection::{Connection, FilePieceResponseFrame};
-use crate::peer::connection::ConnectionFrame::FilePieceDownloadStatusResponse;
+use crate::peer::cache::LruCache;
+use crate::peer::download::DownloadRegistry;
+use crate::peer::part_file::{read_piece_at, PartFile};
 
+/// Catalog of the known files and the reads of their pieces. The catalog is read-mostly, the
+/// piece cache and the open files have their own locks, so the disk reads of one connection
+/// don't block the other ones.
 pub struct FileManager {
-    files: HashMap<String, RFSFile>,
-    fs_config: FSConfig
+    files: RwLock<HashMap<String, Arc<RFSFile>>>,
+    /// Content ids of the files from the legacy metafiles, mapped to the ids of the metafiles.
+    aliases: RwLock<HashMap<String, String>>,
+    fs_config: FSConfig,
+    downloads: DownloadRegistry,
+    piece_cache: Mutex<LruCache<(String, u64), Vec<u8>>>,
+    open_files: Mutex<LruCache<String, Arc<tokio::sync::Mutex<File>>>>,
 }
 
 impl FileManager {
-    pub async fn get_file_piece(&mut self, file_id: String, piece: u64) -> Result<Vec<u8>, String> {
-        let file = self.files.get(&file_id).ok_or(format!("File not found by id {:?}", file_id))?;
+    /// Pieces of the file this peer can serve. Pieces of a file that is not fully downloaded yet
+    /// are taken from the running download or from the bitfield persisted with the part file.
+    pub async fn get_bitfield(&self, file_id: &str) -> Result<Bitfield, String> {
+        let file = self.get_file(file_id).ok_or(format!("File not found by id {:?}", file_id))?;
+        let n_pieces = file.data.hashes.len() as u64;
+        if Path::new(&self.fs_config.storage.file_path(&file.data)).exists() {
+            return Ok(Bitfield::full(n_pieces));
+        }
+        if let Some(bitfield) = self.downloads.get_bitfield(&file.data.id) {
+            return Ok(bitfield);
+        }
+        Ok(PartFile::load_bitfield(&self.fs_config, &file).await.unwrap_or(Bitfield::new(n_pieces)))
+    }
+
+    async fn open_file(&self, path: &String) -> Result<Arc<tokio::sync::Mutex<File>>, String> {
+        if let Some(handle) = self.open_files.lock().unwrap().get(path) {
+            return Ok(handle.clone());
+        }
+        let handle = tokio::fs::File::open(path).await
+            .map_err(|err| format!("Error when opening file {err}"))?;
+        let handle = Arc::new(tokio::sync::Mutex::new(handle));
+        self.open_files.lock().unwrap().insert(path.clone(), handle.clone(), 1);
+        Ok(handle)
+    }
+
+    /// Reads only the range of the piece from the disk. Pieces of the downloaded files are kept in
+    /// the LRU cache and the files are kept open, so the frequently requested pieces are served
+    /// without touching the disk.
+    pub async fn get_file_piece(&self, file_id: String, piece: u64) -> Result<Vec<u8>, String> {
+        let file = self.get_file(&file_id).ok_or(format!("File not found by id {:?}", file_id))?;
+        if piece >= file.data.hashes.len() as u64 {
+            return Err(format!("File {file_id} has no piece {piece}"));
+        }
+
+        if !Path::new(&self.fs_config.storage.file_path(&file.data)).exists() {
+            if !self.get_bitfield(&file_id).await?.has(piece) {
+                return Err(format!("Piece {piece} of file {file_id} is not available"));
+            }
+            return PartFile::read_available_piece(&self.fs_config, &file, piece).await;
+        }
 
-        // todo: rewrite to read only piece from the fs
-        let contents = tokio::fs::read(file.get_path()).await
-            .map_err(|err| format!("Error when reading file {err}"))?;
+        let key = (file.data.id.clone(), piece);
+        if let Some(content) = self.piece_cache.lock().unwrap().get(&key) {
+            return Ok(content.clone());
+        }
+
+        let handle = self.open_file(&self.fs_config.storage.file_path(&file.data)).await?;
+        let content = read_piece_at(&mut *handle.lock().await, file.data.piece_size, file.data.length, piece).await?;
 
-        let start = (file.data.piece_size * piece) as usize;
-        let end = (file.data.piece_size * (piece + 1)) as usize;
+        self.piece_cache.lock().unwrap().insert(key, content.clone(), content.len());
+        Ok(content)
+    }
 
-        let piece = if end < contents.len() {
-            &contents[start..end]
-        } else {
-            &contents[start..]
-        };
+    /// Finds the file by its id or by the content id of a legacy file.
+    pub fn get_file(&self, file_id: &str) -> Option<Arc<RFSFile>> {
+        let files = self.files.read().unwrap();
+        if let Some(file) = files.get(file_id) {
+            return Some(file.clone());
+        }
+        let alias = self.aliases.read().unwrap().get(file_id).cloned()?;
+        files.get(&alias).cloned()
+    }
 
-        Ok(piece.to_vec())
+    /// Ids the file is served by, the legacy files are also served by their content ids once the
+    /// content is verified.
+    pub fn file_ids_of(&self, file: &RFSFile) -> Vec<String> {
+        let content_id = file.content_id();
+        match self.aliases.read().unwrap().get(&content_id) {
+            Some(alias) if alias == &file.data.id => vec![file.data.id.clone(), content_id],
+            _ => vec![file.data.id.clone()],
+        }
     }
 
     pub fn get_files(&self) -> Vec<RFSFile> {
-        Vec::from_iter(self.files.values().cloned())
+        self.files.read().unwrap().values().map(|f| f.as_ref().clone()).collect()
     }
 
     pub fn get_file_ids(&self) -> Vec<String> {
-        Vec::from_iter(self.files.keys().cloned())
+        self.files.read().unwrap().keys().cloned().collect()
+    }
+
+    pub fn fs_config(&self) -> &FSConfig {
+        &self.fs_config
     }
 }
 
 impl FileManager {
-    pub fn new(fs_config: FSConfig) -> Self {
+    /// The piece cache is bounded by the total size of the cached pieces.
+    pub fn new(fs_config: FSConfig, downloads: DownloadRegistry, piece_cache_size: usize, max_open_files: usize) -> Self {
         Self {
             files: Default::default(),
+            aliases: Default::default(),
             fs_config,
+            downloads,
+            piece_cache: Mutex::new(LruCache::new(piece_cache_size)),
+            open_files: Mutex::new(LruCache::new(max_open_files)),
         }
     }
 
-    fn calculate_pieces_ratio(&self, n_pieces: i64, pings: Vec<u128>) -> Vec<u64> {
-        let values = pings.into_iter().map(|p| 1f64 / (p as f64)).collect::<Vec<f64>>();
-        let sum = values.iter().sum::<f64>();
-        let res = values.into_iter().map(|p| ((p / sum) * n_pieces as f64).round() as u64).collect::<Vec<u64>>();
-        if res.iter().sum::<u64>() != n_pieces as u64 {
-            panic!("Pieces ratio doesn't add up: n_pieces = {n_pieces}, res={:?}", res)
+    /// Files are kept by their ids, so the metafiles of the same content made by different peers
+    /// are one file. A file with the name of another file is refused, they would share the data.
+    pub fn add_file(&self, file: RFSFile) -> Result<(), String> {
+        let mut files = self.files.write().unwrap();
+        self.fs_config.storage.check_collision(&file.data, files.values().map(|f| &f.data))?;
+        let file_id = file.data.id.clone();
+        self.piece_cache.lock().unwrap().retain(|(id, _)| id != &file_id);
+        self.open_files.lock().unwrap().remove(&self.fs_config.storage.file_path(&file.data));
+        files.insert(file_id, Arc::new(file));
+        Ok(())
+    }
+
+    /// Maps the content id of the legacy file to its id. Only called when the content on the disk
+    /// was checked against the piece hashes, so the peer doesn't announce a content it can't serve.
+    pub fn link_content_id(&self, file_id: &str) {
+        let Some(file) = self.get_file(file_id) else { return };
+        let content_id = file.content_id();
+        if !file.is_legacy() || self.files.read().unwrap().contains_key(&content_id) {
+            return;
         }
-        res
+        println!("Legacy file {} is also served by its content id {content_id}", file.data.id);
+        self.aliases.write().unwrap().insert(content_id, file.data.id.clone());
     }
 
-    fn assign_pieces(&self, pieces_ratio: Vec<u64>) -> Vec<Vec<u64>> {
-        let mut i = 0;
-        let mut res = vec![];
-        for r in pieces_ratio {
-            let mut connection_pieces = vec![];
-            for _ in 0..r {
-                connection_pieces.push(i);
-                i += 1;
+    /// Verifies the downloaded content of the legacy files and maps their content ids.
+    pub async fn link_legacy_files(&self) -> Result<(), String> {
+        let files = self.get_files();
+        for file in files.iter().filter(|f| f.is_legacy()) {
+            let path = self.fs_config.storage.file_path(&file.data);
+            if !Path::new(&path).exists() {
+                continue;
+            }
+            match file.verify_file(&path).await {
+                Ok(true) => self.link_content_id(&file.data.id),
+                Ok(false) => println!("File {path} doesn't match the metafile of {}", file.data.id),
+                Err(err) => println!("Failed to verify file {path}: {err}"),
             }
-            res.push(connection_pieces);
-        };
-        res
+        }
+        Ok(())
     }
 
-    pub fn add_file(&mut self, file: RFSFile) {
-        // todo: check if file with this name and piece hashes already present in the system
-        let file_id = file.data.id.clone();
-        self.files.insert(file_id, file);
+    /// Ids of the files that have a partially downloaded data on the disk.
+    pub fn get_partial_file_ids(&self) -> Vec<String> {
+        self.files.read().unwrap().values()
+            .filter(|f| PartFile::exists(&self.fs_config, f))
+            .map(|f| f.data.id.clone())
+            .collect()
     }
 
-    async fn save_file_piece(&self, frame: FilePieceResponseFrame) -> Result<(), String> {
-        let path = "file_pieces/".to_string() + &frame.get_piece_id();
-        fs::write(path, frame.content).await.map_err(|err| format!("Error when writing file piece {err}"))?;
+    /// Checks the partially downloaded data against the piece hashes and rebuilds the persisted
+    /// bitfields, the data may be left in any state if the peer was stopped in the middle of a download.
+    pub async fn scan_partial_files(&self) -> Result<(), String> {
+        for file_id in self.get_partial_file_ids() {
+            let Some(file) = self.get_file(&file_id) else { continue };
+            PartFile::open(&self.fs_config, &file).await?.scan(&file).await?;
+        }
         Ok(())
     }
+}
 
-    async fn assemble_file(&self, file_name: String, piece_ids: Vec<String>) -> Result<(), String> {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(self.fs_config.files_dir.clone() + "/" + &file_name)
-            .await
-            .map_err(|err| format!("Error when opening a file {err}"))?;
-
-        for pid in piece_ids {
-            let path = "file_pieces/".to_string() + &pid;
-            let contents = tokio::fs::read(&path).await
-                .map_err(|err| format!("Error when reading a file piece {err}"))?;
-            file.write(&contents)  // or to use write_all?
-                .await
-                .map_err(|err| format!("Error when writing a file piece {err}"))?;
-            tokio::fs::remove_file(path).await
-                .map_err(|err| format!("Error when removing a file piece {err}"))?;
-        };
-
-        file.flush().await.map_err(|err| format!("Error when flushing a file{err}"))?;
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use crate::domain::files::generate_meta_file;
+    use super::*;
+
+    const LEGACY_ID: &str = "0155d08b-609b-45fa-804d-53456c2a863d";
+
+    fn manager() -> FileManager {
+        FileManager::new(FSConfig::default(), DownloadRegistry::default(), 1024, 1)
     }
 
-    pub async fn download_file(&self, ui_connection: &mut Connection, file_id: String) -> Result<(), String> {
-        let file = self.files.get(&file_id).ok_or("No file with such name")?;
+    fn legacy_file() -> RFSFile {
+        let mut file = generate_meta_file("127.0.0.1:8000".to_string(), "files/image.HEIC", 16384).unwrap();
+        file.data.id = LEGACY_ID.to_string();
+        file
+    }
 
-        let peers = file.data.peers.clone();
+    #[test]
+    fn file_with_name_of_other_file_is_refused() {
+        let manager = manager();
+        let file = generate_meta_file("127.0.0.1:8000".to_string(), "files/image.HEIC", 16384).unwrap();
+        let mut other = generate_meta_file("127.0.0.1:8000".to_string(), "files/0050MSS-061-2008.pdf", 16384).unwrap();
+        other.data.name = file.data.name.clone();
+        manager.add_file(file.clone()).unwrap();
+        assert!(manager.add_file(other.clone()).is_err());
+        assert!(manager.get_file(&other.data.id).is_none());
+        // the legacy metafile of the same content shares the data
+        manager.add_file(legacy_file()).unwrap();
+    }
 
-        let connections: Vec<Option<Connection>> = Connection::from_addresses(peers).await;
+    #[test]
+    fn legacy_file_is_served_by_content_id_once_linked() {
+        let manager = manager();
+        let file = legacy_file();
+        let content_id = file.content_id();
+        manager.add_file(file.clone()).unwrap();
+        assert!(manager.get_file(&content_id).is_none());
+        assert_eq!(manager.file_ids_of(&file), vec![LEGACY_ID.to_string()]);
 
-        let connections = join_all(connections.into_iter().flatten().map(|mut c| async {
-            if let Err(e) = c.retrieve_info().await {
-                println!("Error when retrieving connection info: {:?}", e);
-            };
-            c
-        })).await;
+        manager.link_content_id(LEGACY_ID);
+        assert_eq!(manager.get_file(&content_id).unwrap().data.id, LEGACY_ID);
+        assert_eq!(manager.file_ids_of(&file), vec![LEGACY_ID.to_string(), content_id]);
+    }
 
-        let pings = connections.iter().map(|c| {
-            match &c.info {
-                None => u128::MAX,
-                Some(info) => info.ping as u128
-            }
-        }).collect::<Vec<u128>>();
-
-        let pieces_ratios = self.calculate_pieces_ratio(file.data.hashes.len() as i64, pings);
-        let assigned_pieces = self.assign_pieces(pieces_ratios);
-
-        let mut piece_ids = vec![];
-        for (pieces, mut c) in assigned_pieces.iter().zip(connections) {
-            for piece in pieces {
-                ui_connection.send_file_piece_download_status(
-                    file_id.clone(), piece.to_owned(), PieceDownloadStatus::Downloading,
-                ).await;
-                println!("Written downloading frame to {file_id}");
-                let frame = c.get_file_piece(file_id.clone(), piece.to_owned()).await?;
-                // todo: check piece hash before saving
-                piece_ids.push(frame.get_piece_id());
-                self.save_file_piece(frame).await?;
-
-                ui_connection.send_file_piece_download_status(
-                    file_id.clone(), piece.to_owned(), PieceDownloadStatus::Downloaded,
-                ).await;
-                println!("Written downloaded frame to {file_id}");
-            }
-        };
+    #[test]
+    fn content_id_of_present_file_is_not_aliased() {
+        let manager = manager();
+        let file = legacy_file();
+        let content_id = file.content_id();
+        manager.add_file(generate_meta_file("127.0.0.1:8000".to_string(), "files/image.HEIC", 16384).unwrap()).unwrap();
+        manager.add_file(file.clone()).unwrap();
+        manager.link_content_id(LEGACY_ID);
+        assert_eq!(manager.get_file(&content_id).unwrap().data.id, content_id);
+        assert_eq!(manager.file_ids_of(&file), vec![LEGACY_ID.to_string()]);
+    }
 
-        self.assemble_file(file.data.name.clone(), piece_ids).await?;
-        Ok(())
+    #[tokio::test]
+    async fn downloaded_legacy_file_is_linked_after_verification() {
+        let fs_config = FSConfig::temp("file_manager_legacy");
+        let manager = FileManager::new(fs_config.clone(), DownloadRegistry::default(), 1024, 1);
+        let file = legacy_file();
+        let content_id = file.content_id();
+        manager.add_file(file.clone()).unwrap();
+
+        // the file is not downloaded yet
+        manager.link_legacy_files().await.unwrap();
+        assert!(manager.get_file(&content_id).is_none());
+
+        let mut contents = std::fs::read("files/image.HEIC").unwrap();
+        contents[100] ^= 1;
+        std::fs::write(fs_config.storage.file_path(&file.data), &contents).unwrap();
+        manager.link_legacy_files().await.unwrap();
+        assert!(manager.get_file(&content_id).is_none());
+
+        std::fs::copy("files/image.HEIC", fs_config.storage.file_path(&file.data)).unwrap();
+        manager.link_legacy_files().await.unwrap();
+        assert_eq!(manager.get_file(&content_id).unwrap().data.id, LEGACY_ID);
+        let _ = std::fs::remove_dir_all(&fs_config.rfs_dir);
     }
-}
\ No newline at end of file
+}
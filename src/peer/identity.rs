@@ -0,0 +1,45 @@
+use std::io::Write;
+use ed25519_dalek::{SigningKey, SECRET_KEY_LENGTH};
+use rand_core::OsRng;
+use uuid::Uuid;
+use crate::domain::config::FSConfig;
+
+/// Returns the stable id of the peer, it is generated once and stored in the rfs dir.
+pub fn load_or_create_peer_id(fs_config: &FSConfig) -> String {
+    let path = fs_config.rfs_dir.clone() + "/peer_id";
+    if let Ok(peer_id) = std::fs::read_to_string(&path) {
+        if !peer_id.trim().is_empty() {
+            return peer_id.trim().to_string();
+        }
+    }
+    let peer_id = Uuid::new_v4().to_string();
+    if let Err(err) = std::fs::write(&path, &peer_id) {
+        println!("Unable to save peer id to {path}: {err}");
+    }
+    peer_id
+}
+
+/// Returns the ed25519 key the peer signs its announcements with, it is generated once and stored
+/// in the rfs dir.
+pub fn load_or_create_identity_key(fs_config: &FSConfig) -> SigningKey {
+    let path = fs_config.rfs_dir.clone() + "/identity_key";
+    if let Ok(bytes) = std::fs::read(&path) {
+        if let Ok(secret) = <[u8; SECRET_KEY_LENGTH]>::try_from(bytes.as_slice()) {
+            return SigningKey::from_bytes(&secret);
+        }
+        println!("Identity key in {path} is corrupted, generating a new one");
+    }
+    let key = SigningKey::generate(&mut OsRng);
+    if let Err(err) = write_private_file(&path, key.as_bytes()) {
+        println!("Unable to save identity key to {path}: {err}");
+    }
+    key
+}
+
+pub(crate) fn write_private_file(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
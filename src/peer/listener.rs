@@ -1,85 +1,258 @@
 use std::time::Duration;
+use futures::future::join_all;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use crate::peer::connection::{Connection, ConnectionFrame, FilePieceResponseFrame, GetFileFrame, GetFilePieceFrame, GetInfoFrame, GetPingFrame, InfoResponseFrame, PingResponseFrame};
+use crate::peer::access::verify_access;
+use crate::peer::connection::{AccessGrantedFrame, ChallengeFrame, GetChallengeFrame, ProveAccessFrame, AnnounceFrame, PexFrame, PexResponseFrame, AnnounceResponseFrame, BitfieldFrame, ConnectionFrame, FindNodeFrame, FindValueFrame, NodesFrame, ValueFrame, GetBitfieldFrame, ErrorResponseFrame, FilePieceResponseFrame, GetFilePieceFrame, GetInfoFrame, GetPingFrame, InboundConnection, InfoResponseFrame, PingResponseFrame, Connection, ConnectionOptions};
+use crate::peer::dht::{Contact, NodeId};
+use crate::peer::noise::encode_public_key;
 use crate::peer::state::{KnownPeer, SharableStateContainer};
-use crate::values::SYNC_DELAY_SECS;
+use crate::values::PEX_SAMPLE_SIZE;
+
+/// Private files are served only to the allowed peers, they look the same as unknown files to the others.
+fn check_access(connection: &InboundConnection, container: &SharableStateContainer, file_id: &str) -> Result<(), String> {
+    match container.file_manager.get_file(file_id) {
+        Some(file) if !connection.is_allowed(&file.data) => Err(format!("File not found by id {:?}", file_id)),
+        _ => Ok(()),
+    }
+}
+
+async fn process_get_challenge_frame(
+    connection: &InboundConnection,
+    _: &mut SharableStateContainer,
+    frame: GetChallengeFrame,
+) -> Result<(), String> {
+    connection.write_frame(ConnectionFrame::Challenge(ChallengeFrame {
+        request_id: frame.request_id,
+        nonce: connection.nonce.to_vec(),
+    })).await
+}
+
+async fn process_prove_access_frame(
+    connection: &InboundConnection,
+    container: &mut SharableStateContainer,
+    frame: ProveAccessFrame,
+) -> Result<(), String> {
+    let file = container.file_manager.get_file(&frame.file_id).filter(|file| {
+        let secret = file.data.swarm_secret.as_deref();
+        secret.is_some_and(|secret| verify_access(secret, &connection.nonce, &frame.file_id, &frame.proof))
+    });
+    // the same error for the unknown files and the wrong proofs, so the files can't be probed
+    let Some(file) = file else {
+        return Err(format!("Access to the file {:?} is denied", frame.file_id));
+    };
+    println!("Peer {} proved access to the file {}", connection.address, frame.file_id);
+    // the file may be requested by its content id, the access is kept for the file itself
+    connection.unlock_file(file.data.id.clone());
+    connection.write_frame(ConnectionFrame::AccessGranted(AccessGrantedFrame {
+        request_id: frame.request_id,
+    })).await
+}
 
 async fn process_get_ping_frame(
-    connection: &mut Connection,
+    connection: &InboundConnection,
     _: &mut SharableStateContainer,
-    _: GetPingFrame,
-) {
-    connection.write_frame(ConnectionFrame::PingResponse(PingResponseFrame {})).await;
+    frame: GetPingFrame,
+) -> Result<(), String> {
+    connection.write_frame(ConnectionFrame::PingResponse(PingResponseFrame {
+        request_id: frame.request_id,
+    })).await
 }
 
 async fn process_get_info_frame(
-    connection: &mut Connection,
+    connection: &InboundConnection,
     container: &mut SharableStateContainer,
-    _: GetInfoFrame,
-) {
-    let container_locked = container.lock().await;
+    frame: GetInfoFrame,
+) -> Result<(), String> {
+    // private files are listed only to the peers allowed to get them
+    let file_ids = container.file_manager.get_files().into_iter()
+        .filter(|file| connection.is_allowed(&file.data))
+        .flat_map(|file| container.file_manager.file_ids_of(&file))
+        .collect();
     connection.write_frame(ConnectionFrame::InfoResponse(InfoResponseFrame {
-        file_ids: container_locked.file_manager.get_file_ids(),
-        known_peers: container_locked.known_peers.clone(),
-    })).await;
+        request_id: frame.request_id,
+        address: container.connection_options.advertised_address().to_string(),
+        file_ids,
+        known_peers: container.peers.get_known_peers(),
+    })).await
 }
 
 async fn process_get_file_piece_frame(
-    connection: &mut Connection,
+    connection: &InboundConnection,
     container: &mut SharableStateContainer,
     frame: GetFilePieceFrame,
 ) -> Result<(), String> {
-    let mut container_locked = container.lock().await;
-    let content = container_locked.file_manager.get_file_piece(frame.file_id.clone(), frame.piece).await?;
+    let content = match check_access(connection, container, &frame.file_id) {
+        Ok(()) => container.file_manager.get_file_piece(frame.file_id.clone(), frame.piece).await,
+        Err(err) => Err(err),
+    };
+    if !connection.dequeue_piece_request(frame.request_id) {
+        println!("Dropping response for cancelled request {} of piece {}", frame.request_id, frame.piece);
+        return Ok(());
+    }
+    let content = content?;
     connection.write_frame(ConnectionFrame::FilePieceResponse(FilePieceResponseFrame {
+        request_id: frame.request_id,
         file_id: frame.file_id,
         piece: frame.piece,
         content,
-    })).await;
-    Ok(())
+    })).await
 }
 
-async fn process_get_file_frame(
-    connection: &mut Connection,
+async fn process_get_bitfield_frame(
+    connection: &InboundConnection,
     container: &mut SharableStateContainer,
-    frame: GetFileFrame,
+    frame: GetBitfieldFrame,
 ) -> Result<(), String> {
-    let start = tokio::time::Instant::now();
+    check_access(connection, container, &frame.file_id)?;
+    // subscribing before taking the bitfield, so no piece downloaded in between is missed
+    container.downloads.subscribe(&frame.file_id, connection);
+    let bitfield = container.file_manager.get_bitfield(&frame.file_id).await?;
+    connection.write_frame(ConnectionFrame::Bitfield(BitfieldFrame {
+        request_id: frame.request_id,
+        file_id: frame.file_id,
+        bitfield,
+    })).await
+}
 
-    let container_locked = container.lock().await;
-    // todo: file download potentially long operation, should sync how to not block other connections
-    container_locked.file_manager.download_file(connection, frame.file_id).await?;
+async fn process_find_node_frame(
+    connection: &InboundConnection,
+    container: &mut SharableStateContainer,
+    frame: FindNodeFrame,
+) -> Result<(), String> {
+    connection.write_frame(ConnectionFrame::Nodes(NodesFrame {
+        request_id: frame.request_id,
+        nodes: container.dht.closest(&frame.target, container.dht.options().bucket_size),
+    })).await
+}
 
-    println!("Time spent for processing file download : {}ms", start.elapsed().as_millis());
-    Ok(())
+async fn process_find_value_frame(
+    connection: &InboundConnection,
+    container: &mut SharableStateContainer,
+    frame: FindValueFrame,
+) -> Result<(), String> {
+    connection.write_frame(ConnectionFrame::Value(ValueFrame {
+        request_id: frame.request_id,
+        providers: container.dht.get_providers(&frame.key),
+        nodes: container.dht.closest(&NodeId::from_key(&frame.key), container.dht.options().bucket_size),
+    })).await
 }
 
+async fn process_announce_frame(
+    connection: &InboundConnection,
+    container: &mut SharableStateContainer,
+    frame: AnnounceFrame,
+) -> Result<(), String> {
+    // only the sender itself can be announced, so peers can't direct downloads to other addresses
+    let contact = Contact::from_inbound(connection)
+        .ok_or("Peer can be announced only with a listen address on the host it connects from")?;
+    container.dht.add_provider(frame.key, contact.address)?;
+    connection.write_frame(ConnectionFrame::AnnounceResponse(AnnounceResponseFrame {
+        request_id: frame.request_id,
+    })).await
+}
+
+async fn process_pex_frame(
+    connection: &InboundConnection,
+    container: &mut SharableStateContainer,
+    frame: PexFrame,
+) -> Result<(), String> {
+    let mut peers = frame.peers;
+    peers.truncate(PEX_SAMPLE_SIZE);
+    container.peers.add_peers(peers, container.connection_options.advertised_address());
+    connection.write_frame(ConnectionFrame::PexResponse(PexResponseFrame {
+        request_id: frame.request_id,
+        peers: container.peers.sample_accessible_peers(PEX_SAMPLE_SIZE),
+    })).await
+}
+
+async fn process_frame(
+    connection: &InboundConnection,
+    sharable_state_container: &mut SharableStateContainer,
+    frame: ConnectionFrame,
+) -> Result<(), String> {
+    match frame {
+        ConnectionFrame::GetPing(frame) => {
+            process_get_ping_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::GetInfo(frame) => {
+            process_get_info_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::GetFilePiece(frame) => {
+            process_get_file_piece_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::GetBitfield(frame) => {
+            process_get_bitfield_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::FindNode(frame) => {
+            process_find_node_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::FindValue(frame) => {
+            process_find_value_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::Announce(frame) => {
+            process_announce_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::Pex(frame) => {
+            process_pex_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::GetChallenge(frame) => {
+            process_get_challenge_frame(connection, sharable_state_container, frame).await
+        }
+        ConnectionFrame::ProveAccess(frame) => {
+            process_prove_access_frame(connection, sharable_state_container, frame).await
+        }
+        frame => {
+            Err(format!("Wrong frame received: {:?}", frame))
+        }
+    }
+}
 
 // todo: rewrite with some pattern?
-async fn process_inbound_connection(
-    connection: &mut Connection,
+pub(crate) async fn process_inbound_connection<S: AsyncRead + AsyncWrite + Send + 'static>(
+    socket: S,
+    address: String,
     sharable_state_container: &mut SharableStateContainer,
 ) -> Result<(), String> {
+    let (connection, mut reader) = InboundConnection::accept(socket, address, &sharable_state_container.connection_options).await?;
+    match &connection.remote_key {
+        Some(key) => println!(
+            "Encrypted handshake completed with peer {} ({}) with key {}",
+            connection.remote.peer_id, connection.address, encode_public_key(key),
+        ),
+        None => println!("Handshake completed with peer {} ({})", connection.remote.peer_id, connection.address),
+    }
+    // the reported listen address is taken only from the host it belongs to
+    if let Some(contact) = Contact::from_inbound(&connection) {
+        let options = &sharable_state_container.connection_options;
+        sharable_state_container.peers.add_peers(vec![contact.address.clone()], options.advertised_address());
+        sharable_state_container.dht.add_contact(contact);
+    }
     loop {
         println!("Waiting from new frames...");
-        match connection.read_frame().await? {
-            ConnectionFrame::GetPing(frame) => {
-                process_get_ping_frame(connection, sharable_state_container, frame).await
-            }
-            ConnectionFrame::GetInfo(frame) => {
-                process_get_info_frame(connection, sharable_state_container, frame).await
-            }
-            ConnectionFrame::GetFilePiece(frame) => {
-                process_get_file_piece_frame(connection, sharable_state_container, frame).await?
-            }
-            ConnectionFrame::GetFile(frame) => {
-                process_get_file_frame(connection, sharable_state_container, frame).await?
-            }
-            _ => {
-                eprintln!("Wrong frame received!");
+        let frame = reader.read_frame().await?;
+        let request_id = frame.request_id();
+        match &frame {
+            ConnectionFrame::Cancel(frame) => {
+                connection.cancel_piece_request(frame.request_id);
                 continue;
             }
+            ConnectionFrame::GetFilePiece(_) => connection.queue_piece_request(request_id),
+            _ => {}
         }
+        let connection = connection.clone();
+        let mut sharable_state_container = sharable_state_container.clone();
+        // every request is processed in its own task, so the responses for the requests
+        // multiplexed on the same connection may be written in any order
+        tokio::spawn(async move {
+            if let Err(err) = process_frame(&connection, &mut sharable_state_container, frame).await {
+                println!("Error when processing frame from {}: {err}", connection.address);
+                let _ = connection.write_frame(ConnectionFrame::ErrorResponse(ErrorResponseFrame {
+                    request_id,
+                    message: err,
+                })).await;
+            }
+        });
     };
 }
 
@@ -93,53 +266,146 @@ pub async fn serve_listener(
         println!("Waiting for new connection...");
         let (socket, addr) = listener.accept().await.unwrap();
         println!("Accepted new connection from addr {addr}");
-        let mut connection = Connection::from_stream(socket).await;
         let mut sharable_state_container = sharable_state_container.clone();
         tokio::spawn(async move {
-            process_inbound_connection(&mut connection, &mut sharable_state_container)
-                .await.map_err(|err| {
-                    println!("Error when processing inbound connection: {err}");
-                });
+            if let Err(err) = process_inbound_connection(socket, addr.to_string(), &mut sharable_state_container).await {
+                println!("Error when processing inbound connection: {err}");
+            }
         });
     };
 }
 
+/// Connects to the peer and gets its ping and its static key.
+async fn probe_peer(address: &String, options: &ConnectionOptions) -> (Option<i64>, Option<String>) {
+    let Some(connection) = Connection::from_address(address, options).await else { return (None, None) };
+    match connection.get_ping().await {
+        Ok(v) => (Some(v as i64), connection.remote_key.as_deref().map(encode_public_key)),
+        Err(err) => {
+            println!("Error when getting ping from the client: {err}");
+            (None, None)
+        }
+    }
+}
+
 pub async fn refresh_pings_for_peers(
     sharable_state_container: &mut SharableStateContainer,
 ) {
     loop {
-        let known_peers = {
-            let locked_state_container = sharable_state_container.lock().await;
-            locked_state_container.known_peers.clone()
-        };
-
-        let mut values = vec![];
-        for peer in known_peers {
-            let connection = Connection::from_address(&peer.address).await;
-            if let None = connection {
-                continue
-            }
-            let mut connection = connection.unwrap();
+        let known_peers = sharable_state_container.peers.get_known_peers();
+        let options = &sharable_state_container.connection_options;
+        let timeout = Duration::from_secs(sharable_state_container.config.ping_timeout_secs);
 
-            let ping = match connection.get_ping().await {
-                Ok(v) => v,
-                Err(err) => {
-                    println!("Error when getting ping from the client: {err}");
-                    continue
+        // peers are probed concurrently, a peer that doesn't answer can't hold the others
+        let values: Vec<KnownPeer> = join_all(known_peers.into_iter().map(|peer| async move {
+            // peers that can't be reached lose their ping, so they are not gossiped as accessible
+            let (ping, public_key) = match tokio::time::timeout(timeout, probe_peer(&peer.address, options)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    println!("Peer {} didn't answer the ping in time", peer.address);
+                    (None, None)
                 }
             };
-            values.push(KnownPeer {
+            KnownPeer {
                 address: peer.address,
-                ping: Some(ping as i64),
-            });
-        }
+                ping,
+                public_key,
+            }
+        })).await;
+
+        println!("Updated values for known peers {:?}", values.clone());
+        sharable_state_container.peers.update_pings_for_peers(values);
+        sharable_state_container.peers.expire_peers();
+
+        tokio::time::sleep(Duration::from_secs(sharable_state_container.config.ping_interval_secs)).await;
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio::io::duplex;
+    use crate::domain::config::FSConfig;
+    use crate::domain::files::{generate_meta_file, RFSFile};
+    use crate::domain::bitfield::Bitfield;
+    use crate::peer::access::generate_swarm_secret;
+    use crate::peer::state::State;
+    use super::*;
 
-        {
-            let mut locked_state_container = sharable_state_container.lock().await;
-            println!("Updated values for known peers {:?}", values.clone());
-            locked_state_container.update_pings_for_peers(values);
+    /// Peer serving a downloaded public file and a downloaded private file, returns the private one.
+    fn serving_peer(name: &str) -> (SharableStateContainer, RFSFile, RFSFile) {
+        let state = State::new(FSConfig::temp(&format!("listener_{name}")));
+        let public = generate_meta_file("127.0.0.1:8000".to_string(), "files/image.HEIC", 16384).unwrap();
+        let mut private = generate_meta_file("127.0.0.1:8000".to_string(), "files/0050MSS-061-2008.pdf", 16384).unwrap();
+        private.data.swarm_secret = Some(generate_swarm_secret());
+        for (file, path) in [(&public, "files/image.HEIC"), (&private, "files/0050MSS-061-2008.pdf")] {
+            std::fs::copy(path, state.file_manager.fs_config().storage.file_path(&file.data)).unwrap();
+            state.file_manager.add_file(file.clone()).unwrap();
         }
+        (Arc::new(state), public, private)
+    }
+
+    /// Connects to the peer over an in-memory stream.
+    async fn connect(container: &SharableStateContainer) -> Connection {
+        let (client_stream, server_stream) = duplex(1024 * 1024);
+        let mut container = container.clone();
+        tokio::spawn(async move {
+            let _ = process_inbound_connection(server_stream, "127.0.0.1:9000".to_string(), &mut container).await;
+        });
+        let options = ConnectionOptions { peer_id: "client".to_string(), ..Default::default() };
+        Connection::from_stream("server".to_string(), client_stream, &options).await.unwrap()
+    }
 
-        tokio::time::sleep(Duration::from_secs(SYNC_DELAY_SECS)).await;
+    #[tokio::test]
+    async fn private_file_is_not_listed_to_peer_without_secret() {
+        let (container, public, private) = serving_peer("info");
+        let mut connection = connect(&container).await;
+        connection.retrieve_info().await.unwrap();
+        let file_ids = &connection.info.as_ref().unwrap().file_ids;
+        assert!(file_ids.contains(&public.data.id));
+        assert!(!file_ids.contains(&private.data.id));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn private_file_is_served_only_after_proof() {
+        let (container, public, private) = serving_peer("proof");
+        let connection = connect(&container).await;
+        let n_pieces = private.data.hashes.len() as u64;
+        assert!(connection.get_bitfield(public.data.id.clone()).await.unwrap().is_complete());
+        assert!(connection.get_bitfield(private.data.id.clone()).await.is_err());
+        assert!(connection.get_file_piece(private.data.id.clone(), 0).await.is_err());
+
+        let wrong_secret = generate_swarm_secret();
+        assert!(connection.prove_access(private.data.id.clone(), &wrong_secret).await.is_err());
+        assert!(connection.get_bitfield(private.data.id.clone()).await.is_err());
+
+        let secret = private.data.swarm_secret.clone().unwrap();
+        connection.prove_access(private.data.id.clone(), &secret).await.unwrap();
+        assert_eq!(connection.get_bitfield(private.data.id.clone()).await.unwrap(), Bitfield::full(n_pieces));
+        let piece = connection.get_file_piece(private.data.id.clone(), 0).await.unwrap();
+        assert!(private.verify_piece(0, &piece.content));
+
+        // the proof is bound to the nonce of the connection
+        let other_connection = connect(&container).await;
+        assert!(other_connection.get_bitfield(private.data.id.clone()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pex_frame_adds_bounded_sample_of_valid_peers() {
+        let (container, _, _) = serving_peer("pex");
+        container.peers.add_peers(vec!["10.0.1.1:8000".to_string()], "");
+        container.peers.update_pings_for_peers(vec![KnownPeer { ping: Some(1), ..KnownPeer::new("10.0.1.1:8000".to_string()) }]);
+        let connection = connect(&container).await;
+
+        let own_address = container.connection_options.advertised_address().to_string();
+        let mut peers = vec![own_address.clone(), "0.0.0.0:8000".to_string()];
+        peers.extend((1..=PEX_SAMPLE_SIZE * 2).map(|i| format!("10.0.0.{i}:8000")));
+        let response = connection.exchange_peers(peers).await.unwrap();
+        assert_eq!(response, vec!["10.0.1.1:8000"]);
+
+        // the sent peers are truncated to the sample size, the own and the invalid address are skipped
+        // and the accessible peer was already known
+        let known = container.peers.get_known_peers().into_iter().map(|p| p.address).collect::<Vec<_>>();
+        assert_eq!(known.len(), PEX_SAMPLE_SIZE - 1);
+        assert!(!known.contains(&own_address));
+        assert!(!known.contains(&"0.0.0.0:8000".to_string()));
+    }
+}
@@ -1,6 +1,20 @@
 pub mod client;
 pub mod connection;
+pub mod codec;
+pub mod protocol;
 pub mod file;
+pub mod part_file;
+pub mod download;
+pub mod download_manager;
+pub mod scheduler;
+pub mod dht;
+pub mod pex;
+pub mod discovery;
+pub mod cache;
 pub mod enums;
 pub mod state;
 pub mod listener;
+pub mod identity;
+pub mod address;
+pub mod noise;
+pub mod access;
@@ -0,0 +1,218 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use crate::domain::config::FSConfig;
+use crate::peer::identity::write_private_file;
+
+// Encrypted transport of the peer connections. After the Hello frames are exchanged, peers that
+// both offer the noise feature run a Noise XX handshake with their static keys. Both Hello frames
+// are the prologue of the handshake, so a handshake with tampered Hello frames fails. After the
+// handshake every frame is encrypted, frames longer than a Noise message are encrypted in chunks.
+
+pub const NOISE_FEATURE: &str = "noise";
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const MAX_MESSAGE_SIZE: usize = 65535;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+
+/// Size of the encrypted frame with the given payload size.
+pub fn sealed_size(size: usize) -> usize {
+    size + size.div_ceil(MAX_MESSAGE_SIZE - TAG_SIZE).max(1) * TAG_SIZE
+}
+
+pub fn encode_public_key(key: &[u8]) -> String {
+    general_purpose::STANDARD.encode(key)
+}
+
+fn builder<'a>() -> Builder<'a> {
+    Builder::new(NOISE_PARAMS.parse().expect("Noise params are valid"))
+}
+
+/// Static X25519 keypair of the peer, the public key is the identity of the peer in the encrypted
+/// connections.
+#[derive(Clone)]
+pub struct NoiseKey {
+    private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+impl Debug for NoiseKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseKey").field("public", &encode_public_key(&self.public)).finish()
+    }
+}
+
+impl NoiseKey {
+    pub fn generate() -> Result<Self, String> {
+        let keypair = builder().generate_keypair().map_err(|err| format!("Error when generating noise key {err}"))?;
+        Ok(Self { private: keypair.private, public: keypair.public })
+    }
+
+    /// Returns the key stored in the rfs dir, it is generated once. The private key and the public
+    /// key are stored one after another.
+    pub fn load_or_create(fs_config: &FSConfig) -> Result<Self, String> {
+        let path = fs_config.rfs_dir.clone() + "/noise_key";
+        if let Ok(bytes) = std::fs::read(&path) {
+            if bytes.len() == 2 * KEY_SIZE {
+                return Ok(Self { private: bytes[..KEY_SIZE].to_vec(), public: bytes[KEY_SIZE..].to_vec() });
+            }
+            println!("Noise key in {path} is corrupted, generating a new one");
+        }
+        let key = Self::generate()?;
+        if let Err(err) = write_private_file(&path, &[key.private.as_slice(), key.public.as_slice()].concat()) {
+            println!("Unable to save noise key to {path}: {err}");
+        }
+        Ok(key)
+    }
+
+    pub fn handshake(&self, initiator: bool, prologue: &[u8]) -> Result<Handshake, String> {
+        let builder = builder().local_private_key(&self.private).prologue(prologue);
+        let state = if initiator { builder.build_initiator() } else { builder.build_responder() };
+        Ok(Handshake { state: state.map_err(|err| format!("Error when starting noise handshake {err}"))? })
+    }
+}
+
+pub struct Handshake {
+    state: HandshakeState,
+}
+
+impl Handshake {
+    pub fn is_finished(&self) -> bool {
+        self.state.is_handshake_finished()
+    }
+
+    pub fn is_my_turn(&self) -> bool {
+        self.state.is_my_turn()
+    }
+
+    pub fn write_message(&mut self) -> Result<Vec<u8>, String> {
+        let mut message = vec![0u8; MAX_MESSAGE_SIZE];
+        let size = self.state.write_message(&[], &mut message)
+            .map_err(|err| format!("Noise handshake failed {err}"))?;
+        message.truncate(size);
+        Ok(message)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<(), String> {
+        let mut payload = vec![0u8; MAX_MESSAGE_SIZE];
+        self.state.read_message(message, &mut payload)
+            .map_err(|err| format!("Noise handshake failed {err}"))?;
+        Ok(())
+    }
+
+    /// Finishes the handshake, returns the ciphers of both directions and the static key of the
+    /// remote peer.
+    pub fn into_transport(self) -> Result<(Sealer, Opener, Vec<u8>), String> {
+        let remote_key = self.state.get_remote_static()
+            .ok_or("Remote peer didn't send its static key")?
+            .to_vec();
+        let state = Arc::new(self.state.into_stateless_transport_mode()
+            .map_err(|err| format!("Noise handshake is not finished {err}"))?);
+        Ok((Sealer { state: state.clone(), nonce: 0 }, Opener { state, nonce: 0 }, remote_key))
+    }
+}
+
+/// Encrypts the outgoing frames, the frames must be sent in the order they are encrypted in.
+pub struct Sealer {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Sealer {
+    pub fn seal(&mut self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        let mut data = vec![0u8; sealed_size(payload.len())];
+        // an empty payload is still sent as one message
+        let chunks = match payload.is_empty() {
+            true => vec![payload],
+            false => payload.chunks(MAX_MESSAGE_SIZE - TAG_SIZE).collect(),
+        };
+        let mut offset = 0;
+        for chunk in chunks {
+            offset += self.state.write_message(self.nonce, chunk, &mut data[offset..])
+                .map_err(|err| format!("Error when encrypting frame {err}"))?;
+            self.nonce += 1;
+        }
+        data.truncate(offset);
+        Ok(data)
+    }
+}
+
+/// Decrypts the incoming frames in the order they were received.
+pub struct Opener {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Opener {
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut payload = vec![0u8; data.len()];
+        let mut offset = 0;
+        for chunk in data.chunks(MAX_MESSAGE_SIZE) {
+            offset += self.state.read_message(self.nonce, chunk, &mut payload[offset..])
+                .map_err(|err| format!("Error when decrypting frame {err}"))?;
+            self.nonce += 1;
+        }
+        payload.truncate(offset);
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the handshake between two new keys, returns the ciphers of the initiator and the responder.
+    fn transports(initiator_prologue: &[u8], responder_prologue: &[u8]) -> Result<((Sealer, Opener), (Sealer, Opener)), String> {
+        let mut initiator = NoiseKey::generate()?.handshake(true, initiator_prologue)?;
+        let mut responder = NoiseKey::generate()?.handshake(false, responder_prologue)?;
+        while !initiator.is_finished() || !responder.is_finished() {
+            let (from, to) = match initiator.is_my_turn() {
+                true => (&mut initiator, &mut responder),
+                false => (&mut responder, &mut initiator),
+            };
+            to.read_message(&from.write_message()?)?;
+        }
+        let (initiator_sealer, initiator_opener, _) = initiator.into_transport()?;
+        let (responder_sealer, responder_opener, _) = responder.into_transport()?;
+        Ok(((initiator_sealer, initiator_opener), (responder_sealer, responder_opener)))
+    }
+
+    #[test]
+    fn payloads_are_chunked_at_message_boundary() {
+        let ((mut sealer, _), (_, mut opener)) = transports(b"hello", b"hello").unwrap();
+        let chunk = MAX_MESSAGE_SIZE - TAG_SIZE;
+        for size in [0, 1, chunk - 1, chunk, chunk + 1, 2 * chunk, 200_000] {
+            let payload = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let sealed = sealer.seal(&payload).unwrap();
+            assert_eq!(sealed.len(), sealed_size(size), "payload of {size} bytes");
+            assert_eq!(opener.open(&sealed).unwrap(), payload, "payload of {size} bytes");
+        }
+        assert_eq!(sealed_size(chunk), MAX_MESSAGE_SIZE);
+        assert_eq!(sealed_size(chunk + 1), MAX_MESSAGE_SIZE + 1 + TAG_SIZE);
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let payload = vec![7; 100_000];
+        // a byte of the first chunk, then a byte of the second one
+        for position in [10, MAX_MESSAGE_SIZE + 10] {
+            let ((mut sealer, _), (_, mut opener)) = transports(b"hello", b"hello").unwrap();
+            let mut sealed = sealer.seal(&payload).unwrap();
+            sealed[position] ^= 1;
+            assert!(opener.open(&sealed).is_err());
+        }
+        // frames can't be replayed or reordered
+        let ((mut sealer, _), (_, mut opener)) = transports(b"hello", b"hello").unwrap();
+        let first = sealer.seal(b"first").unwrap();
+        let second = sealer.seal(b"second").unwrap();
+        assert!(opener.open(&second).is_err());
+        assert_eq!(opener.open(&first).unwrap(), b"first");
+    }
+
+    #[test]
+    fn handshake_with_different_prologue_fails() {
+        assert!(transports(b"hello", b"tampered hello").is_err());
+    }
+}
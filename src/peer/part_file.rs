@@ -0,0 +1,244 @@
+use std::io::SeekFrom;
+use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose;
+use sha2::{Digest, Sha256};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use crate::domain::bitfield::Bitfield;
+use crate::domain::config::FSConfig;
+use crate::domain::files::RFSFile;
+use crate::values::DEFAULT_BUFFER_SIZE;
+
+/// Reads exactly the range of the piece, the last piece may be shorter than the piece size.
+pub async fn read_piece_at(file: &mut File, piece_size: u64, length: u64, piece: u64) -> Result<Vec<u8>, String> {
+    let start = piece * piece_size;
+    let mut content = vec![0; piece_size.min(length.saturating_sub(start)) as usize];
+    file.seek(SeekFrom::Start(start)).await
+        .map_err(|err| format!("Error when seeking in a part file {err}"))?;
+    file.read_exact(&mut content).await
+        .map_err(|err| format!("Error when reading a file piece {err}"))?;
+    Ok(content)
+}
+
+/// Partially downloaded file. It is preallocated to the full file length, every piece is written at
+/// its offset, so pieces may arrive in any order. The bitfield of verified pieces is persisted next
+/// to the part file, so the download can be resumed after a restart. When all pieces are present
+/// the file is moved into the files dir.
+pub struct PartFile {
+    path: String,
+    bitfield_path: String,
+    file: File,
+    piece_size: u64,
+    length: u64,
+    pub bitfield: Bitfield,
+}
+
+impl PartFile {
+    pub fn path_for(fs_config: &FSConfig, file: &RFSFile) -> String {
+        fs_config.storage.part_path(&file.data)
+    }
+
+    pub fn bitfield_path_for(fs_config: &FSConfig, file: &RFSFile) -> String {
+        fs_config.storage.bitfield_path(&file.data)
+    }
+
+    pub fn exists(fs_config: &FSConfig, file: &RFSFile) -> bool {
+        Path::new(&PartFile::path_for(fs_config, file)).exists()
+    }
+
+    /// Opens the part file, creating it if the download is just started. The bitfield of an
+    /// existing part file is loaded from the disk, or rebuilt from the data when it is missing.
+    pub async fn open(fs_config: &FSConfig, file: &RFSFile) -> Result<Self, String> {
+        let path = PartFile::path_for(fs_config, file);
+        let bitfield_path = PartFile::bitfield_path_for(fs_config, file);
+        let existed = Path::new(&path).exists();
+
+        let handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await
+            .map_err(|err| format!("Error when opening a part file {err}"))?;
+        handle.set_len(file.data.length).await
+            .map_err(|err| format!("Error when preallocating a part file {err}"))?;
+
+        let n_pieces = file.data.hashes.len() as u64;
+        let saved_bitfield = match tokio::fs::read(&bitfield_path).await {
+            Ok(bytes) => Bitfield::from_bytes(bytes, n_pieces).ok(),
+            Err(_) => None,
+        };
+
+        let mut part_file = PartFile {
+            path,
+            bitfield_path,
+            file: handle,
+            piece_size: file.data.piece_size,
+            length: file.data.length,
+            bitfield: saved_bitfield.clone().unwrap_or(Bitfield::new(n_pieces)),
+        };
+        if existed && saved_bitfield.is_none() {
+            part_file.scan(file).await?;
+        }
+        Ok(part_file)
+    }
+
+    /// Bitfield persisted for the part file, without opening the part file itself.
+    pub async fn load_bitfield(fs_config: &FSConfig, file: &RFSFile) -> Option<Bitfield> {
+        let bytes = tokio::fs::read(PartFile::bitfield_path_for(fs_config, file)).await.ok()?;
+        Bitfield::from_bytes(bytes, file.data.hashes.len() as u64).ok()
+    }
+
+    /// Reads a piece with a separate handle, so the pieces can be served while the download is
+    /// still writing into the part file. The caller must check the piece is present in the bitfield.
+    pub async fn read_available_piece(fs_config: &FSConfig, file: &RFSFile, piece: u64) -> Result<Vec<u8>, String> {
+        let mut handle = File::open(PartFile::path_for(fs_config, file)).await
+            .map_err(|err| format!("Error when opening a part file {err}"))?;
+        read_piece_at(&mut handle, file.data.piece_size, file.data.length, piece).await
+    }
+
+    pub async fn read_piece(&mut self, piece: u64) -> Result<Vec<u8>, String> {
+        read_piece_at(&mut self.file, self.piece_size, self.length, piece).await
+    }
+
+    /// Writes the verified piece and records it in the persisted bitfield.
+    pub async fn write_piece(&mut self, piece: u64, content: &[u8]) -> Result<(), String> {
+        self.file.seek(SeekFrom::Start(piece * self.piece_size)).await
+            .map_err(|err| format!("Error when seeking in a part file {err}"))?;
+        self.file.write_all(content).await
+            .map_err(|err| format!("Error when writing a file piece {err}"))?;
+        // the piece is served from another handle as soon as it's in the bitfield
+        self.file.flush().await.map_err(|err| format!("Error when flushing a file {err}"))?;
+        self.bitfield.set(piece);
+        self.save_bitfield().await
+    }
+
+    async fn save_bitfield(&self) -> Result<(), String> {
+        tokio::fs::write(&self.bitfield_path, self.bitfield.as_bytes()).await
+            .map_err(|err| format!("Error when saving a bitfield {err}"))
+    }
+
+    /// Rebuilds the bitfield by checking every piece of the data against the metafile hashes.
+    pub async fn scan(&mut self, file: &RFSFile) -> Result<(), String> {
+        let mut bitfield = Bitfield::new(file.data.hashes.len() as u64);
+        for piece in 0..bitfield.len() {
+            let content = self.read_piece(piece).await?;
+            if file.verify_piece(piece, &content) {
+                bitfield.set(piece);
+            }
+        }
+        println!("Scanned part file of {}: {}/{} pieces present", file.data.name, bitfield.count(), bitfield.len());
+        self.bitfield = bitfield;
+        self.save_bitfield().await
+    }
+
+    /// Hash of the whole file contents, calculated without loading the file into memory.
+    pub async fn hash(&mut self) -> Result<String, String> {
+        self.file.seek(SeekFrom::Start(0)).await
+            .map_err(|err| format!("Error when seeking in a part file {err}"))?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; DEFAULT_BUFFER_SIZE];
+        loop {
+            let n = self.file.read(&mut buffer).await
+                .map_err(|err| format!("Error when reading a part file {err}"))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        Ok(general_purpose::STANDARD.encode(hasher.finalize()))
+    }
+
+    pub async fn remove(self) -> Result<(), String> {
+        drop(self.file);
+        let _ = tokio::fs::remove_file(&self.bitfield_path).await;
+        tokio::fs::remove_file(&self.path).await
+            .map_err(|err| format!("Error when removing a part file {err}"))
+    }
+
+    /// Removes the part file and its bitfield without opening them, if they exist.
+    pub async fn discard(fs_config: &FSConfig, file: &RFSFile) -> Result<(), String> {
+        let _ = tokio::fs::remove_file(PartFile::bitfield_path_for(fs_config, file)).await;
+        match tokio::fs::remove_file(PartFile::path_for(fs_config, file)).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(format!("Error when removing a part file {err}")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes the contents to the disk and atomically renames the part file into the target path.
+    pub async fn complete(mut self, target: &str) -> Result<(), String> {
+        self.file.flush().await.map_err(|err| format!("Error when flushing a file {err}"))?;
+        self.file.sync_all().await.map_err(|err| format!("Error when syncing a file {err}"))?;
+        drop(self.file);
+        tokio::fs::rename(&self.path, target).await
+            .map_err(|err| format!("Error when moving the downloaded file {err}"))?;
+        let _ = tokio::fs::remove_file(&self.bitfield_path).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::files::generate_meta_file;
+    use super::*;
+
+    const PATH: &str = "files/image.HEIC";
+
+    fn file() -> RFSFile {
+        generate_meta_file("127.0.0.1:8000".to_string(), PATH, 16384).unwrap()
+    }
+
+    #[tokio::test]
+    async fn corrupt_bitfield_on_disk_is_ignored() {
+        let fs_config = FSConfig::temp("part_file_corrupt_bitfield");
+        let file = file();
+        let contents = std::fs::read(PATH).unwrap();
+        let mut part_file = PartFile::open(&fs_config, &file).await.unwrap();
+        part_file.write_piece(0, &contents[..16384]).await.unwrap();
+        drop(part_file);
+        assert!(PartFile::load_bitfield(&fs_config, &file).await.unwrap().has(0));
+
+        let bitfield_path = PartFile::bitfield_path_for(&fs_config, &file);
+        let mut bytes = std::fs::read(&bitfield_path).unwrap();
+        bytes.pop();
+        std::fs::write(&bitfield_path, &bytes).unwrap();
+        assert!(PartFile::load_bitfield(&fs_config, &file).await.is_none());
+
+        // the bitfield is rebuilt from the data of the part file
+        let part_file = PartFile::open(&fs_config, &file).await.unwrap();
+        assert_eq!(part_file.bitfield.missing().len() as u64, part_file.bitfield.len() - 1);
+        assert!(part_file.bitfield.has(0));
+        let _ = std::fs::remove_dir_all(&fs_config.rfs_dir);
+    }
+
+    #[tokio::test]
+    async fn scan_rebuilds_bitfield_after_crash() {
+        let fs_config = FSConfig::temp("part_file_crash");
+        let file = file();
+        let contents = std::fs::read(PATH).unwrap();
+        let piece = |p: usize| &contents[p * 16384..(p + 1) * 16384];
+        let mut part_file = PartFile::open(&fs_config, &file).await.unwrap();
+        for p in 0..3 {
+            part_file.write_piece(p as u64, piece(p)).await.unwrap();
+        }
+        // the peer stopped while writing the piece 1 again and the piece 3, before the bitfield was saved
+        part_file.file.seek(SeekFrom::Start(16384)).await.unwrap();
+        part_file.file.write_all(&piece(1)[..100]).await.unwrap();
+        part_file.file.write_all(&[0; 100]).await.unwrap();
+        part_file.file.seek(SeekFrom::Start(3 * 16384)).await.unwrap();
+        part_file.file.write_all(piece(3)).await.unwrap();
+        part_file.file.flush().await.unwrap();
+        drop(part_file);
+        std::fs::remove_file(PartFile::bitfield_path_for(&fs_config, &file)).unwrap();
+
+        // the piece with the bad hash is not present, the written piece is found
+        let part_file = PartFile::open(&fs_config, &file).await.unwrap();
+        assert!(part_file.bitfield.has(0) && part_file.bitfield.has(2) && part_file.bitfield.has(3));
+        assert!(!part_file.bitfield.has(1));
+        assert_eq!(part_file.bitfield.count(), 3);
+        // the rebuilt bitfield is persisted
+        assert_eq!(PartFile::load_bitfield(&fs_config, &file).await.unwrap(), part_file.bitfield);
+    }
+}
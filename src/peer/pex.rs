@@ -0,0 +1,37 @@
+use std::time::Duration;
+use futures::future::join_all;
+use crate::peer::connection::Connection;
+use crate::peer::state::{shuffle, SharableStateContainer};
+use crate::values::{PEX_FANOUT, PEX_SAMPLE_SIZE};
+
+/// Exchanges the samples of the accessible peers with the peer, returns the number of new peers.
+pub async fn exchange_peers(container: &SharableStateContainer, address: &String) -> Result<usize, String> {
+    let options = &container.connection_options;
+    let connection = Connection::from_address(address, options).await
+        .ok_or(format!("Failed to connect to {address}"))?;
+    let mut peers = connection.exchange_peers(container.peers.sample_accessible_peers(PEX_SAMPLE_SIZE)).await?;
+    peers.truncate(PEX_SAMPLE_SIZE);
+    Ok(container.peers.add_peers(peers, options.advertised_address()))
+}
+
+/// Periodically gossips with a few random known peers, so the whole network is discovered from
+/// a single seed peer. Accessible peers are preferred, the others are used while no peer is pinged yet.
+pub async fn run_pex(container: SharableStateContainer) {
+    loop {
+        let mut targets = container.peers.sample_accessible_peers(PEX_FANOUT);
+        if targets.is_empty() {
+            targets = container.peers.get_known_peers().into_iter().map(|p| p.address).collect();
+            shuffle(&mut targets);
+            targets.truncate(PEX_FANOUT);
+        }
+        let results = join_all(targets.iter().map(|address| exchange_peers(&container, address))).await;
+        for (address, result) in targets.iter().zip(results) {
+            match result {
+                Ok(0) => {}
+                Ok(added) => println!("Learned {added} new peers from {address}"),
+                Err(err) => println!("Peer exchange with {address} failed: {err}"),
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(container.config.pex_interval_secs)).await;
+    }
+}
@@ -0,0 +1,251 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor::from_slice;
+use serde_cbor::ser::to_vec_packed;
+use crate::peer::connection::{ConnectionFrame, FilePieceResponseFrame};
+use crate::peer::noise::NOISE_FEATURE;
+
+// Binary protocol of the peer connections. Every frame starts with a fixed header of the protocol
+// version and the frame kind. File piece responses carry the piece content as raw bytes after a small
+// fixed header, all the other frames are encoded as packed CBOR (struct fields without names).
+//
+// Hello and HelloReject frames are decoded whatever the version in the header is, so the handshake
+// can report a version mismatch instead of failing on the first frame it can't parse.
+//
+// FilePieceResponse layout:
+// | version: u8 | kind: u8 | request_id: u64 | piece: u64 | file_id_len: u16 | file_id | content |
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Optional protocol features this build can negotiate in the handshake.
+pub const SUPPORTED_FEATURES: &[&str] = &[NOISE_FEATURE];
+
+const HEADER_SIZE: usize = 2;
+
+const HELLO: u8 = 0;
+const HELLO_REJECT: u8 = 10;
+const GET_INFO: u8 = 1;
+const INFO_RESPONSE: u8 = 2;
+const GET_PING: u8 = 3;
+const PING_RESPONSE: u8 = 4;
+const GET_FILE_PIECE: u8 = 5;
+const FILE_PIECE_RESPONSE: u8 = 7;
+const ERROR_RESPONSE: u8 = 9;
+const GET_BITFIELD: u8 = 11;
+const BITFIELD: u8 = 12;
+const HAVE: u8 = 13;
+const CANCEL: u8 = 14;
+const FIND_NODE: u8 = 20;
+const NODES: u8 = 21;
+const FIND_VALUE: u8 = 22;
+const VALUE: u8 = 23;
+const ANNOUNCE: u8 = 24;
+const ANNOUNCE_RESPONSE: u8 = 25;
+const PEX: u8 = 26;
+const PEX_RESPONSE: u8 = 27;
+const GET_CHALLENGE: u8 = 28;
+const CHALLENGE: u8 = 29;
+const PROVE_ACCESS: u8 = 30;
+const ACCESS_GRANTED: u8 = 31;
+// kinds 6, 8 and 15-19 were taken by the download control frames, which moved to the control api
+
+fn encode_packed<T: Serialize>(kind: u8, frame: &T) -> Result<Vec<u8>, String> {
+    let body = to_vec_packed(frame).map_err(|err| format!("Failed to serialize frame {err}"))?;
+    let mut data = Vec::with_capacity(HEADER_SIZE + body.len());
+    data.push(PROTOCOL_VERSION);
+    data.push(kind);
+    data.extend_from_slice(&body);
+    Ok(data)
+}
+
+fn decode_packed<T: DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+    from_slice(body).map_err(|err| format!("Error when parsing frame {err}"))
+}
+
+fn encode_file_piece_response(frame: &FilePieceResponseFrame) -> Result<Vec<u8>, String> {
+    let file_id = frame.file_id.as_bytes();
+    let file_id_len = u16::try_from(file_id.len()).map_err(|_| "File id is too long".to_string())?;
+    let mut data = Vec::with_capacity(HEADER_SIZE + 18 + file_id.len() + frame.content.len());
+    data.push(PROTOCOL_VERSION);
+    data.push(FILE_PIECE_RESPONSE);
+    data.extend_from_slice(&frame.request_id.to_be_bytes());
+    data.extend_from_slice(&frame.piece.to_be_bytes());
+    data.extend_from_slice(&file_id_len.to_be_bytes());
+    data.extend_from_slice(file_id);
+    data.extend_from_slice(&frame.content);
+    Ok(data)
+}
+
+fn decode_file_piece_response(body: &[u8]) -> Result<FilePieceResponseFrame, String> {
+    let error = || "File piece response frame is truncated".to_string();
+    let request_id = u64::from_be_bytes(body.get(0..8).ok_or_else(error)?.try_into().unwrap());
+    let piece = u64::from_be_bytes(body.get(8..16).ok_or_else(error)?.try_into().unwrap());
+    let file_id_len = u16::from_be_bytes(body.get(16..18).ok_or_else(error)?.try_into().unwrap()) as usize;
+    let file_id = body.get(18..18 + file_id_len).ok_or_else(error)?;
+    let file_id = String::from_utf8(file_id.to_vec()).map_err(|_| "File id is not valid utf-8".to_string())?;
+    Ok(FilePieceResponseFrame {
+        request_id,
+        file_id,
+        piece,
+        content: body[18 + file_id_len..].to_vec(),
+    })
+}
+
+pub fn encode_frame(frame: &ConnectionFrame) -> Result<Vec<u8>, String> {
+    match frame {
+        ConnectionFrame::Hello(f) => encode_packed(HELLO, f),
+        ConnectionFrame::HelloReject(f) => encode_packed(HELLO_REJECT, f),
+        ConnectionFrame::GetInfo(f) => encode_packed(GET_INFO, f),
+        ConnectionFrame::InfoResponse(f) => encode_packed(INFO_RESPONSE, f),
+        ConnectionFrame::GetPing(f) => encode_packed(GET_PING, f),
+        ConnectionFrame::PingResponse(f) => encode_packed(PING_RESPONSE, f),
+        ConnectionFrame::GetFilePiece(f) => encode_packed(GET_FILE_PIECE, f),
+        ConnectionFrame::FilePieceResponse(f) => encode_file_piece_response(f),
+        ConnectionFrame::ErrorResponse(f) => encode_packed(ERROR_RESPONSE, f),
+        ConnectionFrame::GetBitfield(f) => encode_packed(GET_BITFIELD, f),
+        ConnectionFrame::Bitfield(f) => encode_packed(BITFIELD, f),
+        ConnectionFrame::Have(f) => encode_packed(HAVE, f),
+        ConnectionFrame::Cancel(f) => encode_packed(CANCEL, f),
+        ConnectionFrame::FindNode(f) => encode_packed(FIND_NODE, f),
+        ConnectionFrame::Nodes(f) => encode_packed(NODES, f),
+        ConnectionFrame::FindValue(f) => encode_packed(FIND_VALUE, f),
+        ConnectionFrame::Value(f) => encode_packed(VALUE, f),
+        ConnectionFrame::Announce(f) => encode_packed(ANNOUNCE, f),
+        ConnectionFrame::AnnounceResponse(f) => encode_packed(ANNOUNCE_RESPONSE, f),
+        ConnectionFrame::Pex(f) => encode_packed(PEX, f),
+        ConnectionFrame::PexResponse(f) => encode_packed(PEX_RESPONSE, f),
+        ConnectionFrame::GetChallenge(f) => encode_packed(GET_CHALLENGE, f),
+        ConnectionFrame::Challenge(f) => encode_packed(CHALLENGE, f),
+        ConnectionFrame::ProveAccess(f) => encode_packed(PROVE_ACCESS, f),
+        ConnectionFrame::AccessGranted(f) => encode_packed(ACCESS_GRANTED, f),
+    }
+}
+
+pub fn decode_frame(data: &[u8]) -> Result<ConnectionFrame, String> {
+    if data.len() < HEADER_SIZE {
+        return Err("Frame is shorter than the frame header".to_string());
+    }
+    let (version, kind, body) = (data[0], data[1], &data[HEADER_SIZE..]);
+    match kind {
+        HELLO => return Ok(ConnectionFrame::Hello(decode_packed(body)?)),
+        HELLO_REJECT => return Ok(ConnectionFrame::HelloReject(decode_packed(body)?)),
+        _ => {}
+    }
+    if version != PROTOCOL_VERSION {
+        return Err(format!("Unsupported protocol version {version}, expected {PROTOCOL_VERSION}"));
+    }
+    Ok(match kind {
+        GET_INFO => ConnectionFrame::GetInfo(decode_packed(body)?),
+        INFO_RESPONSE => ConnectionFrame::InfoResponse(decode_packed(body)?),
+        GET_PING => ConnectionFrame::GetPing(decode_packed(body)?),
+        PING_RESPONSE => ConnectionFrame::PingResponse(decode_packed(body)?),
+        GET_FILE_PIECE => ConnectionFrame::GetFilePiece(decode_packed(body)?),
+        FILE_PIECE_RESPONSE => ConnectionFrame::FilePieceResponse(decode_file_piece_response(body)?),
+        ERROR_RESPONSE => ConnectionFrame::ErrorResponse(decode_packed(body)?),
+        GET_BITFIELD => ConnectionFrame::GetBitfield(decode_packed(body)?),
+        BITFIELD => ConnectionFrame::Bitfield(decode_packed(body)?),
+        HAVE => ConnectionFrame::Have(decode_packed(body)?),
+        CANCEL => ConnectionFrame::Cancel(decode_packed(body)?),
+        FIND_NODE => ConnectionFrame::FindNode(decode_packed(body)?),
+        NODES => ConnectionFrame::Nodes(decode_packed(body)?),
+        FIND_VALUE => ConnectionFrame::FindValue(decode_packed(body)?),
+        VALUE => ConnectionFrame::Value(decode_packed(body)?),
+        ANNOUNCE => ConnectionFrame::Announce(decode_packed(body)?),
+        ANNOUNCE_RESPONSE => ConnectionFrame::AnnounceResponse(decode_packed(body)?),
+        PEX => ConnectionFrame::Pex(decode_packed(body)?),
+        PEX_RESPONSE => ConnectionFrame::PexResponse(decode_packed(body)?),
+        GET_CHALLENGE => ConnectionFrame::GetChallenge(decode_packed(body)?),
+        CHALLENGE => ConnectionFrame::Challenge(decode_packed(body)?),
+        PROVE_ACCESS => ConnectionFrame::ProveAccess(decode_packed(body)?),
+        ACCESS_GRANTED => ConnectionFrame::AccessGranted(decode_packed(body)?),
+        kind => return Err(format!("Unknown frame kind {kind}")),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::bitfield::Bitfield;
+    use crate::peer::connection::BitfieldFrame;
+    use super::*;
+
+    const PIECE_SIZE: usize = 16 * 1024;
+    const FILE_ID: &str = "0155d08b-609b-45fa-804d-53456c2a863d";
+
+    #[test]
+    fn file_piece_response_round_trip() {
+        let content = (0..PIECE_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let frame = ConnectionFrame::FilePieceResponse(FilePieceResponseFrame {
+            request_id: 7,
+            file_id: FILE_ID.to_string(),
+            piece: 3,
+            content: content.clone(),
+        });
+        let data = encode_frame(&frame).unwrap();
+        assert_eq!(data.len(), HEADER_SIZE + 18 + FILE_ID.len() + PIECE_SIZE);
+        match decode_frame(&data).unwrap() {
+            ConnectionFrame::FilePieceResponse(frame) => {
+                assert_eq!((frame.request_id, frame.piece), (7, 3));
+                assert_eq!(frame.file_id, FILE_ID);
+                assert_eq!(frame.content, content);
+            }
+            frame => panic!("Unexpected frame {frame:?}"),
+        }
+    }
+
+    #[test]
+    fn file_piece_response_overhead_is_below_one_percent() {
+        // content ids are the longest file ids
+        let file_id = "f".repeat(64);
+        let data = encode_frame(&ConnectionFrame::FilePieceResponse(FilePieceResponseFrame {
+            request_id: u64::MAX,
+            file_id,
+            piece: u64::MAX,
+            content: vec![0; PIECE_SIZE],
+        })).unwrap();
+        let overhead = data.len() - PIECE_SIZE;
+        assert!(overhead * 100 < PIECE_SIZE, "overhead of {overhead} bytes");
+    }
+
+    #[test]
+    fn bitfield_is_encoded_as_bytes() {
+        // bytes over 23 take two bytes as CBOR integers
+        let bitfield = Bitfield::full(8 * 1024);
+        let frame = ConnectionFrame::Bitfield(BitfieldFrame {
+            request_id: 1,
+            file_id: FILE_ID.to_string(),
+            bitfield: bitfield.clone(),
+        });
+        let data = encode_frame(&frame).unwrap();
+        assert!(data.len() < 1024 + FILE_ID.len() + 32, "bitfield frame of {} bytes", data.len());
+        match decode_frame(&data).unwrap() {
+            ConnectionFrame::Bitfield(frame) => assert_eq!(frame.bitfield, bitfield),
+            frame => panic!("Unexpected frame {frame:?}"),
+        }
+    }
+
+    /// Bitfield frame with the bits that don't match the number of pieces.
+    #[derive(Serialize)]
+    struct MalformedBitfieldFrame {
+        request_id: u64,
+        file_id: String,
+        bitfield: (serde_bytes::ByteBuf, u64),
+    }
+
+    #[test]
+    fn malformed_bitfield_is_rejected() {
+        for (bits, len) in [(vec![], 100), (vec![0; 2], 100), (vec![0; 14], 100)] {
+            let data = encode_packed(BITFIELD, &MalformedBitfieldFrame {
+                request_id: 1,
+                file_id: FILE_ID.to_string(),
+                bitfield: (serde_bytes::ByteBuf::from(bits), len),
+            }).unwrap();
+            assert!(decode_frame(&data).is_err());
+        }
+        let data = encode_packed(BITFIELD, &MalformedBitfieldFrame {
+            request_id: 1,
+            file_id: FILE_ID.to_string(),
+            bitfield: (serde_bytes::ByteBuf::from(vec![0; 13]), 100),
+        }).unwrap();
+        assert!(decode_frame(&data).is_ok());
+    }
+}
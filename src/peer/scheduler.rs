@@ -0,0 +1,285 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use crate::domain::bitfield::Bitfield;
+use crate::domain::config::DownloadOptions;
+use crate::values::MAX_PIECE_TIMEOUTS;
+
+/// Decides which piece is requested from which source. Every source has a queue of the requested
+/// pieces, new pieces are handed out as the requests complete, so faster sources get more work.
+/// Rarer pieces are preferred, so the pieces only a few peers have are fetched while they are
+/// still accessible. Sources that time out get a shorter queue and are dropped after a few timeouts,
+/// their pieces are handed out to other sources. In the endgame, when only a few pieces are left,
+/// the same piece is requested from several sources, so the download doesn't wait for the slowest one.
+pub struct PieceScheduler {
+    missing: BTreeSet<u64>,
+    // number of sources that have the piece
+    availability: Vec<u32>,
+    bitfields: Vec<Bitfield>,
+    queues: Vec<HashSet<u64>>,
+    depths: Vec<usize>,
+    timeouts: Vec<u32>,
+    removed: Vec<bool>,
+    // sources the piece is requested from
+    requested: HashMap<u64, Vec<usize>>,
+    queue_depth: usize,
+    endgame_pieces: u64,
+}
+
+impl PieceScheduler {
+    pub fn new(bitfield: &Bitfield, options: &DownloadOptions) -> Self {
+        Self {
+            missing: bitfield.missing().into_iter().collect(),
+            availability: vec![0; bitfield.len() as usize],
+            bitfields: vec![],
+            queues: vec![],
+            depths: vec![],
+            timeouts: vec![],
+            removed: vec![],
+            requested: Default::default(),
+            queue_depth: options.queue_depth,
+            endgame_pieces: options.endgame_pieces,
+        }
+    }
+
+    pub fn add_source(&mut self, bitfield: Bitfield) -> usize {
+        for piece in 0..bitfield.len() {
+            if bitfield.has(piece) {
+                self.availability[piece as usize] += 1;
+            }
+        }
+        self.bitfields.push(bitfield);
+        self.queues.push(Default::default());
+        self.depths.push(self.queue_depth);
+        self.timeouts.push(0);
+        self.removed.push(false);
+        self.bitfields.len() - 1
+    }
+
+    /// Records a piece the source received after its bitfield was sent.
+    pub fn have(&mut self, source: usize, piece: u64) {
+        let bitfield = &mut self.bitfields[source];
+        if piece >= bitfield.len() || bitfield.has(piece) {
+            return;
+        }
+        bitfield.set(piece);
+        if !self.removed[source] {
+            self.availability[piece as usize] += 1;
+        }
+    }
+
+    pub fn has_capacity(&self, source: usize) -> bool {
+        !self.removed[source] && self.queues[source].len() < self.depths[source]
+    }
+
+    pub fn is_endgame(&self) -> bool {
+        (self.missing.len() as u64) < self.endgame_pieces
+    }
+
+    /// Assigns the rarest of the pieces the source has, which are not requested from anyone yet.
+    /// In the endgame the pieces already requested from other sources are assigned as well.
+    pub fn next_piece(&mut self, source: usize) -> Option<u64> {
+        if !self.has_capacity(source) {
+            return None;
+        }
+        let piece = self.missing.iter()
+            .filter(|p| !self.requested.contains_key(p) && self.bitfields[source].has(**p))
+            .min_by_key(|p| self.availability[**p as usize])
+            .cloned()
+            .or_else(|| self.endgame_piece(source))?;
+        self.requested.entry(piece).or_default().push(source);
+        self.queues[source].insert(piece);
+        Some(piece)
+    }
+
+    fn endgame_piece(&self, source: usize) -> Option<u64> {
+        if !self.is_endgame() {
+            return None;
+        }
+        self.missing.iter()
+            .filter(|p| self.bitfields[source].has(**p) && !self.queues[source].contains(p))
+            .min_by_key(|p| self.requested.get(p).map_or(0, |sources| sources.len()))
+            .cloned()
+    }
+
+    /// Marks the piece as downloaded, returns the other sources the piece is still requested from.
+    pub fn completed(&mut self, source: usize, piece: u64) -> Vec<usize> {
+        self.missing.remove(&piece);
+        self.timeouts[source] = 0;
+        self.depths[source] = (self.depths[source] + 1).min(self.queue_depth);
+        let sources = self.requested.remove(&piece).unwrap_or_default();
+        for s in sources.iter() {
+            self.queues[*s].remove(&piece);
+        }
+        sources.into_iter().filter(|s| *s != source).collect()
+    }
+
+    /// Returns the piece back, so it can be requested from another source.
+    pub fn failed(&mut self, source: usize, piece: u64) {
+        self.queues[source].remove(&piece);
+        self.unrequest(source, piece);
+    }
+
+    fn unrequest(&mut self, source: usize, piece: u64) {
+        if let Some(sources) = self.requested.get_mut(&piece) {
+            sources.retain(|s| *s != source);
+            if sources.is_empty() {
+                self.requested.remove(&piece);
+            }
+        }
+    }
+
+    pub fn timed_out(&mut self, source: usize, piece: u64) {
+        self.failed(source, piece);
+        self.timeouts[source] += 1;
+        self.depths[source] = 1;
+        if self.timeouts[source] >= MAX_PIECE_TIMEOUTS {
+            println!("Source {source} timed out {} times, reassigning its pieces", self.timeouts[source]);
+            self.remove_source(source);
+        }
+    }
+
+    /// Stops using the source, all its pieces are returned back.
+    pub fn remove_source(&mut self, source: usize) {
+        if self.removed[source] {
+            return;
+        }
+        self.removed[source] = true;
+        for piece in std::mem::take(&mut self.queues[source]) {
+            self.unrequest(source, piece);
+        }
+        let bitfield = &self.bitfields[source];
+        for piece in 0..bitfield.len() {
+            if bitfield.has(piece) {
+                self.availability[piece as usize] -= 1;
+            }
+        }
+    }
+
+    pub fn is_removed(&self, source: usize) -> bool {
+        self.removed[source]
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    pub fn missing(&self) -> Vec<u64> {
+        self.missing.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::values::{ENDGAME_PIECES, PIECE_QUEUE_DEPTH};
+    use super::*;
+
+    const N_PIECES: u64 = 4 * PIECE_QUEUE_DEPTH as u64;
+
+    fn bitfield(pieces: impl IntoIterator<Item = u64>) -> Bitfield {
+        let mut bitfield = Bitfield::new(N_PIECES);
+        for piece in pieces {
+            bitfield.set(piece);
+        }
+        bitfield
+    }
+
+    fn new_scheduler(bitfield: &Bitfield) -> PieceScheduler {
+        PieceScheduler::new(bitfield, &DownloadOptions::default())
+    }
+
+    fn assign_all(scheduler: &mut PieceScheduler, source: usize) -> Vec<u64> {
+        std::iter::from_fn(|| scheduler.next_piece(source)).collect()
+    }
+
+    #[test]
+    fn rarest_pieces_are_assigned_first() {
+        let mut scheduler = new_scheduler(&Bitfield::new(N_PIECES));
+        let all = scheduler.add_source(Bitfield::full(N_PIECES));
+        scheduler.add_source(bitfield(0..N_PIECES / 2));
+        scheduler.add_source(bitfield(0..N_PIECES / 4));
+        // only the first source has the second half
+        let pieces = assign_all(&mut scheduler, all);
+        assert!(pieces.iter().all(|p| *p >= N_PIECES / 2), "{pieces:?}");
+        // the second source gets the pieces the third one doesn't have
+        let pieces = assign_all(&mut scheduler, 1);
+        assert!(pieces.iter().all(|p| (N_PIECES / 4..N_PIECES / 2).contains(p)), "{pieces:?}");
+    }
+
+    #[test]
+    fn queue_of_source_is_limited() {
+        let mut scheduler = new_scheduler(&Bitfield::new(N_PIECES));
+        let source = scheduler.add_source(Bitfield::full(N_PIECES));
+        let pieces = assign_all(&mut scheduler, source);
+        assert_eq!(pieces.len(), PIECE_QUEUE_DEPTH);
+        assert!(!scheduler.has_capacity(source));
+        scheduler.completed(source, pieces[0]);
+        assert!(scheduler.next_piece(source).is_some());
+        assert!(scheduler.next_piece(source).is_none());
+    }
+
+    #[test]
+    fn already_downloaded_pieces_are_not_assigned() {
+        let mut scheduler = new_scheduler(&bitfield(1..N_PIECES));
+        let source = scheduler.add_source(Bitfield::full(N_PIECES));
+        assert_eq!(assign_all(&mut scheduler, source), vec![0]);
+    }
+
+    #[test]
+    fn timed_out_piece_is_requeued() {
+        let mut scheduler = new_scheduler(&Bitfield::new(N_PIECES));
+        let slow = scheduler.add_source(bitfield([0]));
+        let fast = scheduler.add_source(bitfield([0]));
+        assert_eq!(scheduler.next_piece(slow), Some(0));
+        assert_eq!(scheduler.next_piece(fast), None);
+
+        scheduler.timed_out(slow, 0);
+        assert_eq!(scheduler.next_piece(fast), Some(0));
+        // the source that timed out gets a queue of one piece
+        let mut scheduler = new_scheduler(&Bitfield::new(N_PIECES));
+        let slow = scheduler.add_source(Bitfield::full(N_PIECES));
+        let piece = scheduler.next_piece(slow).unwrap();
+        scheduler.timed_out(slow, piece);
+        assert_eq!(assign_all(&mut scheduler, slow).len(), 1);
+    }
+
+    #[test]
+    fn source_is_removed_after_timeouts() {
+        let mut scheduler = new_scheduler(&Bitfield::new(N_PIECES));
+        let slow = scheduler.add_source(Bitfield::full(N_PIECES));
+        let other = scheduler.add_source(Bitfield::full(N_PIECES));
+        for _ in 0..MAX_PIECE_TIMEOUTS {
+            let piece = scheduler.next_piece(slow).unwrap();
+            scheduler.timed_out(slow, piece);
+        }
+        assert!(scheduler.is_removed(slow));
+        assert!(scheduler.next_piece(slow).is_none());
+        assert_eq!(assign_all(&mut scheduler, other).len(), PIECE_QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn endgame_requests_pieces_from_several_sources() {
+        let left = ENDGAME_PIECES - 1;
+        let mut scheduler = new_scheduler(&bitfield(0..N_PIECES - left));
+        assert!(scheduler.is_endgame());
+        let first = scheduler.add_source(Bitfield::full(N_PIECES));
+        let second = scheduler.add_source(Bitfield::full(N_PIECES));
+        let pieces = assign_all(&mut scheduler, first);
+        assert_eq!(pieces.len() as u64, left);
+        // the second source gets the same pieces
+        let mut duplicates = assign_all(&mut scheduler, second);
+        duplicates.sort();
+        assert_eq!(duplicates, (N_PIECES - left..N_PIECES).collect::<Vec<_>>());
+        // the duplicate requests are returned, so they can be cancelled
+        assert_eq!(scheduler.completed(second, pieces[0]), vec![first]);
+        assert!(scheduler.completed(first, pieces[1]).contains(&second));
+    }
+
+    #[test]
+    fn no_duplicates_before_endgame() {
+        let mut scheduler = new_scheduler(&bitfield(0..N_PIECES - ENDGAME_PIECES));
+        assert!(!scheduler.is_endgame());
+        let first = scheduler.add_source(Bitfield::full(N_PIECES));
+        let second = scheduler.add_source(Bitfield::full(N_PIECES));
+        assert_eq!(assign_all(&mut scheduler, first).len() as u64, ENDGAME_PIECES);
+        assert!(scheduler.next_piece(second).is_none());
+    }
+}
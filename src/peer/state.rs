@@ -1,12 +1,23 @@
-use std::sync::Arc;
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hash};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use ed25519_dalek::SigningKey;
 use serde::{Deserialize, Serialize};
 use crate::peer::client::{LocalFSInfo};
-use tokio::sync::{Mutex};
-use crate::domain::config::FSConfig;
+use crate::domain::config::{FSConfig, PeerConfig};
 use crate::domain::enums::PieceDownloadStatus;
+use crate::peer::connection::ConnectionOptions;
+use crate::peer::dht::Dht;
+use crate::peer::download::DownloadRegistry;
+use crate::peer::download_manager::DownloadManager;
 use crate::peer::file::FileManager;
+use crate::peer::identity::{load_or_create_identity_key, load_or_create_peer_id};
+use crate::peer::noise::NoiseKey;
 
-pub type SharableStateContainer = Arc<Mutex<State>>;
+pub type SharableStateContainer = Arc<State>;
 
 #[derive(Clone)]
 pub struct PieceDownloadProgress {
@@ -39,34 +50,303 @@ impl FileDownloadProgress {
 pub struct KnownPeer {
     pub address: String,
     pub ping: Option<i64>,
+    /// Static key the peer presented in an encrypted connection, it identifies the peer.
+    #[serde(default)]
+    pub public_key: Option<String>,
 }
 
 impl KnownPeer {
+    pub fn new(address: String) -> Self {
+        Self { address, ping: None, public_key: None }
+    }
+
     pub fn accessible(&self) -> bool {
         self.ping.is_some()
     }
 }
 
+struct PeerEntry {
+    peer: KnownPeer,
+    // when the peer was added or last responded to a ping
+    last_seen: Instant,
+}
+
+/// Checks that the address gossiped by another peer can be connected to, either an ip address or
+/// a host name with a port.
+pub fn validate_peer_address(address: &str) -> Result<(), String> {
+    if let Ok(socket_address) = address.parse::<SocketAddr>() {
+        let ip = socket_address.ip();
+        if socket_address.port() == 0 || ip.is_unspecified() || ip.is_multicast() {
+            return Err(format!("Peer address {address} can't be connected to"));
+        }
+        return Ok(());
+    }
+    let (host, port) = address.rsplit_once(':').ok_or(format!("Invalid peer address {address}"))?;
+    let valid_host = !host.is_empty() && host.len() <= 253
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        && !host.starts_with(['-', '.']);
+    if !valid_host || !port.parse::<u16>().is_ok_and(|port| port != 0) {
+        return Err(format!("Invalid peer address {address}"));
+    }
+    Ok(())
+}
+
+/// Known peers and the peers that sent corrupted pieces during the downloads. The table is
+/// bounded, peers that don't respond for a while are aged out.
+pub struct PeerTable {
+    known_peers: RwLock<Vec<PeerEntry>>,
+    misbehaving_peers: RwLock<HashSet<String>>,
+    max_known_peers: usize,
+    peer_expiry: Duration,
+}
+
+impl Default for PeerTable {
+    fn default() -> Self {
+        let config = PeerConfig::default();
+        Self::new(config.max_known_peers, Duration::from_secs(config.peer_expiry_secs))
+    }
+}
+
+impl PeerTable {
+    pub fn new(max_known_peers: usize, peer_expiry: Duration) -> Self {
+        Self {
+            known_peers: Default::default(),
+            misbehaving_peers: Default::default(),
+            max_known_peers,
+            peer_expiry,
+        }
+    }
+
+    pub fn get_known_peers(&self) -> Vec<KnownPeer> {
+        self.known_peers.read().unwrap().iter().map(|e| e.peer.clone()).collect()
+    }
+
+    pub fn set_known_peers(&self, peers: Vec<KnownPeer>) {
+        *self.known_peers.write().unwrap() = peers.into_iter()
+            .map(|peer| PeerEntry { peer, last_seen: Instant::now() })
+            .collect();
+    }
+
+    /// Adds the valid addresses that are not known yet, returns the number of added peers. When the
+    /// table is full, the unreachable peer seen the longest ago makes room for the new one.
+    pub fn add_peers(&self, addresses: Vec<String>, own_address: &str) -> usize {
+        let mut known_peers = self.known_peers.write().unwrap();
+        let mut added = 0;
+        for address in addresses {
+            if address == own_address || known_peers.iter().any(|e| e.peer.address == address) {
+                continue;
+            }
+            if let Err(err) = validate_peer_address(&address) {
+                println!("Skipping peer: {err}");
+                continue;
+            }
+            if known_peers.len() >= self.max_known_peers {
+                let oldest = known_peers.iter().enumerate()
+                    .filter(|(_, e)| !e.peer.accessible())
+                    .min_by_key(|(_, e)| e.last_seen)
+                    .map(|(i, _)| i);
+                match oldest {
+                    Some(i) => { known_peers.remove(i); }
+                    None => break,
+                }
+            }
+            known_peers.push(PeerEntry { peer: KnownPeer::new(address), last_seen: Instant::now() });
+            added += 1;
+        }
+        added
+    }
+
+    pub fn update_pings_for_peers(&self, values: Vec<KnownPeer>) {
+        let mut known_peers = self.known_peers.write().unwrap();
+        for value in values {
+            if let Some(entry) = known_peers.iter_mut().find(|e| e.peer.address.eq(&value.address)) {
+                // the key of the peer is pinned the first time it's seen
+                let key_changed = matches!((&entry.peer.public_key, &value.public_key), (Some(known), Some(key)) if known != key);
+                if key_changed {
+                    println!("Peer {} presented a different key {:?}, treating it as unreachable", value.address, value.public_key);
+                    entry.peer.ping = None;
+                    continue;
+                }
+                if value.ping.is_some() {
+                    entry.last_seen = Instant::now();
+                }
+                entry.peer.ping = value.ping;
+                if value.public_key.is_some() {
+                    entry.peer.public_key = value.public_key;
+                }
+            };
+        }
+    }
+
+    /// Removes the peers that haven't responded for longer than the peer expiry.
+    pub fn expire_peers(&self) {
+        self.known_peers.write().unwrap().retain(|e| {
+            let expired = e.last_seen.elapsed() > self.peer_expiry;
+            if expired {
+                println!("Removing peer {} that is not responding", e.peer.address);
+            }
+            !expired
+        });
+    }
+
+    /// Random sample of the peers that responded to the last ping.
+    pub fn sample_accessible_peers(&self, count: usize) -> Vec<String> {
+        let mut peers = self.get_known_peers().into_iter()
+            .filter(|p| p.accessible())
+            .map(|p| p.address)
+            .collect::<Vec<_>>();
+        shuffle(&mut peers);
+        peers.truncate(count);
+        peers
+    }
+
+    pub fn is_misbehaving_peer(&self, address: &String) -> bool {
+        self.misbehaving_peers.read().unwrap().contains(address)
+    }
+
+    pub fn record_misbehaving_peers(&self, peers: HashSet<String>) {
+        let mut misbehaving_peers = self.misbehaving_peers.write().unwrap();
+        for address in peers {
+            println!("Recording peer {address} as misbehaving");
+            misbehaving_peers.insert(address);
+        }
+    }
+}
+
+/// Shuffles the values by the hashes with a random key, the std hasher is randomly seeded.
+pub fn shuffle<T: Hash>(values: &mut [T]) {
+    let state = RandomState::new();
+    values.sort_by_cached_key(|v| state.hash_one(v));
+}
+
+/// State shared between the listener, the downloads and the client. Every component is locked
+/// separately and only for short operations, so long downloads and disk reads don't block the
+/// other connections. Connection options are set before the state is shared and not changed after.
 pub struct State {
-    pub known_peers: Vec<KnownPeer>,
+    pub peers: PeerTable,
     pub local_fs_info: LocalFSInfo,
     pub file_manager: FileManager,
+    pub downloads: DownloadRegistry,
+    pub download_manager: DownloadManager,
+    pub dht: Dht,
+    pub identity_key: SigningKey,
+    pub connection_options: ConnectionOptions,
+    pub config: PeerConfig,
 }
 
 impl State {
     pub fn new(fs_config: FSConfig) -> Self {
+        Self::with_config(fs_config, PeerConfig::default())
+    }
+
+    /// State with the limits and the intervals of the config, the advertised address is resolved
+    /// and set by the caller.
+    pub fn with_config(fs_config: FSConfig, config: PeerConfig) -> Self {
+        let connection_options = ConnectionOptions {
+            peer_id: load_or_create_peer_id(&fs_config),
+            max_frame_size: config.max_frame_size,
+            buffer_size: config.buffer_size,
+            listen_address: config.listen_address.clone(),
+            advertised_address: None,
+            noise_key: config.encryption.then(|| NoiseKey::load_or_create(&fs_config))
+                .and_then(|key| key.map_err(|err| println!("Encryption is disabled: {err}")).ok()),
+            require_encryption: config.require_encryption,
+        };
+        let identity_key = load_or_create_identity_key(&fs_config);
+        let downloads = DownloadRegistry::default();
         State {
-            known_peers: vec![],
+            peers: PeerTable::new(config.max_known_peers, Duration::from_secs(config.peer_expiry_secs)),
             local_fs_info: LocalFSInfo{},
-            file_manager: FileManager::new(fs_config),
+            file_manager: FileManager::new(fs_config, downloads.clone(), config.piece_cache_size, config.max_open_files),
+            downloads,
+            download_manager: DownloadManager::new(config.max_downloads),
+            dht: Dht::new(&connection_options.peer_id, config.dht_options()),
+            identity_key,
+            connection_options,
+            config,
         }
     }
-    
-    pub fn update_pings_for_peers(&mut self, values: Vec<KnownPeer>) {
-        for value in values {
-            if let Some(peer) = self.known_peers.iter_mut().find(|p| p.address.eq(&value.address)) {
-                peer.ping = value.ping;
-            };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPIRY: Duration = Duration::from_secs(600);
+
+    fn addresses(count: usize) -> Vec<String> {
+        (1..=count).map(|i| format!("10.0.0.{i}:8000")).collect()
+    }
+
+    fn ping(table: &PeerTable, address: &str) {
+        table.update_pings_for_peers(vec![KnownPeer { ping: Some(1), ..KnownPeer::new(address.to_string()) }]);
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        for address in ["10.0.0.1:8000", "[::1]:8000", "peer-1.local:8000"] {
+            assert!(validate_peer_address(address).is_ok(), "{address}");
+        }
+        for address in ["10.0.0.1", "10.0.0.1:0", "0.0.0.0:8000", "224.0.0.1:8000", "-peer:8000", "peer/1:8000", ":8000", "peer:port"] {
+            assert!(validate_peer_address(address).is_err(), "{address}");
+        }
+    }
+
+    #[test]
+    fn only_new_valid_peers_are_added() {
+        let table = PeerTable::new(10, EXPIRY);
+        let peers = vec![
+            "10.0.0.1:8000".to_string(),
+            "10.0.0.1:8000".to_string(),
+            "127.0.0.1:8000".to_string(),
+            "0.0.0.0:8000".to_string(),
+            "not an address".to_string(),
+        ];
+        assert_eq!(table.add_peers(peers, "127.0.0.1:8000"), 1);
+        assert_eq!(table.add_peers(addresses(1), "127.0.0.1:8000"), 0);
+        let known = table.get_known_peers().into_iter().map(|p| p.address).collect::<Vec<_>>();
+        assert_eq!(known, vec!["10.0.0.1:8000"]);
+    }
+
+    #[test]
+    fn full_table_replaces_oldest_unreachable_peer() {
+        let table = PeerTable::new(3, EXPIRY);
+        assert_eq!(table.add_peers(addresses(5), ""), 5);
+        // every peer is unreachable, the new peers replace the oldest ones
+        let known = table.get_known_peers().into_iter().map(|p| p.address).collect::<Vec<_>>();
+        assert_eq!(known, addresses(5)[2..].to_vec());
+
+        for address in &known {
+            ping(&table, address);
         }
+        // reachable peers are never replaced
+        assert_eq!(table.add_peers(vec!["10.0.1.1:8000".to_string()], ""), 0);
+        assert_eq!(table.get_known_peers().len(), 3);
+    }
+
+    #[test]
+    fn stale_peers_are_expired() {
+        let table = PeerTable::new(10, EXPIRY);
+        table.add_peers(addresses(2), "");
+        let stale = Instant::now().checked_sub(EXPIRY + Duration::from_secs(1)).unwrap();
+        table.known_peers.write().unwrap().iter_mut().for_each(|e| e.last_seen = stale);
+        // a response refreshes the peer
+        ping(&table, "10.0.0.2:8000");
+        table.expire_peers();
+        let known = table.get_known_peers().into_iter().map(|p| p.address).collect::<Vec<_>>();
+        assert_eq!(known, vec!["10.0.0.2:8000"]);
+    }
+
+    #[test]
+    fn changed_key_makes_peer_unreachable() {
+        let table = PeerTable::new(10, EXPIRY);
+        table.add_peers(addresses(1), "");
+        let peer = |key: &str| KnownPeer { ping: Some(1), public_key: Some(key.to_string()), ..KnownPeer::new("10.0.0.1:8000".to_string()) };
+        table.update_pings_for_peers(vec![peer("a")]);
+        assert!(table.get_known_peers()[0].accessible());
+        table.update_pings_for_peers(vec![peer("b")]);
+        assert!(!table.get_known_peers()[0].accessible());
+        assert_eq!(table.get_known_peers()[0].public_key.as_deref(), Some("a"));
+        assert!(table.sample_accessible_peers(10).is_empty());
     }
 }
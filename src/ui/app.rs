@@ -9,17 +9,20 @@ use eframe::{egui};
 use eframe::egui::{Color32, Rounding, Stroke, vec2};
 use eframe::emath::{Align};
 use tinyfiledialogs as tfd;
-use crate::domain::config::FSConfig;
+use crate::control::client::ControlClient;
+use crate::control::protocol::{ControlEvent, ControlMessage, ControlRequest, ControlResponse};
+use crate::domain::config::{FSConfig, PeerConfig};
 use crate::domain::enums::PieceDownloadStatus;
 use crate::domain::files::{generate_meta_file, refresh_file_status, RFSFile};
 use crate::domain::fs::check_folders;
-use crate::peer::connection::{ConnectionFrame, FilePieceDownloadStatusResponseFrame, GetFileFrame, GetInfoFrame, InfoResponseFrame};
+use crate::domain::names::check_file_names;
+use crate::domain::signing::TrustStore;
 use crate::peer::enums::FileStatus;
+use crate::peer::identity::load_or_create_identity_key;
 use crate::peer::state::{FileDownloadProgress, KnownPeer, PieceDownloadProgress};
-use crate::ui::connection::{Connection};
 use crate::ui::enums::LeftPanelView;
 use crate::ui::format::to_readable_size;
-use crate::values::{LOCAL_PEER_ADDRESS, SYNC_DELAY_SECS};
+use crate::values::{DEFAULT_DOWNLOAD_PRIORITY, SYNC_DELAY_SECS};
 
 const ACCENT: Color32 = Color32::from_rgb(200, 255, 200);
 const SUCCESS: Color32 = Color32::from_rgb(150, 255, 150);
@@ -33,22 +36,30 @@ pub struct AppState {
     left_panel_view_selected: LeftPanelView,
     rfs_files: Vec<RFSFile>,
     known_peers: Vec<KnownPeer>,
+    // advertised address of the local peer, it's known after the first info response
+    local_peer_address: Option<String>,
     file_download_progresses: HashMap<String, FileDownloadProgress>
 }
 
 // todo: change heap strings to str refs with lifetime
 #[derive(Default)]
 pub struct AppConfig {
-    local_peer_address: String,
     fs: FSConfig,
+    piece_size: u64,
+    require_signed_metafiles: bool,
 }
 
 impl AppConfig {
+    /// Dirs of the local peer are taken from its config, so the ui works with the same files.
     fn new() -> Self {
-        let local_peer_address = LOCAL_PEER_ADDRESS.to_string();
+        let config = PeerConfig::load(None, Default::default()).unwrap_or_else(|err| {
+            println!("Error when loading the peer config, using the defaults: {err}");
+            PeerConfig::default()
+        });
         Self {
-            local_peer_address,
-            fs: FSConfig::new(None),
+            fs: config.fs_config(),
+            piece_size: config.piece_size,
+            require_signed_metafiles: config.require_signed_metafiles,
         }
     }
 }
@@ -80,8 +91,8 @@ pub struct DownloadFileCommandPayload {
 
 #[derive(Debug)]
 pub enum EventChannelEvent {
-    PeersInfoUpdate(InfoResponseFrame),
-    FilePieceDownloadStatus(FilePieceDownloadStatusResponseFrame),
+    PeersInfoUpdate { address: String, known_peers: Vec<KnownPeer> },
+    FilePieceDownloadStatus { file_id: String, piece: u64, status: PieceDownloadStatus },
     FileDownloadStarted(DownloadFileCommandPayload)
 }
 
@@ -105,36 +116,39 @@ fn run_sync_scheduler(sync_tx: Sender<SyncChannelEvent>) -> ! {
 
 
 fn run_background_worker(
+    control_socket: String,
     command_rx: Receiver<CommandChannelEvent>,
     event_tx: Sender<EventChannelEvent>,
 ) -> ! {
-    let mut connection = Connection::from_address(&LOCAL_PEER_ADDRESS.to_string()).unwrap();
+    let mut client = ControlClient::connect(&control_socket).unwrap();
+    client.request(ControlRequest::Subscribe).unwrap();
+    client.set_nonblocking(true).unwrap();
     loop {
         if let Ok(command) = command_rx.try_recv() {
-            match command {
-                CommandChannelEvent::GetPeersInfo => {
-                    connection.write_frame(ConnectionFrame::GetInfo(GetInfoFrame {}));
-                }
+            let result = match command {
+                CommandChannelEvent::GetPeersInfo => client.send(ControlRequest::GetInfo),
                 CommandChannelEvent::DownloadFile(payload) => {
                     let file_id = payload.file_id;
-                    connection.write_frame(ConnectionFrame::GetFile(GetFileFrame { file_id: file_id.clone() }));
-                    event_tx.send(EventChannelEvent::FileDownloadStarted(DownloadFileCommandPayload {file_id})).unwrap()
+                    let result = client.send(ControlRequest::DownloadFile { file_id: file_id.clone(), priority: DEFAULT_DOWNLOAD_PRIORITY });
+                    event_tx.send(EventChannelEvent::FileDownloadStarted(DownloadFileCommandPayload {file_id})).unwrap();
+                    result
                 }
+            };
+            if let Err(err) = result {
+                println!("Error when sending the request to the local peer: {err}");
             }
         }
-        match connection.read_frame() {
-            Ok(response) => {
-                match response {
-                    ConnectionFrame::InfoResponse(frame) => {
-                        event_tx.send(EventChannelEvent::PeersInfoUpdate(frame)).unwrap()
-                    }
-                    ConnectionFrame::FilePieceDownloadStatusResponse(frame) => {
-                        event_tx.send(EventChannelEvent::FilePieceDownloadStatus(frame)).unwrap()
-                    }
-                    _ => {}
-                }
+        match client.read_message() {
+            Ok(ControlMessage::Response { response: ControlResponse::Info { address, known_peers, .. }, .. }) => {
+                event_tx.send(EventChannelEvent::PeersInfoUpdate { address, known_peers }).unwrap()
             }
-            Err(_) => {}
+            Ok(ControlMessage::Response { response: ControlResponse::Error(err), .. }) => {
+                println!("Local peer responded with an error: {err}")
+            }
+            Ok(ControlMessage::Event(ControlEvent::PieceDownloadStatus { file_id, piece, status })) => {
+                event_tx.send(EventChannelEvent::FilePieceDownloadStatus { file_id, piece, status }).unwrap()
+            }
+            _ => {}
         }
         thread::sleep(Duration::from_millis(20));
     }
@@ -146,11 +160,23 @@ impl RFSApp {
         let config = AppConfig::new();
         let mut state: AppState = Default::default();
 
-        state.rfs_files = fs::read_dir(&config.fs.metafiles_dir).unwrap()
+        // the same metafiles as the daemon loads are listed
+        let trust_store = TrustStore::load(&config.fs);
+        let own_key = load_or_create_identity_key(&config.fs).verifying_key();
+        state.rfs_files = fs::read_dir(&config.fs.storage.metafiles_dir).unwrap()
             .into_iter().map(|path| {
                 let p = path.unwrap().path().to_str().unwrap().to_owned();
                 if p.ends_with(".rfs") {
-                    Some(RFSFile::from_path_sync(&p))
+                    let mut file = RFSFile::from_path_sync(&p);
+                    let checked = trust_store.check_file(&file.data, Some(&own_key), config.require_signed_metafiles)
+                        .and_then(|_| check_file_names(&mut file.data));
+                    match checked {
+                        Ok(_) => Some(file),
+                        Err(err) => {
+                            println!("Skipping metafile {p}: {err}");
+                            None
+                        }
+                    }
                 } else {
                     None
                 }
@@ -168,7 +194,8 @@ impl RFSApp {
         
         // spawning a background thread that will handle interactions with local peer without
         // blocking main ui thread
-        thread::spawn(move || run_background_worker(command_rx, event_tx));
+        let control_socket = config.fs.control_socket.clone();
+        thread::spawn(move || run_background_worker(control_socket, command_rx, event_tx));
 
         Self {
             config,
@@ -250,18 +277,20 @@ impl RFSApp {
                 }
                 if ui.add_sized([100., 0.0], egui::Button::new("Generate .rfs file")).clicked() {
                     if let Some(path) = tfd::open_file_dialog("Select a file to generate .rfs file", &self.config.fs.home_dir, None) {
-                        self.generate_rfs_file(path);
+                        if let Err(err) = self.generate_rfs_file(path) {
+                            println!("Error when generating .rfs file: {err}");
+                        }
                     }
                 }
                 if ui.add_sized([100., 0.0], egui::Button::new("Open files dir")).clicked() {
                     Command::new("open")
-                        .arg(&self.config.fs.files_dir)
+                        .arg(&self.config.fs.storage.files_dir)
                         .spawn()
                         .unwrap();
                 }
                 if ui.add_sized([100., 0.0], egui::Button::new("Open metafiles dir")).clicked() {
                     Command::new("open")
-                        .arg(&self.config.fs.metafiles_dir)
+                        .arg(&self.config.fs.storage.metafiles_dir)
                         .spawn()
                         .unwrap();
                 }
@@ -280,7 +309,7 @@ impl RFSApp {
                 }
                 SyncChannelEvent::RefreshFileStatus => {
                     for file in self.state.rfs_files.iter_mut() {
-                        refresh_file_status(file, self.config.fs.files_dir.clone());
+                        refresh_file_status(file, &self.config.fs);
                     }
                 }
             }
@@ -288,28 +317,31 @@ impl RFSApp {
 
         if let Ok(v) = self.channels.event_rx.try_recv() {
             match v {
-                EventChannelEvent::PeersInfoUpdate(frame) => {
-                    self.state.known_peers = frame.known_peers;
+                EventChannelEvent::PeersInfoUpdate { address, known_peers } => {
+                    self.state.local_peer_address = Some(address);
+                    self.state.known_peers = known_peers;
                 }
-                EventChannelEvent::FilePieceDownloadStatus(frame) => {
-                    let download_info = match self.state.file_download_progresses.get_mut(&frame.file_id) {
-                        None => panic!("File is not in the system!"),
-                        Some(v) => v,
-                    };
-                    
-                    download_info.pieces[frame.piece as usize] = PieceDownloadProgress {
-                        piece: frame.piece,
-                        status: frame.status,
+                EventChannelEvent::FilePieceDownloadStatus { file_id, piece, status } => {
+                    // the downloads may be started by the daemon or by other control clients
+                    let Some(download_info) = self.get_download_progress_mut(&file_id) else {
+                        println!("Ignoring the progress of the unknown file {file_id}");
+                        return;
                     };
+                    if let Some(progress) = download_info.pieces.get_mut(piece as usize) {
+                        *progress = PieceDownloadProgress {
+                            piece,
+                            status,
+                        };
+                    }
                 }
                 EventChannelEvent::FileDownloadStarted(payload) => {
                     println!("File download event handling!");
-                    let pieces: u64;
-                    {
-                        let file = self.get_file_by_id_mut(&payload.file_id).unwrap();
-                        file.status = Some(FileStatus::Downloading);
-                        pieces = file.data.hashes.len() as u64;
-                    }
+                    let Some(file) = self.get_file_by_id_mut(&payload.file_id) else {
+                        println!("Ignoring the download of the unknown file {}", payload.file_id);
+                        return;
+                    };
+                    file.status = Some(FileStatus::Downloading);
+                    let pieces = file.data.hashes.len() as u64;
                     self.state.file_download_progresses.insert(
                         payload.file_id.clone(),
                         FileDownloadProgress::empty(pieces),
@@ -319,6 +351,18 @@ impl RFSApp {
         }
     }
 
+    /// Progress of the download, created when the first event of a download the ui didn't start
+    /// arrives. Downloads of the files the ui doesn't know have no progress.
+    fn get_download_progress_mut(&mut self, file_id: &String) -> Option<&mut FileDownloadProgress> {
+        if !self.state.file_download_progresses.contains_key(file_id) {
+            let file = self.get_file_by_id_mut(file_id)?;
+            file.status = Some(FileStatus::Downloading);
+            let pieces = file.data.hashes.len() as u64;
+            self.state.file_download_progresses.insert(file_id.clone(), FileDownloadProgress::empty(pieces));
+        }
+        self.state.file_download_progresses.get_mut(file_id)
+    }
+
     fn get_selected_file(&self) -> Option<RFSFile> {
         match self.state.file_id_selected.borrow().deref() {
             None => None,
@@ -354,7 +398,7 @@ impl RFSApp {
                     self.render_info_panel_field(ui, "piece size", &file.data.piece_size.to_string(), 0.);
                     self.render_info_panel_field(ui, "number of pieces", &file.data.hashes.len().to_string(), 0.);
                     
-                    let peers_count = file.data.peers.iter().filter(|p| !p.eq(&&self.config.local_peer_address)).count();
+                    let peers_count = file.data.peers.iter().filter(|p| Some(*p) != self.state.local_peer_address.as_ref()).count();
                     self.render_info_panel_field(ui, "peers", &peers_count.to_string(), 0.);
                     for peer in file.data.peers.iter() {
                         let ping = if let Some(known_peer) = self.state.known_peers.iter().find(|p| p.address.eq(peer)) {
@@ -366,7 +410,7 @@ impl RFSApp {
                         } else {
                             0i64
                         };
-                        if peer == LOCAL_PEER_ADDRESS {
+                        if Some(peer) == self.state.local_peer_address.as_ref() {
                             continue;
                         }
                         self.render_info_panel_field(ui, peer, &ping.to_string(), 20.);
@@ -429,26 +473,40 @@ impl RFSApp {
     // todo: move to domain/files.rs
     fn add_rfs_file(&mut self, path: String) {
         let path = path.clone();
-        let file_name = path.split('/').last().unwrap();
-        let destination = self.config.fs.metafiles_dir.clone() + &"/" + file_name;
+        let mut file = RFSFile::from_path_sync(&path);
+        let own_key = load_or_create_identity_key(&self.config.fs).verifying_key();
+        let checked = TrustStore::load(&self.config.fs)
+            .check_file(&file.data, Some(&own_key), self.config.require_signed_metafiles)
+            .and_then(|_| check_file_names(&mut file.data))
+            .and_then(|_| self.config.fs.storage.check_collision(&file.data, self.state.rfs_files.iter().map(|f| &f.data)));
+        if let Err(err) = checked {
+            println!("Refusing to add {path}: {err}");
+            return;
+        }
+        let destination = match self.config.fs.storage.metafile_path(&path) {
+            Ok(destination) => destination,
+            Err(err) => {
+                println!("{err}");
+                return;
+            }
+        };
         fs::copy(path, &destination).unwrap_or_else(|err| {
             println!("Unable to copy file to metafiles dir {err}");
             0
         });
-        self.state.rfs_files.push(RFSFile::from_path_sync(&destination));
+        self.state.rfs_files.push(file);
     }
 
     fn generate_rfs_file(&mut self, path: String) -> Result<(), String> {
-        let meta_file_path = self.config.fs.metafiles_dir.clone()
-            + "/"
-            + path.clone().split('/').last().unwrap().split('.').next()
-            .ok_or("Failed to parse the file name, should be in format {name}.{extension}!")?
-            + ".rfs";
-        if let Ok(rfs_file) = generate_meta_file(self.config.local_peer_address.clone(), &path) {
+        let meta_file_path = self.config.fs.storage.metafile_path(&path)?;
+        let local_peer_address = self.state.local_peer_address.clone()
+            .ok_or("Address of the local peer is not known yet")?;
+        if let Ok(rfs_file) = generate_meta_file(local_peer_address, &path, self.config.piece_size) {
+            self.config.fs.storage.check_collision(&rfs_file.data, self.state.rfs_files.iter().map(|f| &f.data))?;
             rfs_file.save(meta_file_path.clone())?;
             self.state.rfs_files.push(RFSFile::from_path_sync(&meta_file_path));
             
-            fs::copy(path, self.config.fs.files_dir.clone() + "/" + &rfs_file.data.name).unwrap_or_else(|err| {
+            fs::copy(path, self.config.fs.storage.file_path(&rfs_file.data)).unwrap_or_else(|err| {
                 println!("Unable to copy file to metafiles dir {err}");
                 0
             });
@@ -1,4 +1,3 @@
 pub mod app;
-pub mod connection;
 mod format;
 mod enums;
\ No newline at end of file
@@ -1,11 +1,42 @@
+use std::net::Ipv4Addr;
 use eframe::egui::Color32;
 
 pub const DEFAULT_PIECE_SIZE: u64 = 2u64.pow(14);
 pub const DEFAULT_BUFFER_SIZE: usize = 2usize.pow(16);
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 2usize.pow(24);
+pub const PIECE_CACHE_SIZE: usize = 2usize.pow(26);
+pub const MAX_OPEN_FILES: usize = 64;
 pub const SYNC_DELAY_SECS: u64 = 1;
-// pub const LOCAL_PEER_ADDRESS: &str = "127.0.0.1:8000";
-// pub const DEFAULT_RFS_DIR: &str = ".rfs_peer2";
-pub const LOCAL_PEER_ADDRESS: &str = "127.0.0.1:8001";
+pub const PING_TIMEOUT_SECS: u64 = 10;
+pub const PIECE_WAIT_SECS: u64 = 30;
+pub const PIECE_TIMEOUT_SECS: u64 = 10;
+pub const PIECE_QUEUE_DEPTH: usize = 8;
+pub const MAX_PIECE_TIMEOUTS: u32 = 3;
+pub const ENDGAME_PIECES: u64 = 8;
+pub const MAX_CONCURRENT_DOWNLOADS: usize = 3;
+pub const DEFAULT_DOWNLOAD_PRIORITY: u8 = 0;
+pub const DOWNLOAD_EVENTS_CAPACITY: usize = 1024;
+pub const DHT_BUCKET_SIZE: usize = 20;
+pub const DHT_ALPHA: usize = 3;
+pub const DHT_ANNOUNCE_SECS: u64 = 300;
+pub const DHT_PROVIDER_TTL_SECS: u64 = 900;
+pub const DHT_MAX_PROVIDERS_PER_KEY: usize = 20;
+pub const DHT_MAX_KEYS_PER_PROVIDER: usize = 1024;
+pub const DHT_MAX_PROVIDER_KEYS: usize = 16384;
+pub const MAX_KNOWN_PEERS: usize = 200;
+pub const PEER_EXPIRY_SECS: u64 = 600;
+pub const PEX_INTERVAL_SECS: u64 = 30;
+pub const PEX_FANOUT: usize = 3;
+pub const PEX_SAMPLE_SIZE: usize = 20;
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 70, 83);
+pub const DISCOVERY_PORT: u16 = 7645;
+pub const DISCOVERY_INTERVAL_SECS: u64 = 5;
+pub const DISCOVERY_MAX_AGE_SECS: u64 = 60;
+pub const DISCOVERY_MAX_PINNED_KEYS: usize = 1024;
+pub const DISCOVERY_KEY_EXPIRY_SECS: u64 = 3600;
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8001";
 pub const DEFAULT_RFS_DIR: &str = ".rfs";
+pub const MAX_FILE_NAME_LENGTH: usize = 255;
+pub const MAX_FILE_ID_LENGTH: usize = 128;
 
 pub const ACCENT: Color32 = Color32::from_rgb(200, 255, 200);
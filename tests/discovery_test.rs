@@ -0,0 +1,134 @@
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use ed25519_dalek::SigningKey;
+use socket2::{Domain, Protocol, Socket, Type};
+use distributed_fs::domain::config::{DiscoveryOptions, FSConfig};
+use distributed_fs::domain::fs::check_folders;
+use distributed_fs::peer::discovery::{run_discovery, sign_announcement, verify_announcement, Announcement};
+use distributed_fs::peer::protocol::PROTOCOL_VERSION;
+use distributed_fs::peer::state::{SharableStateContainer, State};
+
+fn start_peer(dir: &Path, address: &str, options: &DiscoveryOptions) -> SharableStateContainer {
+    let fs_config = FSConfig::new(Some(dir.to_string_lossy().to_string()));
+    check_folders(&fs_config);
+    let mut state = State::new(fs_config);
+    state.connection_options.listen_address = address.to_string();
+    let container = Arc::new(state);
+    tokio::spawn(run_discovery(container.clone(), options.clone()));
+    container
+}
+
+fn known_addresses(container: &SharableStateContainer) -> Vec<String> {
+    let mut addresses = container.peers.get_known_peers().into_iter().map(|p| p.address).collect::<Vec<_>>();
+    addresses.sort();
+    addresses
+}
+
+/// Binds a discovery socket on a free port, the peers bind the same port with the reuse options.
+fn reserve_discovery_port() -> (Socket, u16) {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+    socket.set_reuse_address(true).unwrap();
+    #[cfg(unix)]
+    socket.set_reuse_port(true).unwrap();
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into()).unwrap();
+    let port = socket.local_addr().unwrap().as_socket().unwrap().port();
+    (socket, port)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_discover_each_other_on_loopback() {
+    let dir = std::env::temp_dir().join(format!("rfs_discovery_test_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let (_discovery_socket, port) = reserve_discovery_port();
+    let options = DiscoveryOptions {
+        port,
+        interface: Ipv4Addr::LOCALHOST,
+        interval: Duration::from_millis(100),
+        ..Default::default()
+    };
+    // the listeners keep the announced addresses free for the test
+    let listeners = (0..3).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect::<Vec<_>>();
+    let addresses = listeners.iter().map(|l| l.local_addr().unwrap().to_string()).collect::<Vec<_>>();
+    let a = start_peer(&dir.join("a"), &addresses[0], &options);
+    let b = start_peer(&dir.join("b"), &addresses[1], &options);
+    let c = start_peer(&dir.join("c"), &addresses[2], &options);
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while [&a, &b, &c].iter().any(|peer| peer.peers.get_known_peers().len() < 2) {
+        assert!(tokio::time::Instant::now() < deadline, "peers were not discovered in time");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let others = |i: usize| {
+        let mut others = addresses.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, a)| a.clone()).collect::<Vec<_>>();
+        others.sort();
+        others
+    };
+    assert_eq!(known_addresses(&a), others(0));
+    assert_eq!(known_addresses(&b), others(1));
+    assert_eq!(known_addresses(&c), others(2));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn announced_address_must_be_on_sender_host() {
+    let dir = std::env::temp_dir().join(format!("rfs_discovery_host_test_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let (_discovery_socket, port) = reserve_discovery_port();
+    let options = DiscoveryOptions {
+        port,
+        interface: Ipv4Addr::LOCALHOST,
+        interval: Duration::from_secs(60),
+        ..Default::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let peer = start_peer(&dir.join("a"), &listener.local_addr().unwrap().to_string(), &options);
+
+    let sender = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+    sender.set_multicast_if_v4(&Ipv4Addr::LOCALHOST).unwrap();
+    sender.set_multicast_loop_v4(true).unwrap();
+    let group = SocketAddr::from((options.group, port)).into();
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let spoofed = Announcement { listen_address: "10.1.2.3:8000".to_string(), ..announcement(key.verifying_key().to_bytes()) };
+    let valid = Announcement { peer_id: "other".to_string(), ..announcement(key.verifying_key().to_bytes()) };
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while known_addresses(&peer).is_empty() {
+        assert!(tokio::time::Instant::now() < deadline, "valid announcement was not received in time");
+        // the spoofed announcement is sent first, so it's processed before the valid one
+        sender.send_to(&sign_announcement(&spoofed, &key).unwrap(), &group).unwrap();
+        sender.send_to(&sign_announcement(&valid, &key).unwrap(), &group).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(known_addresses(&peer), vec!["127.0.0.1:8001"]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn announcement(public_key: [u8; 32]) -> Announcement {
+    Announcement {
+        peer_id: "peer".to_string(),
+        listen_address: "127.0.0.1:8001".to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        public_key,
+        timestamp: std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+    }
+}
+
+#[test]
+fn announcement_with_wrong_signature_is_rejected() {
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let mut data = sign_announcement(&announcement(key.verifying_key().to_bytes()), &key).unwrap();
+    assert!(verify_announcement(&data).is_ok());
+
+    // the last byte belongs to the signature
+    *data.last_mut().unwrap() ^= 1;
+    assert!(verify_announcement(&data).is_err());
+
+    let other_key = SigningKey::from_bytes(&[8; 32]);
+    let data = sign_announcement(&announcement(key.verifying_key().to_bytes()), &other_key).unwrap();
+    assert!(verify_announcement(&data).is_err());
+}
@@ -1,7 +1,6 @@
 use std::os::macos::fs::MetadataExt;
 use std::sync::Arc;
 use tokio::fs::OpenOptions;
-use tokio::sync::Mutex;
 use distributed_fs::domain::config::FSConfig;
 use distributed_fs::domain::fs::check_folders;
 use distributed_fs::peer::client::Client;
@@ -20,7 +19,7 @@ async fn main() {
     let fs_config = FSConfig::new(None);
     check_folders(&fs_config);
 
-    let peer_sharable_state_container = Arc::new(Mutex::new(State::new(fs_config.clone())));
+    let peer_sharable_state_container = Arc::new(State::new(fs_config.clone()));
 
     let mut peer_client = Client::new(peer_address.clone(), peer_sharable_state_container.clone());
     peer_client.load_state(peer_address.clone(), &fs_config).await.unwrap();
@@ -33,7 +32,7 @@ async fn main() {
     });
 
     // setting up the client
-    let sharable_state_container = Arc::new(Mutex::new(State::new(fs_config.clone())));
+    let sharable_state_container = Arc::new(State::new(fs_config.clone()));
 
     let mut client = Client::new(host_address.clone(), sharable_state_container.clone());
     client.load_state(host_address, &fs_config).await.unwrap();
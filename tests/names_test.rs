@@ -0,0 +1,120 @@
+use std::path::{Component, Path};
+use proptest::prelude::*;
+use distributed_fs::domain::names::{check_file_names, sanitize_name, validate_id, validate_name};
+use distributed_fs::domain::models::File;
+use distributed_fs::domain::storage::StorageLayout;
+
+fn file(id: &str, name: &str) -> File {
+    File {
+        id: id.to_string(),
+        hash: String::new(),
+        name: name.to_string(),
+        length: 0,
+        peers: vec![],
+        piece_size: 1,
+        hashes: vec![],
+        swarm_secret: None,
+        allowed_peers: vec![],
+        publisher: None,
+        signature: None,
+    }
+}
+
+/// Names made of the parts that are likely to break the paths.
+fn hostile_name() -> impl Strategy<Value = String> {
+    let part = prop_oneof![
+        Just("..".to_string()),
+        Just(".".to_string()),
+        Just("/".to_string()),
+        Just("\\".to_string()),
+        Just("C:".to_string()),
+        Just("CON".to_string()),
+        Just("nul".to_string()),
+        Just(" ".to_string()),
+        Just("\0".to_string()),
+        Just("\n".to_string()),
+        Just("é".to_string()),
+        "[a-z]{1,8}",
+        any::<String>(),
+    ];
+    prop::collection::vec(part, 0..12).prop_map(|parts| parts.concat())
+}
+
+/// Checks that the path is the dir joined with exactly one normal component.
+fn assert_in_dir(dir: &str, path: &str) {
+    let relative = Path::new(path).strip_prefix(dir).expect("path is outside of the dir");
+    let components = relative.components().collect::<Vec<_>>();
+    assert_eq!(components.len(), 1, "{path:?} is not a direct child of {dir:?}");
+    assert!(matches!(components[0], Component::Normal(_)), "{path:?} is not a normal file name");
+}
+
+proptest! {
+    #[test]
+    fn sanitized_names_are_valid(name in prop_oneof![hostile_name(), any::<String>()]) {
+        let sanitized = sanitize_name(&name);
+        prop_assert!(validate_name(&sanitized).is_ok(), "{:?} -> {:?}", name, sanitized);
+        prop_assert_eq!(sanitize_name(&sanitized), sanitized);
+    }
+
+    #[test]
+    fn valid_names_are_not_changed(name in "[a-zA-Z0-9_ .-]{1,300}") {
+        if validate_name(&name).is_ok() {
+            prop_assert_eq!(sanitize_name(&name), name);
+        }
+    }
+
+    #[test]
+    fn paths_stay_in_their_dirs(name in hostile_name(), id in hostile_name()) {
+        let storage = StorageLayout::new("/rfs");
+        let file = file(&id, &name);
+        assert_in_dir(&storage.files_dir, &storage.file_path(&file));
+        assert_in_dir(&storage.file_parts_dir, &storage.part_path(&file));
+        assert_in_dir(&storage.file_parts_dir, &storage.bitfield_path(&file));
+        if let Ok(path) = storage.metafile_path(&name) {
+            assert_in_dir(&storage.metafiles_dir, &path);
+        }
+    }
+
+    #[test]
+    fn checked_files_have_valid_names(name in hostile_name(), id in hostile_name()) {
+        let mut file = file(&id, &name);
+        match check_file_names(&mut file) {
+            Ok(_) => {
+                prop_assert!(validate_id(&file.id).is_ok());
+                prop_assert!(validate_name(&file.name).is_ok());
+            }
+            Err(_) => prop_assert!(validate_id(&id).is_err()),
+        }
+    }
+}
+
+#[test]
+fn unsafe_names_are_rejected() {
+    for name in [
+        "", ".", "..", "../../.ssh/authorized_keys", "/etc/passwd", "C:\\Windows\\win.ini", "dir\\file",
+        "con", "CON.txt", "lpt1.tar.gz", "Aux .txt", "file.", "file ", "new\nline", "nul\0byte", "a:b",
+    ] {
+        assert!(validate_name(name).is_err(), "{name:?} should be rejected");
+    }
+    assert!(validate_name(&"a".repeat(256)).is_err());
+}
+
+#[test]
+fn safe_names_are_accepted() {
+    for name in ["image.HEIC", ".hidden", "archive.tar.gz", "résumé.pdf", "console.log", "a".repeat(255).as_str()] {
+        assert!(validate_name(name).is_ok(), "{name:?} should be accepted");
+    }
+}
+
+#[test]
+fn traversal_names_are_renamed() {
+    assert_eq!(sanitize_name("../../.ssh/authorized_keys"), ".._.._.ssh_authorized_keys");
+    assert_eq!(sanitize_name(".."), "file");
+    assert_eq!(sanitize_name("CON.txt"), "_CON.txt");
+    assert_eq!(sanitize_name("ok.txt"), "ok.txt");
+
+    let mut traversal = file("3f1b7c52-6a3e-4d7e-9b61-2f0c1a9e8d44", "../../.ssh/authorized_keys");
+    check_file_names(&mut traversal).unwrap();
+    assert_eq!(traversal.name, ".._.._.ssh_authorized_keys");
+    assert!(check_file_names(&mut file("../part", "ok.txt")).is_err());
+}